@@ -0,0 +1,110 @@
+//! relay throughput of `ChildHandler` buffers, compared with the byte by byte
+//! `Vec` buffers they replaced. run with `cargo bench -p network`.
+
+extern crate network;
+extern crate mio;
+
+use std::io::{Result, Write};
+use std::time::{Duration, Instant};
+use mio::Token;
+use network::server::ChildHandler;
+
+/// bytes returned by one socket read in the server
+const READ_SIZE: usize = 16 * 1024;
+
+/// bytes a socket accepts per write
+const WRITE_SIZE: usize = 4 * 1024;
+
+/// socket which accepts at most `WRITE_SIZE` bytes per write
+struct Sink {
+    written: usize,
+}
+
+impl Write for Sink {
+    fn write(&mut self, data: &[u8]) -> Result<usize> {
+        let size = data.len().min(WRITE_SIZE);
+        std::hint::black_box(&data[..size]);
+        self.written += size;
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// the buffers as they were: a byte pushed at a time and removed from the front
+#[derive(Default)]
+struct VecBuffers {
+    receive_buffer: Vec<u8>,
+    dst_send_buffer: Vec<u8>,
+}
+
+impl VecBuffers {
+    fn relay(&mut self, data: &[u8], sink: &mut Sink) {
+        for byte in data {
+            self.receive_buffer.push(*byte);
+        }
+
+        for byte in self.receive_buffer.iter() {
+            self.dst_send_buffer.push(*byte);
+        }
+        self.receive_buffer.clear();
+
+        let mut total = 0;
+        while total < self.dst_send_buffer.len() {
+            total += sink.write(&self.dst_send_buffer[total..]).unwrap();
+        }
+
+        for _ in 0..total {
+            self.dst_send_buffer.remove(0);
+        }
+    }
+}
+
+fn relay_vec(total: usize) -> Duration {
+    let mut buffers = VecBuffers::default();
+    let mut sink = Sink { written: 0 };
+    let data = vec![7u8; READ_SIZE];
+
+    let start = Instant::now();
+    for _ in 0..total / READ_SIZE {
+        buffers.relay(&data, &mut sink);
+    }
+    let elapsed = start.elapsed();
+
+    assert_eq!(total, sink.written);
+    elapsed
+}
+
+fn relay_handler(total: usize) -> Duration {
+    let mut handler = ChildHandler::new_test(&Token(0));
+    let mut sink = Sink { written: 0 };
+    let data = vec![7u8; READ_SIZE];
+
+    let start = Instant::now();
+    for _ in 0..total / READ_SIZE {
+        handler.receive_data(&data, false);
+        handler.move_to_proxy();
+        handler.write_to_socket(&mut sink, true).unwrap();
+    }
+    let elapsed = start.elapsed();
+
+    assert_eq!(total, sink.written);
+    elapsed
+}
+
+fn report(name: &str, total: usize, elapsed: Duration) {
+    let mib = total as f64 / (1024.0 * 1024.0);
+    println!("{:<8} {:>6.0} MiB in {:>7.3}s {:>10.1} MiB/s"
+             , name, mib, elapsed.as_secs_f64(), mib / elapsed.as_secs_f64());
+}
+
+fn main() {
+    // the old buffers are far slower, they relay less data to finish in time
+    let vec_total = 8 * 1024 * 1024;
+    let handler_total = 1024 * 1024 * 1024;
+
+    report("vec", vec_total, relay_vec(vec_total));
+    report("buffer", handler_total, relay_handler(handler_total));
+}
@@ -0,0 +1,293 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs::{read_to_string, rename, write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::RwLock;
+use argon2::Argon2;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use rand_core::OsRng;
+use protocol::packet::{AuthType, UserPassAuthRequest};
+
+/// verified instead of a missing user, with the same cost as `hash_password`,
+/// so response time does not tell which names exist. nothing hashes to it.
+const DUMMY_HASH: &str = "$argon2id$v=19$m=19456,t=2,p=1$IWVIDE7K2cqAB+jb+8bLSA$Y4cqPKB0IjSV2OE+0feGWv0zmNNmPdEZv+F02gJy6/I";
+
+/// identity attached to a session once authentication finishes
+#[derive(Debug, Clone, PartialEq)]
+pub enum Identity {
+    Anonymous,
+    User(String),
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Identity::Anonymous => write!(f, "anonymous"),
+            Identity::User(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// decides how clients authenticate, called by `ChildHandler` during
+/// method selection and sub negotiation.
+pub trait Authenticator: Send + Sync {
+    /// pick one of the methods offered by client, `None` when nothing is acceptable
+    fn select_method(&self, client: &SocketAddr, offered: &[AuthType]) -> Option<AuthType>;
+
+    /// verify client for the selected method, `credentials` is only present
+    /// for name/password. returns the identity when client is accepted.
+    fn authenticate(&self, client: &SocketAddr, method: &AuthType
+                    , credentials: Option<&UserPassAuthRequest>) -> Option<Identity>;
+}
+
+/// accept every client without authentication
+#[derive(Default)]
+pub struct AnonymousAuthenticator;
+
+impl Authenticator for AnonymousAuthenticator {
+    fn select_method(&self, _client: &SocketAddr, offered: &[AuthType]) -> Option<AuthType> {
+        if offered.contains(&AuthType::Non) {
+            Some(AuthType::Non)
+        } else {
+            None
+        }
+    }
+
+    fn authenticate(&self, _client: &SocketAddr, method: &AuthType
+                    , _credentials: Option<&UserPassAuthRequest>) -> Option<Identity> {
+        match method {
+            AuthType::Non => Some(Identity::Anonymous),
+            _ => None,
+        }
+    }
+}
+
+/// name/password authentication against users kept in memory
+#[derive(Default)]
+pub struct MemoryAuthenticator {
+    users: HashMap<String, String>,
+}
+
+impl MemoryAuthenticator {
+    pub fn new() -> MemoryAuthenticator {
+        MemoryAuthenticator {
+            users: HashMap::new(),
+        }
+    }
+
+    pub fn add_user(&mut self, name: &str, password: &str) {
+        self.users.insert(name.to_string(), password.to_string());
+    }
+
+    pub fn verify(&self, name: &str, password: &str) -> bool {
+        match self.users.get(name) {
+            Some(expected) => expected == password,
+            None => false,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+}
+
+impl Authenticator for MemoryAuthenticator {
+    fn select_method(&self, _client: &SocketAddr, offered: &[AuthType]) -> Option<AuthType> {
+        select_name_password(offered)
+    }
+
+    fn authenticate(&self, _client: &SocketAddr, method: &AuthType
+                    , credentials: Option<&UserPassAuthRequest>) -> Option<Identity> {
+        verify_credentials(method, credentials, |name, password| self.verify(name, password))
+    }
+}
+
+/// name/password authentication against a `UsersFile`
+pub struct FileAuthenticator {
+    path: String,
+    users: RwLock<UsersFile>,
+}
+
+impl FileAuthenticator {
+    pub fn load(path: &str) -> Result<FileAuthenticator, String> {
+        let users = UsersFile::load(path)?;
+
+        Ok(FileAuthenticator {
+            path: path.to_string(),
+            users: RwLock::new(users),
+        })
+    }
+
+    /// read the users file again, current users are kept when it fails
+    pub fn reload(&self) -> Result<(), String> {
+        let users = UsersFile::load(&self.path)?;
+        match self.users.write() {
+            Ok(mut guard) => *guard = users,
+            Err(_) => return Err("users lock is poisoned.".to_string()),
+        }
+
+        Ok(())
+    }
+}
+
+impl Authenticator for FileAuthenticator {
+    fn select_method(&self, _client: &SocketAddr, offered: &[AuthType]) -> Option<AuthType> {
+        select_name_password(offered)
+    }
+
+    fn authenticate(&self, _client: &SocketAddr, method: &AuthType
+                    , credentials: Option<&UserPassAuthRequest>) -> Option<Identity> {
+        let users = match self.users.read() {
+            Ok(users) => users,
+            Err(_) => return None,
+        };
+
+        verify_credentials(method, credentials, |name, password| users.verify(name, password))
+    }
+}
+
+/// users file with one `name:hash` per line, the hash is an argon2 PHC string.
+/// empty lines and lines starting with `#` are skipped.
+pub struct UsersFile {
+    path: String,
+    users: BTreeMap<String, String>,
+}
+
+impl UsersFile {
+    /// load an existing users file
+    pub fn load(path: &str) -> Result<UsersFile, String> {
+        let content = match read_to_string(path) {
+            Ok(content) => content,
+            Err(e) => return Err(format!("read users file {} failed: {}", path, e)),
+        };
+
+        let mut users = BTreeMap::new();
+        for (index, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (name, hash) = match line.find(':') {
+                Some(pos) if pos > 0 => (&line[..pos], &line[pos + 1..]),
+                _ => return Err(format!("users file {} line {} should be name:hash", path, index + 1)),
+            };
+
+            if PasswordHash::new(hash).is_err() {
+                return Err(format!("users file {} line {} is not a hashed password", path, index + 1));
+            }
+
+            users.insert(name.to_string(), hash.to_string());
+        }
+
+        Ok(UsersFile {
+            path: path.to_string(),
+            users,
+        })
+    }
+
+    /// load a users file, an absent file is treated as empty
+    pub fn open(path: &str) -> Result<UsersFile, String> {
+        if Path::new(path).exists() {
+            return UsersFile::load(path);
+        }
+
+        Ok(UsersFile {
+            path: path.to_string(),
+            users: BTreeMap::new(),
+        })
+    }
+
+    /// add a user or replace its password, both are limited to 255 bytes by rfc 1929.
+    /// names which would read back as a comment or another line are refused
+    pub fn add(&mut self, name: &str, password: &str) -> Result<(), String> {
+        if name.is_empty() || name.len() > 255 || name.contains(':') || name.trim() != name {
+            return Err("name should be 1 to 255 bytes without ':' or surrounding spaces.".to_string());
+        }
+
+        if name.starts_with('#') || name.contains(['\n', '\r']) {
+            return Err("name should not start with '#' or contain line breaks.".to_string());
+        }
+
+        if password.is_empty() || password.len() > 255 {
+            return Err("password should be 1 to 255 bytes.".to_string());
+        }
+
+        let hash = hash_password(password)?;
+        self.users.insert(name.to_string(), hash);
+
+        Ok(())
+    }
+
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.users.remove(name).is_some()
+    }
+
+    pub fn names(&self) -> Vec<&String> {
+        self.users.keys().collect()
+    }
+
+    pub fn verify(&self, name: &str, password: &str) -> bool {
+        match self.users.get(name) {
+            Some(hash) => verify_password(hash, password),
+            None => {
+                verify_password(DUMMY_HASH, password);
+                false
+            }
+        }
+    }
+
+    /// write users back, through a temporary file so readers never see half of it
+    pub fn save(&self) -> Result<(), String> {
+        let mut content = String::new();
+        for (name, hash) in self.users.iter() {
+            content.push_str(name);
+            content.push(':');
+            content.push_str(hash);
+            content.push('\n');
+        }
+
+        let temp = format!("{}.tmp", self.path);
+        if let Err(e) = write(&temp, content) {
+            return Err(format!("write users file {} failed: {}", temp, e));
+        }
+
+        match rename(&temp, &self.path) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("replace users file {} failed: {}", self.path, e)),
+        }
+    }
+}
+
+pub fn hash_password(password: &str) -> Result<String, String> {
+    let salt = SaltString::generate(&mut OsRng);
+    match Argon2::default().hash_password(password.as_bytes(), &salt) {
+        Ok(hash) => Ok(hash.to_string()),
+        Err(e) => Err(format!("hash password failed: {}", e)),
+    }
+}
+
+pub fn verify_password(hash: &str, password: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(hash) => Argon2::default().verify_password(password.as_bytes(), &hash).is_ok(),
+        Err(_) => false,
+    }
+}
+
+fn select_name_password(offered: &[AuthType]) -> Option<AuthType> {
+    if offered.contains(&AuthType::NamePassword) {
+        Some(AuthType::NamePassword)
+    } else {
+        None
+    }
+}
+
+fn verify_credentials<F>(method: &AuthType, credentials: Option<&UserPassAuthRequest>, verify: F)
+                         -> Option<Identity> where F: Fn(&str, &str) -> bool {
+    match (method, credentials) {
+        (AuthType::NamePassword, Some(request)) if verify(request.name(), request.password()) =>
+            Some(Identity::User(request.name().to_string())),
+        _ => None,
+    }
+}
@@ -0,0 +1,125 @@
+use std::io::{Error, ErrorKind, Write};
+
+/// consumed bytes at the front are only moved away once they are at least this many
+const COMPACT_THRESHOLD: usize = 64 * 1024;
+
+/// byte queue of a connection, data is appended at the back and consumed from the front.
+///
+/// consuming only moves a read position, the unread bytes are moved to the front
+/// when consumed space is large enough, so every byte is copied a bounded number of times.
+#[derive(Debug, Default)]
+pub struct Buffer {
+    data: Vec<u8>,
+    start: usize,
+}
+
+impl Buffer {
+    pub fn new() -> Buffer {
+        Buffer {
+            data: Vec::new(),
+            start: 0,
+        }
+    }
+
+    /// unread bytes, always contiguous
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data[self.start..]
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len() - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn extend_from_slice(&mut self, data: &[u8]) {
+        self.compact();
+        self.data.extend_from_slice(data);
+    }
+
+    /// drop `size` bytes from the front, all of them when fewer are buffered
+    pub fn consume(&mut self, size: usize) {
+        self.start += size.min(self.len());
+        if self.start == self.data.len() {
+            self.clear();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.data.clear();
+        self.start = 0;
+    }
+
+    /// move all bytes of `other` to the back of this buffer
+    pub fn append(&mut self, other: &mut Buffer) {
+        if self.is_empty() {
+            // take the storage instead of copying
+            std::mem::swap(self, other);
+            other.clear();
+            return;
+        }
+
+        self.extend_from_slice(other.as_slice());
+        other.clear();
+    }
+
+    /// write until buffer is empty or writer would block, returns the number written
+    pub fn write_to<W: Write>(&mut self, writer: &mut W) -> Result<usize, Error> {
+        let mut total = 0;
+        while !self.is_empty() {
+            match writer.write(self.as_slice()) {
+                Ok(0) => return Err(Error::from(ErrorKind::WriteZero)),
+                Ok(size) => {
+                    self.consume(size);
+                    total += size;
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(total)
+    }
+
+    fn compact(&mut self) {
+        if self.start >= COMPACT_THRESHOLD && self.start * 2 >= self.data.len() {
+            self.data.drain(..self.start);
+            self.start = 0;
+        }
+    }
+}
+
+/// default bytes buffered in one direction before reading from its producer stops
+pub const DEFAULT_HIGH_WATER: usize = 1024 * 1024;
+
+/// default bytes left buffered when reading from the producer resumes
+pub const DEFAULT_LOW_WATER: usize = 256 * 1024;
+
+/// bounds of data buffered per direction of a connection
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BufferLimits {
+    pub high_water: usize,
+    pub low_water: usize,
+}
+
+impl BufferLimits {
+    pub fn new(high_water: usize, low_water: usize) -> Result<BufferLimits, String> {
+        if high_water == 0 || low_water >= high_water {
+            return Err(format!("low-water mark {} should be below high-water mark {}", low_water, high_water));
+        }
+
+        Ok(BufferLimits { high_water, low_water })
+    }
+}
+
+impl Default for BufferLimits {
+    fn default() -> BufferLimits {
+        BufferLimits {
+            high_water: DEFAULT_HIGH_WATER,
+            low_water: DEFAULT_LOW_WATER,
+        }
+    }
+}
@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// limits of `DnsCache`, ttl of records is clamped into `min_ttl..=max_ttl`
+#[derive(Debug, Clone)]
+pub struct DnsCacheConfig {
+    pub min_ttl: Duration,
+    pub max_ttl: Duration,
+    /// upper bound of how long a missing domain is remembered
+    pub negative_ttl: Duration,
+    /// ttl used when lookup does not tell one
+    pub default_ttl: Duration,
+    /// max number of domains kept
+    pub capacity: usize,
+}
+
+impl Default for DnsCacheConfig {
+    fn default() -> DnsCacheConfig {
+        DnsCacheConfig {
+            min_ttl: Duration::from_secs(5),
+            max_ttl: Duration::from_secs(60 * 60),
+            negative_ttl: Duration::from_secs(30),
+            default_ttl: Duration::from_secs(60),
+            capacity: 4096,
+        }
+    }
+}
+
+struct Entry {
+    // `None` for a domain which does not exist
+    ips: Option<Vec<IpAddr>>,
+    expires: Instant,
+}
+
+/// hit and miss counters of a `DnsCache`, clones share the counters so
+/// they can be read from another thread while the cache is in use
+#[derive(Debug, Clone, Default)]
+pub struct DnsCacheStats {
+    hits: Arc<AtomicU64>,
+    misses: Arc<AtomicU64>,
+}
+
+impl DnsCacheStats {
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+/// answers of recent lookups, both found addresses and missing domains
+pub struct DnsCache {
+    config: DnsCacheConfig,
+    entries: HashMap<String, Entry>,
+    stats: DnsCacheStats,
+}
+
+impl DnsCache {
+    pub fn new(config: DnsCacheConfig) -> DnsCache {
+        DnsCache {
+            config,
+            entries: HashMap::new(),
+            stats: DnsCacheStats::default(),
+        }
+    }
+
+    /// cached answer of `domain`, `Some(None)` means it is known to be missing
+    pub fn get(&mut self, domain: &str, now: Instant) -> Option<Option<Vec<IpAddr>>> {
+        let key = domain.to_ascii_lowercase();
+        let expired = match self.entries.get(&key) {
+            Some(entry) if entry.expires > now => {
+                self.stats.hits.fetch_add(1, Ordering::Relaxed);
+                return Some(entry.ips.clone());
+            }
+            Some(_) => true,
+            None => false,
+        };
+
+        if expired {
+            self.entries.remove(&key);
+        }
+        self.stats.misses.fetch_add(1, Ordering::Relaxed);
+
+        None
+    }
+
+    /// remember addresses of `domain` for their record ttl
+    pub fn insert(&mut self, domain: &str, ips: Vec<IpAddr>, ttl: Option<Duration>, now: Instant) {
+        let ttl = ttl.unwrap_or(self.config.default_ttl)
+            .max(self.config.min_ttl)
+            .min(self.config.max_ttl);
+        self.put(domain, Some(ips), ttl, now);
+    }
+
+    /// remember that `domain` does not exist, `ttl` comes from SOA when present
+    pub fn insert_negative(&mut self, domain: &str, ttl: Option<Duration>, now: Instant) {
+        let ttl = ttl.unwrap_or(self.config.negative_ttl).min(self.config.negative_ttl);
+        self.put(domain, None, ttl, now);
+    }
+
+    fn put(&mut self, domain: &str, ips: Option<Vec<IpAddr>>, ttl: Duration, now: Instant) {
+        if self.config.capacity == 0 || ttl == Duration::from_secs(0) {
+            return;
+        }
+
+        let key = domain.to_ascii_lowercase();
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.config.capacity {
+            self.evict(now);
+        }
+
+        self.entries.insert(key, Entry {
+            ips,
+            expires: now + ttl,
+        });
+    }
+
+    /// drop expired entries, or the one expiring first when none is expired
+    fn evict(&mut self, now: Instant) {
+        self.entries.retain(|_, entry| entry.expires > now);
+        if self.entries.len() < self.config.capacity {
+            return;
+        }
+
+        let first = self.entries.iter()
+            .min_by_key(|(_, entry)| entry.expires)
+            .map(|(domain, _)| domain.clone());
+        if let Some(domain) = first {
+            self.entries.remove(&domain);
+        }
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.stats.hits()
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.stats.misses()
+    }
+
+    /// handle to the counters which stays valid after the cache is moved
+    pub fn stats(&self) -> DnsCacheStats {
+        self.stats.clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn config(&self) -> &DnsCacheConfig {
+        &self.config
+    }
+}
@@ -0,0 +1,203 @@
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use mio::{Poll, PollOpt, Ready, Token};
+use mio::net::TcpStream;
+use protocol::packet::ReplyType;
+use crate::server::connect_error_reply;
+
+/// rfc 8305 recommends 250ms between connection attempts
+pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// state of a race after an attempt finished or a timer fired
+pub enum RaceState {
+    Pending,
+    /// the winning socket and its address, other attempts are closed
+    Connected(TcpStream, SocketAddr),
+    /// every address failed, the reply is the most specific failure seen
+    Failed(ReplyType),
+}
+
+struct Attempt {
+    address: SocketAddr,
+    socket: TcpStream,
+    token: Option<Token>,
+}
+
+/// connect to one of several addresses of a destination (rfc 8305).
+///
+/// attempts are started one by one, `delay` apart or as soon as the previous
+/// one failed, alternating between address families. the first connection
+/// which succeeds is kept and the others are closed.
+pub struct ConnectRace {
+    waiting: VecDeque<SocketAddr>,
+    attempts: Vec<Attempt>,
+    delay: Duration,
+    next_attempt: Instant,
+    failure: Option<ReplyType>,
+    closed: Vec<Token>,
+}
+
+impl ConnectRace {
+    /// start the first attempt, the race may already be lost when no address can be tried
+    pub fn start(addresses: Vec<SocketAddr>, delay: Duration, now: Instant) -> (ConnectRace, RaceState) {
+        let mut race = ConnectRace {
+            waiting: interleave(addresses).into(),
+            attempts: Vec::new(),
+            delay,
+            next_attempt: now,
+            failure: None,
+            closed: Vec::new(),
+        };
+
+        let state = race.start_next(now);
+        (race, state)
+    }
+
+    /// start the next attempt once its delay is over
+    pub fn timer(&mut self, now: Instant) -> RaceState {
+        match self.deadline() {
+            Some(deadline) if deadline <= now => self.start_next(now),
+            _ => RaceState::Pending,
+        }
+    }
+
+    /// when the next attempt should start, `None` once every address is tried
+    pub fn deadline(&self) -> Option<Instant> {
+        match self.waiting.is_empty() {
+            true => None,
+            false => Some(self.next_attempt),
+        }
+    }
+
+    /// register attempts started since last call with tokens of `next_token`, returns them
+    pub fn register<F: FnMut() -> Token>(&mut self, poll: &Poll, mut next_token: F) -> Vec<Token> {
+        let mut registered = Vec::new();
+        for attempt in self.attempts.iter_mut().filter(|attempt| attempt.token.is_none()) {
+            let token = next_token();
+            if let Err(e) = poll.register(&attempt.socket, token, Ready::readable() | Ready::writable()
+                                          , PollOpt::edge()) {
+                println!("register connect to {} failed:{}", attempt.address, e);
+            }
+            attempt.token = Some(token);
+            registered.push(token);
+        }
+
+        registered
+    }
+
+    /// socket of attempt `token` is ready, check whether it connected
+    pub fn ready(&mut self, token: Token, now: Instant) -> RaceState {
+        let index = match self.attempts.iter().position(|attempt| attempt.token == Some(token)) {
+            Some(index) => index,
+            None => return RaceState::Pending,
+        };
+
+        match is_connected(&self.attempts[index].socket) {
+            Ok(false) => RaceState::Pending,
+            Ok(true) => {
+                let winner = self.attempts.remove(index);
+                self.waiting.clear();
+                for attempt in self.attempts.drain(..) {
+                    self.closed.extend(attempt.token);
+                }
+                RaceState::Connected(winner.socket, winner.address)
+            }
+            Err(e) => {
+                let attempt = self.attempts.remove(index);
+                println!("connect to {} failed:{}", attempt.address, e);
+                self.closed.extend(attempt.token);
+                self.record_failure(connect_error_reply(&e));
+
+                // no need to wait for the delay when nothing is in flight
+                match self.attempts.is_empty() {
+                    true => self.start_next(now),
+                    false => RaceState::Pending,
+                }
+            }
+        }
+    }
+
+    /// tokens of attempts closed since last call
+    pub fn take_closed(&mut self) -> Vec<Token> {
+        self.closed.drain(..).collect()
+    }
+
+    /// tokens of attempts still in flight
+    pub fn tokens(&self) -> Vec<Token> {
+        self.attempts.iter().filter_map(|attempt| attempt.token).collect()
+    }
+
+    fn start_next(&mut self, now: Instant) -> RaceState {
+        while let Some(address) = self.waiting.pop_front() {
+            match TcpStream::connect(&address) {
+                Ok(socket) => {
+                    self.attempts.push(Attempt { address, socket, token: None });
+                    self.next_attempt = now + self.delay;
+                    return RaceState::Pending;
+                }
+                Err(e) => {
+                    println!("connect to {} failed:{}", address, e);
+                    self.record_failure(connect_error_reply(&e));
+                }
+            }
+        }
+
+        match self.attempts.is_empty() {
+            true => RaceState::Failed(self.failure.clone().unwrap_or(ReplyType::HostUnreachable)),
+            false => RaceState::Pending,
+        }
+    }
+
+    fn record_failure(&mut self, reply: ReplyType) {
+        let replace = match &self.failure {
+            Some(failure) => specificity(&reply) > specificity(failure),
+            None => true,
+        };
+
+        if replace {
+            self.failure = Some(reply);
+        }
+    }
+}
+
+/// order addresses by alternating family, ipv6 goes first when there is one (rfc 8305 4)
+pub fn interleave(addresses: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (mut first, mut second): (VecDeque<_>, VecDeque<_>) = addresses.into_iter()
+        .partition(|address| address.is_ipv6());
+
+    let mut result = Vec::with_capacity(first.len() + second.len());
+    loop {
+        match (first.pop_front(), second.pop_front()) {
+            (None, None) => break,
+            (a, b) => result.extend(a.into_iter().chain(b)),
+        }
+    }
+
+    result
+}
+
+/// a refused connection tells more than an unreachable network, which tells
+/// more than a failure of server itself
+fn specificity(reply: &ReplyType) -> u8 {
+    match reply {
+        ReplyType::ConnectionRefuse => 5,
+        ReplyType::ConnectionNotAllowed => 4,
+        ReplyType::HostUnreachable => 3,
+        ReplyType::TTLExpired => 2,
+        ReplyType::NetWorkUnReachable => 1,
+        _ => 0,
+    }
+}
+
+fn is_connected(socket: &TcpStream) -> Result<bool, Error> {
+    match socket.take_error() {
+        Ok(Some(e)) | Err(e) => Err(e),
+        Ok(None) => match socket.peer_addr() {
+            Ok(_) => Ok(true),
+            Err(ref e) if e.kind() == ErrorKind::NotConnected => Ok(false),
+            Err(e) => Err(e),
+        },
+    }
+}
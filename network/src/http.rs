@@ -1,5 +1,6 @@
-use std::collections::HashMap;
-use self::HttpParseState::*;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
 
 /// 两种解码:
 /// 1. content-length
@@ -8,265 +9,609 @@ use self::HttpParseState::*;
 ///
 static CR: u8 = 13;
 static LF: u8 = 10;
-static CONTENT_LENGTH: &'static str = "content-length";
 
-#[derive(Debug, PartialEq)]
-pub enum HttpResult {
-    End(usize),
-    DataNotEnough,
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PacketType {
+    Request,
+    Response,
 }
 
-#[derive(Debug, PartialEq)]
-pub enum Kind {
-    End(usize),
-    DataNotEnough,
-    Continue(usize),
+/// judge http request/response is finished
+pub fn is_http_packet_finish(data: &[u8]) -> Result<bool, String> {
+    let mut index = 0;
+    loop {
+        let offset = match parse_line(&data[index..]) {
+            Ok((_, offset)) => offset,
+            Err(_) => return Ok(false),
+        };
+
+        index += offset;
+        if index == data.len() {
+            return Ok(true);
+        }
+    }
 }
 
-#[derive(Debug, PartialEq)]
-pub enum HttpParseState {
-    OtherRequest,
-    OtherResponse,
-    ContentLength(usize),
-    TransferEncoding,
+pub fn parse_first_line(data: &[u8]) -> Result<(String, usize), String> {
+    parse_line(data)
 }
 
-impl HttpParseState {
-    pub fn build_by_packet_type(packet_type: &PacketType) -> HttpParseState {
-        match packet_type {
-            Request => OtherRequest,
-            Response => OtherResponse,
-        }
+/// name and value of a header line, whitespace around the value is not part of it
+pub fn parse_http_header(line: &String) -> Result<(String, String), String> {
+    let (name, value) = match line.split_once(':') {
+        Some(result) => result,
+        None => return Err("header formatter error.".to_string()),
+    };
+
+    if name.is_empty() || !name.bytes().all(is_token_char) {
+        return Err(format!("header name is not correct:{}", name));
     }
+
+    Ok((name.to_string(), value.trim_matches(|c| c == ' ' || c == '\t').to_string()))
 }
 
-#[derive(Debug, PartialEq)]
-pub enum PacketType {
-    Request,
-    Response,
+/// line at the front of `data` without its line ending, and the offset after it
+pub fn parse_line(data: &[u8]) -> Result<(String, usize), String> {
+    let end = match data.iter().position(|byte| *byte == LF) {
+        Some(end) => end,
+        None => return Err("data not enough".to_string()),
+    };
+
+    let line = match end > 0 && data[end - 1] == CR {
+        true => &data[..end - 1],
+        false => &data[..end],
+    };
+    Ok((String::from_utf8_lossy(line).to_string(), end + 1))
 }
 
-pub fn get_end_of_http_packet(data: &[u8], packet_type: PacketType, socket_closed: bool)
-                              -> Result<HttpResult, String> {
-    // 1. parse initial line
-    // 2. parse http headers
-    // 3. receive util end
+/// size of a chunk size line, chunk extensions after `;` are ignored
+pub fn parse_chunk_size(data: &[u8]) -> Result<usize, String> {
+    let end = data.iter().position(|byte| *byte == b';').unwrap_or(data.len());
+    let digits = trim_whitespace(&data[..end]);
+
+    if digits.is_empty() || digits.len() > MAX_CHUNK_SIZE_DIGITS {
+        return Err(format!("chunk size is not correct:{}", String::from_utf8_lossy(data)));
+    }
 
-    let (line, initial_offset) = parse_line(data)?;
+    digits.iter().try_fold(0usize, |sum, hex| match (*hex as char).to_digit(16) {
+        Some(num) => Ok(sum * 16 + num as usize),
+        None => Err(format!("chunk size is not correct:{}", String::from_utf8_lossy(data))),
+    })
+}
 
-    let (transfer_type, headers_offset) =
-        parse_http_headers(&data[initial_offset..], &packet_type)?;
+/// heads larger than this are refused
+pub const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// hex digits of a chunk size which fit into usize
+const MAX_CHUNK_SIZE_DIGITS: usize = 15;
+
+/// error raised by `HttpParser`
+#[derive(Debug, Clone, PartialEq)]
+pub enum HttpError {
+    /// head is not complete within `MAX_HEAD_SIZE`
+    HeadTooLarge,
+    /// message does not follow http/1.x syntax
+    Malformed(String),
+    /// connection closed before the message is complete
+    Truncated,
+}
 
-    let pos = initial_offset + headers_offset;
-    let starter = &data[pos..];
-    match transfer_type {
-        TransferEncoding => read_with_transfer_encoding(starter),
-        OtherRequest => Ok(HttpResult::End(pos)),
-        OtherResponse => read_util_close(starter, socket_closed),
-        ContentLength(size) => read_with_length(starter, size),
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HttpError::HeadTooLarge => write!(f, "http head is larger than {} bytes", MAX_HEAD_SIZE),
+            HttpError::Malformed(msg) => write!(f, "malformed http message: {}", msg),
+            HttpError::Truncated => write!(f, "http message is truncated"),
+        }
     }
 }
 
-/// judge http request/response is finished
-pub fn is_http_packet_finish(data: &[u8]) -> Result<bool, String> {
-    let mut index = 0;
-    loop {
-        let (line, offset) = match parse_line(&data[index..]) {
-            Ok((line, offset)) => (line, offset),
-            Err(msg) => return Ok(false),
-        };
+impl Error for HttpError {}
 
-        index = index + offset;
-        if index == data.len() {
-            return Ok(true);
-        }
+fn malformed<T>(msg: String) -> Result<T, HttpError> {
+    Err(HttpError::Malformed(msg))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HttpVersion {
+    Http10,
+    Http11,
+}
 
+impl HttpVersion {
+    fn parse(version: &str) -> Result<HttpVersion, HttpError> {
+        match version {
+            "HTTP/1.0" => Ok(HttpVersion::Http10),
+            "HTTP/1.1" => Ok(HttpVersion::Http11),
+            _ => malformed(format!("version is not supported:{}", version)),
+        }
     }
+}
 
-    Ok(true)
+impl fmt::Display for HttpVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HttpVersion::Http10 => write!(f, "HTTP/1.0"),
+            HttpVersion::Http11 => write!(f, "HTTP/1.1"),
+        }
+    }
 }
 
-pub fn parse_first_line(data: &[u8]) -> Result<(String, usize), String> {
-    parse_line(data)
+/// header fields in the order they are received, names compare case-insensitively
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Headers {
+    fields: Vec<(String, String)>,
 }
 
-///
-/// four conditions:
-/// 1. content-length: use content-length
-/// 2. transfer-encoding: use chunk-size (over write content-length)
-/// 3. http-request and non of each(1/2): non http body
-/// 4. http-response and non of each(1/2): receive util connection close
-///
-///
-pub fn parse_http_headers(data: &[u8], packet_type: &PacketType)
-                          -> Result<(HttpParseState, usize), String> {
-    let mut index = 0;
-    let mut body_send_type = HttpParseState::build_by_packet_type(packet_type);
-    loop {
-        let (header, offset) = parse_line(&data[index..])?;
+impl Headers {
+    pub fn new() -> Headers {
+        Headers { fields: Vec::new() }
+    }
 
-        // only \r\n --- end of headers
-        if offset == 2 {
-            return Ok((body_send_type, index + 2));
-        }
+    /// add a field after the others, fields of the same name are kept
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.fields.push((name.to_string(), value.to_string()));
+    }
 
-        let (name, value) = parse_http_header(&header)?;
+    /// value of the first field named `name`
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields.iter()
+            .find(|(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
 
-        if name.to_ascii_lowercase() == "transfer-encoding".to_string()
-            && value.to_ascii_lowercase() == "chunked".to_string() {
-            body_send_type = TransferEncoding;
-        }
+    /// values of all fields named `name` in received order
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item=&'a str> + 'a {
+        self.fields.iter()
+            .filter(move |(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
 
-        if body_send_type != TransferEncoding
-            && name.to_ascii_lowercase() == "content-length".to_string() {
-            body_send_type = ContentLength(value.parse::<usize>().unwrap());
-        }
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
 
+    /// drop all fields named `name`
+    pub fn remove(&mut self, name: &str) {
+        self.fields.retain(|(field, _)| !field.eq_ignore_ascii_case(name));
+    }
 
-        index = index + offset;
-        if index == data.len() {
-            return Err("data not enough when parse http headers".to_string());
+    pub fn iter(&self) -> impl Iterator<Item=(&str, &str)> {
+        self.fields.iter().map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    fn encode(&self, data: &mut Vec<u8>) {
+        for (name, value) in self.fields.iter() {
+            data.extend_from_slice(name.as_bytes());
+            data.extend_from_slice(b": ");
+            data.extend_from_slice(value.as_bytes());
+            data.extend_from_slice(b"\r\n");
         }
+        data.extend_from_slice(b"\r\n");
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub method: String,
+    pub target: String,
+    pub version: HttpVersion,
+    pub headers: Headers,
+}
 
+impl Request {
+    /// request line and headers as sent on the wire
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = format!("{} {} {}\r\n", self.method, self.target, self.version).into_bytes();
+        self.headers.encode(&mut data);
+        data
     }
 }
 
-pub fn parse_http_header(line: &String) -> Result<(String, String), String> {
-    let new_line = line.replace(" ", "");
-    let mut items: Vec<&str> = new_line.splitn(2, ":").collect();
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub version: HttpVersion,
+    pub status: u16,
+    pub reason: String,
+    pub headers: Headers,
+}
 
-    if items.len() < 2 {
-        return Err("header formatter error.".to_string());
+impl Response {
+    /// status line and headers as sent on the wire
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = format!("{} {} {}\r\n", self.version, self.status, self.reason).into_bytes();
+        self.headers.encode(&mut data);
+        data
     }
+}
 
-    let name = String::from(items.remove(0));
-    let value = String::from(items.remove(0));
-    Ok((name, value))
+/// what `HttpParser::parse` found at the front of its input
+#[derive(Debug, Clone, PartialEq)]
+pub enum HttpEvent {
+    Request(Request),
+    Response(Response),
+    /// body bytes as received, chunk framing and trailer fields included
+    Body,
+    /// trailer fields of a chunked body, they are part of the body bytes before
+    Trailers(Headers),
+    /// message is complete, the parser waits for the next head
+    End,
 }
 
+/// how the body of a message in flight ends
+#[derive(Debug, Clone, PartialEq)]
+enum Framing {
+    /// bytes left
+    Length(usize),
+    Chunked(Chunk),
+    /// message ends when the connection is closed
+    UntilClose,
+}
 
-pub fn parse_line(data: &[u8]) -> Result<(String, usize), String> {
-    let start = 0;
-    let mut cur = 0;
-    loop {
-        if cur >= data.len() {
-            return Err("data not enough".to_string());
-        }
+/// position in a chunked body
+#[derive(Debug, Clone, PartialEq)]
+enum Chunk {
+    Size,
+    /// bytes left of chunk data
+    Data(usize),
+    DataEnd,
+    Trailer(Headers),
+}
 
-        let next_byte = data[cur] & 0xFF;
-        if next_byte == CR {
-            cur = cur + 1;
-            continue;
-        }
+#[derive(Debug, Clone, PartialEq)]
+enum ParseState {
+    Head,
+    Body(Framing),
+    Trailers(Headers),
+    End,
+}
 
-        if next_byte == LF {
-            if cur - 1 == start {
-                return Ok((String::from(""), cur + 1));
-            }
+/// incremental parser of the http/1.x messages of one direction of a connection.
+///
+/// `parse` is called with all bytes received and not consumed yet, it returns
+/// one event and how many bytes at the front of the input it covers. `None` means
+/// more data is needed, nothing is kept by the parser between calls but its state.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpParser {
+    packet_type: PacketType,
+    state: ParseState,
+    /// methods of requests whose responses are not parsed yet, oldest first
+    methods: VecDeque<String>,
+}
 
-            let array = &data[start..cur - 1];
-            return Ok((String::from_utf8_lossy(array).to_string(), cur + 1));
+impl HttpParser {
+    pub fn new(packet_type: PacketType) -> HttpParser {
+        HttpParser {
+            packet_type,
+            state: ParseState::Head,
+            methods: VecDeque::new(),
         }
-
-        cur = cur + 1;
     }
-}
 
-/// read content in content_length
-pub fn read_with_length(data: &[u8], total: usize) -> Result<HttpResult, String> {
-    let mut to_read = total;
-    let len = data.len();
+    /// a request with `method` is sent, the length of its response body depends on it.
+    /// responses without a known request are taken as answers to GET
+    pub fn expect_response(&mut self, method: &str) {
+        self.methods.push_back(method.to_string());
+    }
 
-    if len < total {
-        return Ok(HttpResult::DataNotEnough);
+    /// a request still waits for its final response, interim 1xx ones do not count
+    pub fn expects_response(&self) -> bool {
+        !self.methods.is_empty()
     }
 
-    Ok(HttpResult::End(total))
-}
+    pub fn parse(&mut self, data: &[u8]) -> Result<Option<(HttpEvent, usize)>, HttpError> {
+        loop {
+            match &mut self.state {
+                ParseState::Head => {
+                    let len = match head_len(data) {
+                        Some(len) => len,
+                        None if data.len() >= MAX_HEAD_SIZE => return Err(HttpError::HeadTooLarge),
+                        None => return Ok(None),
+                    };
+
+                    let event = parse_head(&data[..len], &self.packet_type)?;
+                    let framing = match &event {
+                        HttpEvent::Request(request) => body_framing(&request.headers, &self.packet_type)?,
+                        HttpEvent::Response(response) => {
+                            // interim responses come before the final one of the same request
+                            let method = match response.status {
+                                100..=199 if response.status != 101 => self.methods.front().cloned(),
+                                _ => self.methods.pop_front(),
+                            };
+                            response_framing(method.as_deref(), response)?
+                        }
+                        _ => unreachable!(),
+                    };
+                    self.state = match framing {
+                        Framing::Length(0) => ParseState::End,
+                        framing => ParseState::Body(framing),
+                    };
+                    return Ok(Some((event, len)));
+                }
+                ParseState::Body(framing) => {
+                    let (used, end) = frame(framing, data)?;
+                    if end {
+                        self.state = match std::mem::replace(framing, Framing::Length(0)) {
+                            Framing::Chunked(Chunk::Trailer(trailers)) if !trailers.is_empty() =>
+                                ParseState::Trailers(trailers),
+                            _ => ParseState::End,
+                        };
+                    }
+
+                    match used {
+                        0 if !end => return Ok(None),
+                        0 => continue,
+                        used => return Ok(Some((HttpEvent::Body, used))),
+                    }
+                }
+                ParseState::Trailers(trailers) => {
+                    let trailers = std::mem::take(trailers);
+                    self.state = ParseState::End;
+                    return Ok(Some((HttpEvent::Trailers(trailers), 0)));
+                }
+                ParseState::End => {
+                    self.state = ParseState::Head;
+                    return Ok(Some((HttpEvent::End, 0)));
+                }
+            }
+        }
+    }
 
-/// read content in transfer-encoding
-pub fn read_with_transfer_encoding(data: &[u8]) -> Result<HttpResult, String> {
-    // todo receive dst response
-    let mut offset = 0 as usize;
-    loop {
-        let result = parse_chunk(&data[offset..])?;
-        let len = match result {
-            Kind::End(chunk_size) => {
-                return Ok(HttpResult::End(offset+chunk_size));
+    /// connection is closed, which ends a body read until close.
+    /// `End` is reported by next `parse`
+    pub fn eof(&mut self) -> Result<(), HttpError> {
+        match self.state {
+            ParseState::Head | ParseState::Trailers(_) | ParseState::End => Ok(()),
+            ParseState::Body(Framing::UntilClose) => {
+                self.state = ParseState::End;
+                Ok(())
             }
-            Kind::Continue(offset) => offset,
-            Kind::DataNotEnough => {
-                return Ok(HttpResult::DataNotEnough)
-            },
-        };
+            ParseState::Body(_) => Err(HttpError::Truncated),
+        }
+    }
 
-        offset = offset + len;
+    /// no part of a message is parsed yet
+    pub fn is_idle(&self) -> bool {
+        self.state == ParseState::Head
     }
+}
 
-    //Ok(end)
+fn is_token_char(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte)
 }
 
-/// read util socket closed
-pub fn read_util_close(data: &[u8], socket_closed: bool) -> Result<HttpResult, String> {
-    match socket_closed {
-        true => Ok(HttpResult::End(data.len())),
-        false => Ok(HttpResult::DataNotEnough),
-    }
+fn trim_whitespace(data: &[u8]) -> &[u8] {
+    let start = data.iter().position(|byte| *byte != b' ' && *byte != b'\t').unwrap_or(data.len());
+    let end = data.iter().rposition(|byte| *byte != b' ' && *byte != b'\t').map_or(start, |end| end + 1);
+    &data[start..end]
 }
 
-pub fn parse_chunk(data: &[u8]) -> Result<Kind, String> {
-    let (line, first_offset) = parse_line(data)?;
-    let raw_data = &data[0..first_offset - 2];
-    let chunk_size = parse_chunk_size(raw_data);
+/// length of a complete head at the front of `data`, `None` while more is needed.
+/// the head ends with an empty line, lines may end with a bare lf (rfc 9112 section 2.2)
+fn head_len(data: &[u8]) -> Option<usize> {
+    let searched = &data[..data.len().min(MAX_HEAD_SIZE)];
+    searched.iter().enumerate()
+        .filter(|(_, byte)| **byte == LF)
+        .find_map(|(end, _)| match &searched[end + 1..] {
+            [b'\n', ..] => Some(end + 2),
+            [b'\r', b'\n', ..] => Some(end + 3),
+            _ => None,
+        })
+}
 
-    if chunk_size == 0 {
-        let offset = parse_chunk_end(&data[first_offset..])?;
-        return Ok(Kind::End(offset + first_offset));
+/// a line ending with crlf, or a bare lf, at the front of `data` and the offset
+/// after it, `None` while it is incomplete
+fn complete_line(data: &[u8]) -> Result<Option<(&[u8], usize)>, HttpError> {
+    match data.iter().position(|byte| *byte == LF) {
+        Some(end) if end > 0 && data[end - 1] == CR => Ok(Some((&data[..end - 1], end + 1))),
+        Some(end) => Ok(Some((&data[..end], end + 1))),
+        None if data.len() >= MAX_HEAD_SIZE => malformed("line is too long.".to_string()),
+        None => Ok(None),
     }
+}
+
+fn parse_head(head: &[u8], packet_type: &PacketType) -> Result<HttpEvent, HttpError> {
+    let (first, mut index) = match complete_line(head)? {
+        Some((line, offset)) => (String::from_utf8_lossy(line).to_string(), offset),
+        None => return malformed("head is not complete.".to_string()),
+    };
 
-    let end_pos = first_offset + chunk_size;
-    if data.len() < end_pos + 2 {
-        return Ok(Kind::DataNotEnough);
+    let mut headers = Headers::new();
+    loop {
+        let (line, offset) = match complete_line(&head[index..])? {
+            Some(result) => result,
+            None => return malformed("head is not complete.".to_string()),
+        };
+
+        index += offset;
+        if line.is_empty() {
+            break;
+        }
+        parse_field(line, &mut headers)?;
     }
 
-    let first_end = data[end_pos];
-    let second_end = data[end_pos + 1];
+    match packet_type {
+        PacketType::Request => Ok(HttpEvent::Request(parse_request_line(&first, headers)?)),
+        PacketType::Response => Ok(HttpEvent::Response(parse_status_line(&first, headers)?)),
+    }
+}
 
-    if first_end != CR || second_end != LF {
-        return Err("chunk end is not correct.".to_string());
+/// `name: value` of a header or trailer line
+fn parse_field(line: &[u8], headers: &mut Headers) -> Result<(), HttpError> {
+    // obsolete line folding is rejected as rfc 9112 allows
+    if line.first() == Some(&b' ') || line.first() == Some(&b'\t') {
+        return malformed("folded header line.".to_string());
     }
 
-    Ok(Kind::Continue(end_pos + 2))
+    let line = String::from_utf8_lossy(line).to_string();
+    let (name, value) = parse_http_header(&line).map_err(HttpError::Malformed)?;
+    headers.append(&name, &value);
+    Ok(())
 }
 
+fn parse_request_line(line: &str, headers: Headers) -> Result<Request, HttpError> {
+    let items: Vec<&str> = line.split(' ').collect();
+    match items.as_slice() {
+        [method, target, version] if !method.is_empty() && method.bytes().all(is_token_char) && !target.is_empty() =>
+            Ok(Request {
+                method: method.to_string(),
+                target: target.to_string(),
+                version: HttpVersion::parse(version)?,
+                headers,
+            }),
+        _ => malformed(format!("request line is not correct:{}", line)),
+    }
+}
 
-pub fn parse_chunk_end(data: &[u8]) -> Result<usize, String> {
-    let (line, offset) = parse_line(data)?;
+/// `HTTP/1.1 200 OK`, the reason phrase may be empty
+fn parse_status_line(line: &str, headers: Headers) -> Result<Response, HttpError> {
+    let mut items = line.splitn(3, ' ');
+    let version = HttpVersion::parse(items.next().unwrap_or(""))?;
+    let status = match items.next() {
+        Some(status) if status.len() == 3 && status.bytes().all(|byte| byte.is_ascii_digit()) =>
+            status.parse::<u16>().map_err(|_| HttpError::Malformed(format!("status is not correct:{}", status)))?,
+        _ => return malformed(format!("status line is not correct:{}", line)),
+    };
+
+    Ok(Response {
+        version,
+        status,
+        reason: items.next().unwrap_or("").to_string(),
+        headers,
+    })
+}
 
-    Ok(offset)
+/// responses which never have a body whatever their headers say, rfc 9112 section 6.3
+fn response_framing(method: Option<&str>, response: &Response) -> Result<Framing, HttpError> {
+    let status = response.status;
+    let no_body = match method {
+        Some("HEAD") => true,
+        // the connection becomes a tunnel after a successful connect
+        Some("CONNECT") if (200..300).contains(&status) => true,
+        _ => (100..200).contains(&status) || status == 204 || status == 304,
+    };
+
+    match no_body {
+        true => Ok(Framing::Length(0)),
+        false => body_framing(&response.headers, &PacketType::Response),
+    }
 }
 
-pub fn parse_chunk_size(data: &[u8]) -> usize {
-    let total = data.len();
-    let mut sum = 0 as usize;
-    let mut base = 1 as usize;
+/// how the body after a head ends, a message with both transfer-encoding and
+/// content-length is refused as it may be read differently by each hop
+fn body_framing(headers: &Headers, packet_type: &PacketType) -> Result<Framing, HttpError> {
+    let codings: Vec<String> = headers.get_all("transfer-encoding")
+        .flat_map(|value| value.split(','))
+        .map(|coding| coding.trim().to_ascii_lowercase())
+        .filter(|coding| !coding.is_empty())
+        .collect();
+
+    if let Some(last) = codings.last() {
+        if headers.contains("content-length") {
+            return malformed("content-length conflicts with transfer-encoding.".to_string());
+        }
 
-    // todo skip other chars
-    for i in 0..total {
-        let index = total - i - 1;
-        let hex = data[index];
+        return match (last.as_str(), packet_type) {
+            ("chunked", _) => Ok(Framing::Chunked(Chunk::Size)),
+            // length of a request body can not be told without chunked
+            (_, PacketType::Request) => malformed(format!("transfer coding is not supported:{}", last)),
+            (_, PacketType::Response) => Ok(Framing::UntilClose),
+        };
+    }
+
+    match content_length(headers)? {
+        Some(length) => Ok(Framing::Length(length)),
+        None => match packet_type {
+            PacketType::Request => Ok(Framing::Length(0)),
+            PacketType::Response => Ok(Framing::UntilClose),
+        },
+    }
+}
 
-        if hex >= 48 && hex <= 57 {
-            let num = (hex - 48) as usize;
-            sum = sum + num * base;
+/// content-length of a message, repeated values must all be the same
+fn content_length(headers: &Headers) -> Result<Option<usize>, HttpError> {
+    let mut length = None;
+    for value in headers.get_all("content-length").flat_map(|value| value.split(',')) {
+        let value = value.trim();
+        if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_digit()) {
+            return malformed(format!("content-length is not correct:{}", value));
         }
 
-        if hex >= 97 && hex <= 102 {
-            let num = (hex - 87) as usize;
-            sum = sum + num * base;
+        let value = value.parse::<usize>()
+            .map_err(|_| HttpError::Malformed(format!("content-length is too large:{}", value)))?;
+        match length {
+            Some(length) if length != value => return malformed("content-length values differ.".to_string()),
+            _ => length = Some(value),
         }
-        base = base * 16;
     }
 
-    sum
-}
\ No newline at end of file
+    Ok(length)
+}
+
+/// number of bytes at the front of `data` which belong to the body,
+/// and whether the body ends with them
+fn frame(framing: &mut Framing, data: &[u8]) -> Result<(usize, bool), HttpError> {
+    let mut used = 0;
+    loop {
+        let rest = &data[used..];
+        match framing {
+            Framing::Length(left) => {
+                let size = (*left).min(rest.len());
+                *left -= size;
+                return Ok((used + size, *left == 0));
+            }
+            Framing::UntilClose => return Ok((data.len(), false)),
+            Framing::Chunked(Chunk::Size) => {
+                let (line, offset) = match complete_line(rest)? {
+                    Some(result) => result,
+                    None => return Ok((used, false)),
+                };
+
+                used += offset;
+                *framing = match parse_chunk_size(line).map_err(HttpError::Malformed)? {
+                    0 => Framing::Chunked(Chunk::Trailer(Headers::new())),
+                    size => Framing::Chunked(Chunk::Data(size)),
+                };
+            }
+            Framing::Chunked(Chunk::Data(left)) => {
+                let size = (*left).min(rest.len());
+                used += size;
+                *left -= size;
+                if *left > 0 {
+                    return Ok((used, false));
+                }
+                *framing = Framing::Chunked(Chunk::DataEnd);
+            }
+            Framing::Chunked(Chunk::DataEnd) => {
+                used += match rest {
+                    [b'\n', ..] => 1,
+                    [b'\r', b'\n', ..] => 2,
+                    [] | [b'\r'] => return Ok((used, false)),
+                    _ => return malformed("chunk end is not correct.".to_string()),
+                };
+                *framing = Framing::Chunked(Chunk::Size);
+            }
+            // trailer fields end with an empty line
+            Framing::Chunked(Chunk::Trailer(trailers)) => {
+                let (line, offset) = match complete_line(rest)? {
+                    Some(result) => result,
+                    None => return Ok((used, false)),
+                };
+
+                used += offset;
+                if line.is_empty() {
+                    return Ok((used, true));
+                }
+                parse_field(line, trailers)?;
+            }
+        }
+    }
+}
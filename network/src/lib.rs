@@ -2,5 +2,17 @@ pub mod client;
 pub mod server;
 pub mod http;
 pub mod tokens;
+pub mod auth;
+pub mod policy;
+pub mod udp;
+pub mod resolver;
+pub mod verifier;
+pub mod workers;
+pub mod dns_cache;
+pub mod happy_eyeballs;
+pub mod buffer;
+pub mod timer;
+pub mod registry;
 mod io;
+#[cfg(test)]
 mod unit_test;
\ No newline at end of file
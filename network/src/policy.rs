@@ -0,0 +1,218 @@
+use std::fs::read_to_string;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use protocol::packet::{AuthType, UserPassAuthRequest};
+use crate::auth::{Authenticator, Identity};
+
+/// ip network like `10.0.0.0/8` or `fd00::/8`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cidr {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn new(address: IpAddr, prefix: u8) -> Result<Cidr, String> {
+        let max = match address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+
+        if prefix > max {
+            return Err(format!("prefix {} is too long for {}", prefix, address));
+        }
+
+        Ok(Cidr { address, prefix })
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        // ipv4 clients accepted on an ipv6 listener show up as mapped addresses
+        let ip = match ip {
+            IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+                Some(v4) if self.address.is_ipv4() => IpAddr::V4(v4),
+                _ => *ip,
+            },
+            _ => *ip,
+        };
+
+        match (&self.address, &ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) =>
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix),
+            (IpAddr::V6(network), IpAddr::V6(ip)) =>
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix),
+            _ => false,
+        }
+    }
+}
+
+/// accepts `address/prefix`, a bare address is a single host
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Cidr, String> {
+        let (address, prefix) = match s.find('/') {
+            Some(pos) => (&s[..pos], Some(&s[pos + 1..])),
+            None => (s, None),
+        };
+
+        let address = match IpAddr::from_str(address) {
+            Ok(address) => address,
+            Err(_) => return Err(format!("invalid network address: {}", s)),
+        };
+
+        let prefix = match prefix {
+            Some(prefix) => match prefix.parse::<u8>() {
+                Ok(prefix) => prefix,
+                Err(_) => return Err(format!("invalid network prefix: {}", s)),
+            },
+            None if address.is_ipv4() => 32,
+            None => 128,
+        };
+
+        Cidr::new(address, prefix)
+    }
+}
+
+fn prefix_matches(network: &[u8], ip: &[u8], prefix: u8) -> bool {
+    let full = usize::from(prefix / 8);
+    if network[..full] != ip[..full] {
+        return false;
+    }
+
+    let rest = prefix % 8;
+    if rest == 0 {
+        return true;
+    }
+
+    let mask = 0xffu8 << (8 - rest);
+    network[full] & mask == ip[full] & mask
+}
+
+/// auth-methods allowed for clients, in the order the server prefers them.
+/// the first rule matching client address wins, otherwise defaults are used.
+#[derive(Debug, Clone)]
+pub struct AuthPolicy {
+    defaults: Vec<AuthType>,
+    rules: Vec<(Cidr, Vec<AuthType>)>,
+}
+
+impl AuthPolicy {
+    pub fn new(defaults: Vec<AuthType>) -> AuthPolicy {
+        AuthPolicy {
+            defaults,
+            rules: Vec::new(),
+        }
+    }
+
+    pub fn add_rule(&mut self, network: Cidr, methods: Vec<AuthType>) {
+        self.rules.push((network, methods));
+    }
+
+    /// load policy from a file with one `<network|*> <method>[,<method>...]` per line,
+    /// methods are `none` and `password`. `*` sets the defaults, without it clients
+    /// matching no rule must use password.
+    ///
+    /// ```text
+    /// 10.0.0.0/8 none,password
+    /// * password
+    /// ```
+    pub fn load(path: &str) -> Result<AuthPolicy, String> {
+        let content = match read_to_string(path) {
+            Ok(content) => content,
+            Err(e) => return Err(format!("read policy file {} failed: {}", path, e)),
+        };
+
+        let mut policy = AuthPolicy::new(vec![AuthType::NamePassword]);
+        for (index, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut fields = line.split_whitespace();
+            let (network, methods) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(network), Some(methods), None) => (network, parse_methods(methods)?),
+                _ => return Err(format!("policy file {} line {} should be <network> <methods>"
+                                        , path, index + 1)),
+            };
+
+            match network {
+                "*" => policy.defaults = methods,
+                network => policy.add_rule(network.parse()?, methods),
+            }
+        }
+
+        Ok(policy)
+    }
+
+    /// methods allowed for this client, ordered by preference
+    pub fn methods_for(&self, client: &IpAddr) -> &[AuthType] {
+        for (network, methods) in self.rules.iter() {
+            if network.contains(client) {
+                return methods;
+            }
+        }
+
+        &self.defaults
+    }
+
+    /// first allowed method which is offered by client
+    pub fn select(&self, client: &IpAddr, offered: &[AuthType]) -> Option<AuthType> {
+        self.methods_for(client).iter()
+            .find(|method| offered.contains(method))
+            .cloned()
+    }
+}
+
+fn parse_methods(methods: &str) -> Result<Vec<AuthType>, String> {
+    let mut result = Vec::new();
+    for method in methods.split(',') {
+        let method = match method {
+            "none" => AuthType::Non,
+            "password" => AuthType::NamePassword,
+            other => return Err(format!("unknown auth-method: {}", other)),
+        };
+
+        if !result.contains(&method) {
+            result.push(method);
+        }
+    }
+
+    Ok(result)
+}
+
+/// negotiate methods by an `AuthPolicy`, name/password credentials are checked by `users`
+pub struct PolicyAuthenticator {
+    policy: AuthPolicy,
+    users: Arc<dyn Authenticator>,
+}
+
+impl PolicyAuthenticator {
+    pub fn new(policy: AuthPolicy, users: Arc<dyn Authenticator>) -> PolicyAuthenticator {
+        PolicyAuthenticator { policy, users }
+    }
+
+    pub fn policy(&self) -> &AuthPolicy {
+        &self.policy
+    }
+}
+
+impl Authenticator for PolicyAuthenticator {
+    fn select_method(&self, client: &SocketAddr, offered: &[AuthType]) -> Option<AuthType> {
+        self.policy.select(&client.ip(), offered)
+    }
+
+    fn authenticate(&self, client: &SocketAddr, method: &AuthType
+                    , credentials: Option<&UserPassAuthRequest>) -> Option<Identity> {
+        if !self.policy.methods_for(&client.ip()).contains(method) {
+            return None;
+        }
+
+        match method {
+            AuthType::Non => Some(Identity::Anonymous),
+            AuthType::NamePassword => self.users.authenticate(client, method, credentials),
+            _ => None,
+        }
+    }
+}
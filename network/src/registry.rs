@@ -0,0 +1,202 @@
+use mio::{Poll, Token};
+use mio::net::{TcpListener, TcpStream};
+use std::net::Shutdown;
+use std::time::Instant;
+use crate::server::ChildHandler;
+use crate::udp::UdpRelay;
+
+/// low bits of a token tell which socket of a connection it belongs to
+const SIDE_BITS: u32 = 8;
+const SIDE_MASK: usize = (1 << SIDE_BITS) - 1;
+
+/// bits above the side hold generation of the slot
+#[cfg(target_pointer_width = "64")]
+const GENERATION_BITS: u32 = 16;
+#[cfg(not(target_pointer_width = "64"))]
+const GENERATION_BITS: u32 = 8;
+const GENERATION_MASK: usize = (1 << GENERATION_BITS) - 1;
+
+const INDEX_SHIFT: u32 = SIDE_BITS + GENERATION_BITS;
+
+/// tokens below this are never handed out by a registry, they are free for
+/// listeners and other sockets of the server itself
+pub const RESERVED_TOKENS: usize = 1 << INDEX_SHIFT;
+
+const FIRST_ATTEMPT: usize = 4;
+
+/// number of different connect attempt tokens of a connection, they are reused in turn
+pub const ATTEMPT_TOKENS: usize = SIDE_MASK + 1 - FIRST_ATTEMPT;
+
+/// socket of a connection a token stands for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Side {
+    Client,
+    Upstream,
+    BindListener,
+    UdpRelay,
+    /// connect attempt while racing addresses of destination, see `ConnectRace`
+    Attempt(usize),
+}
+
+impl Side {
+    fn bits(self) -> usize {
+        match self {
+            Side::Client => 0,
+            Side::Upstream => 1,
+            Side::BindListener => 2,
+            Side::UdpRelay => 3,
+            Side::Attempt(n) => FIRST_ATTEMPT + n % ATTEMPT_TOKENS,
+        }
+    }
+
+    fn from_bits(bits: usize) -> Side {
+        match bits {
+            0 => Side::Client,
+            1 => Side::Upstream,
+            2 => Side::BindListener,
+            3 => Side::UdpRelay,
+            n => Side::Attempt(n - FIRST_ATTEMPT),
+        }
+    }
+}
+
+/// token of socket `side` of the connection `token` belongs to
+pub fn side_token(token: Token, side: Side) -> Token {
+    Token((token.0 & !SIDE_MASK) | side.bits())
+}
+
+/// which socket of a connection `token` stands for, `None` for reserved tokens
+pub fn side_of(token: Token) -> Option<Side> {
+    match token.0 < RESERVED_TOKENS {
+        true => None,
+        false => Some(Side::from_bits(token.0 & SIDE_MASK)),
+    }
+}
+
+/// a client connection with everything opened for its request
+pub struct Connection {
+    pub handler: ChildHandler,
+    pub client: TcpStream,
+    pub upstream: Option<TcpStream>,
+    pub bind_listener: Option<TcpListener>,
+    pub udp_relay: Option<UdpRelay>,
+    /// earliest deadline the connection waits for on the timer wheel
+    pub scheduled: Option<Instant>,
+}
+
+impl Connection {
+    pub fn new(handler: ChildHandler, client: TcpStream) -> Connection {
+        Connection {
+            handler,
+            client,
+            upstream: None,
+            bind_listener: None,
+            udp_relay: None,
+            scheduled: None,
+        }
+    }
+
+    /// deregister and shut down all sockets, connect attempts close when handler drops
+    pub fn close(self, poll: &Poll) {
+        let _ = poll.deregister(&self.client);
+        let _ = self.client.shutdown(Shutdown::Both);
+
+        if let Some(upstream) = &self.upstream {
+            let _ = poll.deregister(upstream);
+            let _ = upstream.shutdown(Shutdown::Both);
+        }
+
+        if let Some(listener) = &self.bind_listener {
+            let _ = poll.deregister(listener);
+        }
+
+        if let Some(relay) = &self.udp_relay {
+            let _ = poll.deregister(relay.socket());
+        }
+    }
+}
+
+struct Slot {
+    generation: usize,
+    connection: Option<Connection>,
+}
+
+/// connections of a server kept in a slab.
+///
+/// a token encodes slot index, slot generation and `Side`, so every socket of
+/// a connection finds the same entry. freed slots are reused with a new
+/// generation, events and timers still carrying the old token find nothing.
+#[derive(Default)]
+pub struct Registry {
+    slots: Vec<Slot>,
+    free: Vec<usize>,
+    len: usize,
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        Registry {
+            slots: Vec::new(),
+            free: Vec::new(),
+            len: 0,
+        }
+    }
+
+    /// store the connection built by `f` from its client token
+    pub fn insert_with<F: FnOnce(Token) -> Connection>(&mut self, f: F) -> &mut Connection {
+        let index = match self.free.pop() {
+            Some(index) => index,
+            None => {
+                self.slots.push(Slot { generation: 0, connection: None });
+                self.slots.len() - 1
+            }
+        };
+
+        let slot = &mut self.slots[index];
+        let token = Token(((index + 1) << INDEX_SHIFT) | (slot.generation << SIDE_BITS));
+        self.len += 1;
+        slot.connection.insert(f(token))
+    }
+
+    /// connection of a token of any side, `None` once it is removed
+    pub fn get(&self, token: Token) -> Option<&Connection> {
+        let index = self.index_of(token)?;
+        self.slots[index].connection.as_ref()
+    }
+
+    pub fn get_mut(&mut self, token: Token) -> Option<&mut Connection> {
+        let index = self.index_of(token)?;
+        self.slots[index].connection.as_mut()
+    }
+
+    /// take the connection out, its slot is reused by a later insert
+    pub fn remove(&mut self, token: Token) -> Option<Connection> {
+        let index = self.index_of(token)?;
+        let slot = &mut self.slots[index];
+        let connection = slot.connection.take()?;
+
+        slot.generation = (slot.generation + 1) & GENERATION_MASK;
+        self.free.push(index);
+        self.len -= 1;
+        Some(connection)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn index_of(&self, token: Token) -> Option<usize> {
+        side_of(token)?;
+        let index = (token.0 >> INDEX_SHIFT) - 1;
+        let generation = (token.0 >> SIDE_BITS) & GENERATION_MASK;
+
+        match self.slots.get(index) {
+            Some(slot) if slot.generation == generation => Some(index),
+            _ => None,
+        }
+    }
+}
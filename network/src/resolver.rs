@@ -0,0 +1,176 @@
+extern crate dns_lookup;
+
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use hickory_resolver::config::LookupIpStrategy;
+use hickory_resolver::error::ResolveErrorKind;
+use hickory_resolver::system_conf::read_system_conf;
+use mio::{Registration, Token};
+use crate::dns_cache::{DnsCache, DnsCacheConfig};
+use crate::workers::WorkerPool;
+
+/// default number of resolver threads
+pub const DEFAULT_RESOLVER_THREADS: usize = 4;
+
+struct Lookup {
+    token: Token,
+    domain: String,
+}
+
+enum Answer {
+    Found(Vec<IpAddr>, Option<Duration>),
+    /// domain does not exist, with negative ttl when server tells one
+    NotFound(Option<Duration>),
+    Failed(String),
+}
+
+struct Finished {
+    token: Token,
+    domain: String,
+    answer: Answer,
+}
+
+/// result of a lookup, `token` is the one given to `Resolver::resolve`
+pub struct Resolved {
+    pub token: Token,
+    pub domain: String,
+    pub result: Result<Vec<IpAddr>, String>,
+}
+
+/// resolve domains on a thread pool so the event loop never blocks on dns.
+///
+/// `registration` becomes readable when results are waiting, the event loop
+/// should then take all of them with `try_recv`. answers are cached, a cached
+/// domain is delivered the same way without a lookup.
+pub struct Resolver {
+    pool: WorkerPool<Lookup, Finished>,
+    cache: DnsCache,
+    cached: VecDeque<Resolved>,
+}
+
+impl Resolver {
+    pub fn new(threads: usize) -> Resolver {
+        Resolver::with_cache(threads, DnsCacheConfig::default())
+    }
+
+    pub fn with_cache(threads: usize, config: DnsCacheConfig) -> Resolver {
+        Resolver {
+            pool: WorkerPool::new("resolver", threads, resolve_with_system_conf),
+            cache: DnsCache::new(config),
+            cached: VecDeque::new(),
+        }
+    }
+
+    pub fn registration(&self) -> &Registration {
+        self.pool.registration()
+    }
+
+    pub fn cache(&self) -> &DnsCache {
+        &self.cache
+    }
+
+    /// queue a lookup, its result is delivered with the same token
+    pub fn resolve(&mut self, token: Token, domain: &str) {
+        if let Some(ips) = self.cache.get(domain, Instant::now()) {
+            let result = match ips {
+                Some(ips) => Ok(ips),
+                None => Err(format!("{} does not exist (cached).", domain)),
+            };
+            self.cached.push_back(Resolved {
+                token,
+                domain: domain.to_string(),
+                result,
+            });
+            self.pool.wake();
+            return;
+        }
+
+        self.pool.submit(Lookup {
+            token,
+            domain: domain.to_string(),
+        });
+    }
+
+    /// next finished lookup, readiness is cleared once nothing is left
+    pub fn try_recv(&mut self) -> Option<Resolved> {
+        if let Some(resolved) = self.cached.pop_front() {
+            return Some(resolved);
+        }
+
+        let finished = self.pool.try_recv()?;
+
+        let now = Instant::now();
+        let result = match finished.answer {
+            Answer::Found(ips, ttl) => {
+                self.cache.insert(&finished.domain, ips.clone(), ttl, now);
+                Ok(ips)
+            }
+            Answer::NotFound(ttl) => {
+                self.cache.insert_negative(&finished.domain, ttl, now);
+                Err(format!("{} does not exist.", finished.domain))
+            }
+            // failures like timeouts are not cached
+            Answer::Failed(msg) => Err(msg),
+        };
+
+        Some(Resolved {
+            token: finished.token,
+            domain: finished.domain,
+            result,
+        })
+    }
+}
+
+/// lookup function of a worker thread, each of them has its own dns client
+fn resolve_with_system_conf() -> impl FnMut(Lookup) -> Finished {
+    // ttls are only known from a dns client, system lookup is the fallback
+    let dns = match read_system_conf() {
+        Ok((config, mut options)) => {
+            options.cache_size = 0;
+            // both families are raced by connect, the answer holds all of them
+            options.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+            hickory_resolver::Resolver::new(config, options).ok()
+        }
+        Err(_) => None,
+    };
+
+    move |lookup| {
+        let answer = match &dns {
+            Some(dns) => lookup_with_ttl(dns, &lookup.domain),
+            None => lookup_system(&lookup.domain),
+        };
+
+        Finished {
+            token: lookup.token,
+            domain: lookup.domain,
+            answer,
+        }
+    }
+}
+
+fn lookup_with_ttl(dns: &hickory_resolver::Resolver, domain: &str) -> Answer {
+    match dns.lookup_ip(domain) {
+        Ok(lookup) => {
+            let ips: Vec<IpAddr> = lookup.iter().collect();
+            let ttl = lookup.valid_until().saturating_duration_since(Instant::now());
+            match ips.is_empty() {
+                true => Answer::NotFound(None),
+                false => Answer::Found(ips, Some(ttl)),
+            }
+        }
+        Err(e) => match e.kind() {
+            ResolveErrorKind::NoRecordsFound { negative_ttl, .. } =>
+                Answer::NotFound(negative_ttl.map(|ttl| Duration::from_secs(u64::from(ttl)))),
+            _ => Answer::Failed(format!("lookup {} failed: {}", domain, e)),
+        },
+    }
+}
+
+fn lookup_system(domain: &str) -> Answer {
+    match dns_lookup::lookup_host(domain) {
+        Ok(ips) if ips.is_empty() => Answer::NotFound(None),
+        Ok(ips) => Answer::Found(ips, None),
+        Err(e) => Answer::Failed(format!("lookup {} failed: {}", domain, e)),
+    }
+}
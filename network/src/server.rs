@@ -1,5 +1,4 @@
 extern crate protocol;
-extern crate dns_lookup;
 
 use mio::{Poll, Token, Ready, PollOpt};
 use std::net::{SocketAddr, IpAddr, Ipv4Addr, SocketAddrV4};
@@ -7,13 +6,39 @@ use mio::net::{TcpStream, TcpListener};
 use std::rc::Rc;
 use protocol::packet::ServerStage;
 use protocol::packet::*;
+use protocol::error::ProtocolError;
 use self::protocol::packet::ServerStage::{Init, AuthSelectFinish, RequestFinish, ReceiveContent};
 use self::protocol::packet::Version::Socks5;
 use std::io::{Error, Write, ErrorKind};
 use std::collections::VecDeque;
 use self::protocol::packet::CmdType::Connect;
 use crate::http::*;
+use crate::auth::{Authenticator, AnonymousAuthenticator, Identity};
+use crate::udp::UdpRelay;
+use crate::buffer::{Buffer, BufferLimits};
+use crate::happy_eyeballs::{ConnectRace, RaceState, CONNECTION_ATTEMPT_DELAY};
+use crate::registry::{Side, side_token};
+use crate::timer::{Timeout, Timeouts};
+use std::sync::Arc;
 use std::thread::sleep;
+use std::time::Instant;
+
+mod event_loop;
+mod http_proxy;
+mod socks4;
+
+pub use self::event_loop::{Server, ServerBuilder, ShutdownHandle};
+use self::http_proxy::{HttpExchange, http_error_response, http_status};
+
+/// protocol a client speaks, detected from the first bytes it sends
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Socks5,
+    /// socks4 and socks4a, connect only
+    Socks4,
+    /// http/1.1 forward proxy, requests carry absolute-form targets
+    Http,
+}
 
 struct DstAddress {
     ip: IpAddr,
@@ -34,34 +59,27 @@ fn check_cmd_operation(cmd: &CmdType) -> Result<&CmdType, String> {
     }
 }
 
-fn connect_to_dst(address: &IpAddr, port: u16) -> Result<TcpStream, ReplyType> {
-    let socket = match TcpStream::connect(
-        &SocketAddr::new(*address, port)) {
-        Ok(socket) => Ok(socket),
-        Err(e) => {
-            // todo error kind
-            let kind = e.kind();
-            match kind {
-                _ => return Err(ReplyType::Others),
-            }
-        }
-    }?;
-
-    Ok(socket)
+/// reply telling client why connecting to destination failed
+pub fn connect_error_reply(e: &Error) -> ReplyType {
+    match e.kind() {
+        ErrorKind::ConnectionRefused => ReplyType::ConnectionRefuse,
+        ErrorKind::NetworkUnreachable => ReplyType::NetWorkUnReachable,
+        ErrorKind::HostUnreachable => ReplyType::HostUnreachable,
+        ErrorKind::TimedOut => ReplyType::TTLExpired,
+        ErrorKind::PermissionDenied => ReplyType::ConnectionNotAllowed,
+        _ => ReplyType::ServerFailure,
+    }
 }
 
-fn transfer_address(address: String, address_type: &AddressType)
-                    -> Result<IpAddr, String> {
-    match address_type {
-        AddressType::Ipv4 => Ok(address.parse().unwrap()),
-        AddressType::Domain => {
-            let ips = match dns_lookup::lookup_host(&address) {
-                Ok(list) => Ok(list),
-                Err(e) => Err("err when parse domain.".to_string())
-            }?;
-            Ok(*ips.first().unwrap())
-        }
-        AddressType::Ipv6 => Err("ipv6 not support in this version.".to_string())
+/// protocol of a client from its first byte, a socks version or the first
+/// letter of an http method. `None` while nothing is received
+pub fn detect_protocol(data: &[u8]) -> Result<Option<Protocol>, String> {
+    match data.first() {
+        None => Ok(None),
+        Some(5) => Ok(Some(Protocol::Socks5)),
+        Some(4) => Ok(Some(Protocol::Socks4)),
+        Some(byte) if byte.is_ascii_alphabetic() => Ok(Some(Protocol::Http)),
+        Some(byte) => Err(ProtocolError::UnsupportedVersion(*byte).to_string()),
     }
 }
 
@@ -113,53 +131,139 @@ impl ServerHandler {
 pub struct ChildHandler {
     token: Token,
     stage: ServerStage,
-    send_buffer: Vec<u8>,
-    receive_buffer: Vec<u8>,
+    send_buffer: Buffer,
+    receive_buffer: Buffer,
     dst_token: Option<Token>,
-    dst_send_buffer: Vec<u8>,
-    dst_receive_buffer: Vec<u8>,
+    dst_send_buffer: Buffer,
+    dst_receive_buffer: Buffer,
     dst_socket: Option<TcpStream>,
     proxy_inited: bool,
     forward: bool,
+    client: SocketAddr,
+    authenticator: Arc<dyn Authenticator>,
+    auth_method: Option<AuthType>,
+    identity: Option<Identity>,
+    local: SocketAddr,
+    bind_listener: Option<TcpListener>,
+    bind_token: Option<Token>,
+    bind_peer: Option<SocketAddr>,
+    udp_relay: Option<UdpRelay>,
+    udp_token: Option<Token>,
+    lookup: Option<String>,
+    // credentials of client which should be verified off the event loop
+    verifying: Option<UserPassAuthRequest>,
+    pending_request: Option<DstServiceRequest>,
+    race: Option<ConnectRace>,
+    protocol: Protocol,
+    http: HttpExchange,
+    // connect attempts registered so far, numbers their tokens
+    attempts: usize,
+    limits: BufferLimits,
+    // reading from a socket stops while its direction is above high-water mark
+    client_paused: bool,
+    proxy_paused: bool,
+    // interest sockets are registered with
+    client_interest: Ready,
+    proxy_interest: Ready,
+    // a side finished sending, and whether its peer was shut down for writing
+    client_eof: bool,
+    proxy_eof: bool,
+    client_shutdown: bool,
+    proxy_shutdown: bool,
+    timeouts: Timeouts,
+    created: Instant,
+    // when the request arrived, connect timeout counts from here
+    requested: Option<Instant>,
+    last_active: Instant,
 }
 
 impl ChildHandler {
     pub fn new_test(token: &Token) -> ChildHandler {
-        ChildHandler {
-            token: token.clone(),
-            stage: ServerStage::Init,
-            receive_buffer: Vec::<u8>::new(),
-            send_buffer: Vec::<u8>::new(),
-            dst_token: None,
-            dst_receive_buffer: Vec::<u8>::new(),
-            dst_send_buffer: Vec::<u8>::new(),
-            dst_socket: None,
-            proxy_inited: false,
-            forward: false,
-        }
+        let client = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+        ChildHandler::new(token, client, Arc::new(AnonymousAuthenticator))
     }
-    pub fn new(token: &Token) -> ChildHandler {
+
+    pub fn new(token: &Token, client: SocketAddr, authenticator: Arc<dyn Authenticator>) -> ChildHandler {
         ChildHandler {
             token: token.clone(),
-            stage: ServerStage::Init,
-            receive_buffer: Vec::<u8>::new(),
-            send_buffer: Vec::<u8>::new(),
+            stage: ServerStage::Detecting,
+            receive_buffer: Buffer::new(),
+            send_buffer: Buffer::new(),
             dst_token: None,
-            dst_receive_buffer: Vec::<u8>::new(),
-            dst_send_buffer: Vec::<u8>::new(),
+            dst_receive_buffer: Buffer::new(),
+            dst_send_buffer: Buffer::new(),
             dst_socket: None,
             proxy_inited: false,
             forward: false,
+            client,
+            authenticator,
+            auth_method: None,
+            identity: None,
+            local: SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+            bind_listener: None,
+            bind_token: None,
+            bind_peer: None,
+            udp_relay: None,
+            udp_token: None,
+            lookup: None,
+            verifying: None,
+            pending_request: None,
+            race: None,
+            protocol: Protocol::Socks5,
+            http: HttpExchange::default(),
+            attempts: 0,
+            limits: BufferLimits::default(),
+            client_paused: false,
+            proxy_paused: false,
+            client_interest: Ready::readable() | Ready::writable(),
+            proxy_interest: Ready::readable() | Ready::writable(),
+            client_eof: false,
+            proxy_eof: false,
+            client_shutdown: false,
+            proxy_shutdown: false,
+            timeouts: Timeouts::default(),
+            created: Instant::now(),
+            requested: None,
+            last_active: Instant::now(),
         }
     }
 
     pub fn handle(&mut self) -> Result<usize, String> {
+        if self.relays_http_messages() {
+            return self.handle_http();
+        }
+
         let stage = &mut self.stage;
         match stage {
+            ServerStage::Detecting => {
+                match detect_protocol(self.receive_buffer.as_slice())? {
+                    Some(protocol) => {
+                        println!("{:?} client {}", protocol, self.client);
+                        self.set_protocol(protocol);
+                        self.handle()
+                    }
+                    None => Ok(0),
+                }
+            }
             ServerStage::Init => {
                 match self.handle_init_stage()? {
                     Some(size) => {
-                        self.stage = ServerStage::AuthSelectFinish;
+                        self.stage = match self.auth_method {
+                            _ if self.stage == ServerStage::Closing => ServerStage::Closing,
+                            Some(AuthType::NamePassword) => ServerStage::AuthSubNegotiation,
+                            _ => ServerStage::AuthSelectFinish,
+                        };
+                        Ok(size)
+                    }
+                    None => Ok(0)
+                }
+            }
+            ServerStage::AuthSubNegotiation => {
+                match self.handle_user_auth()? {
+                    Some(size) => {
+                        if self.stage == ServerStage::AuthSubNegotiation {
+                            self.stage = ServerStage::AuthSelectFinish;
+                        }
                         Ok(size)
                     }
                     None => Ok(0)
@@ -167,15 +271,29 @@ impl ChildHandler {
             }
             ServerStage::AuthSelectFinish => {
                 // parse packet and send
-                let size = match self.handle_dst_request()? {
+                let request = match self.protocol {
+                    Protocol::Socks4 => self.handle_socks4_request()?,
+                    _ => self.handle_dst_request()?,
+                };
+                let size = match request {
                     Some(result) => result,
                     None => return Ok(0),
                 };
 
-
-                self.stage = RequestFinish;
+                if self.stage == ServerStage::AuthSelectFinish {
+                    self.stage = RequestFinish;
+                }
                 Ok(2)
             }
+            ServerStage::Authenticating | ServerStage::Resolving | ServerStage::Connecting
+            | ServerStage::BindListening => {
+                Ok(0)
+            }
+            ServerStage::UdpAssociated => {
+                // nothing is expected from client any more
+                self.receive_buffer.clear();
+                Ok(0)
+            }
             ServerStage::RequestFinish => {
                 Ok(2)
             }
@@ -185,8 +303,9 @@ impl ChildHandler {
             ServerStage::ContentFinish => {
                 Ok(0)
             }
-
-            _ => Err("unreachable err".to_string())
+            ServerStage::Closing => {
+                Ok(0)
+            }
         }
     }
 
@@ -202,25 +321,33 @@ impl ChildHandler {
         check_version_type(request.version())?;
 
         let n_methods = request.n_methods();
-        if n_methods == 0 {
-            return Err("non auth method is specified.".to_string());
-        }
-
         let methods = request.methods();
-        let contains_name_pass = methods.contains(&AuthType::NamePassword);
-        let contains_non = methods.contains(&AuthType::Non);
-
-        let auth_type = if contains_name_pass {
-            AuthType::NamePassword
-        } else if contains_non {
-            AuthType::Non
-        } else {
-            return Err("proxy only support non and name/password auth-method.".to_string());
+        let auth_type = self.authenticator.select_method(&self.client, methods);
+
+        // methods without sub negotiation are decided right now
+        let identity = match &auth_type {
+            Some(AuthType::NamePassword) | None => None,
+            Some(auth_type) => self.authenticator.authenticate(&self.client, auth_type, None),
+        };
+
+        let auth_type = match (auth_type, identity) {
+            (Some(AuthType::NamePassword), _) => AuthType::NamePassword,
+            (Some(auth_type), Some(identity)) => {
+                self.identity = Some(identity);
+                auth_type
+            }
+            _ => {
+                // rfc 1928: reply X'FF' and close when no method is acceptable
+                println!("no acceptable auth-method offered by {}", self.client);
+                self.stage = ServerStage::Closing;
+                AuthType::NonAccept
+            }
         };
 
         let auth_select_reply = AuthSelectReply::new(Socks5, auth_type);
-        let data = encode_auth_select_reply(&auth_select_reply)?;
-        self.clear_receive_buffer(3);
+        let data = encode_auth_select_reply(&auth_select_reply).map_err(|e| e.to_string())?;
+        self.auth_method = Some(auth_select_reply.auth_type().clone());
+        self.clear_receive_buffer(2 + usize::from(n_methods));
 
         // Ok(data.len())
         match self.write_to_buffer(data, false) {
@@ -229,190 +356,393 @@ impl ChildHandler {
         }
     }
 
+    /// username/password sub negotiation, connection is closed when it fails
+    pub fn handle_user_auth(&mut self) -> Result<Option<usize>, String> {
+        let data = self.receive_buffer.as_slice();
+        let (request, request_len) = match parse_user_auth_request(data) {
+            Ok(Some(result)) => result,
+            Ok(None) => return Ok(None),
+            Err(e) => return Err(e.to_string()),
+        };
+
+        self.clear_receive_buffer(request_len);
+
+        if *request.version() != SubVersion::V1 {
+            return Err("sub negotiation version should be 1.".to_string());
+        }
+
+        // hashing a password is slow, reply is sent by `verified`
+        self.verifying = Some(request);
+        self.stage = ServerStage::Authenticating;
+
+        Ok(Some(0))
+    }
+
+    /// carry on with sub negotiation, or a held http request, once the
+    /// credentials taken by `take_verification` are checked
+    pub fn verified(&mut self, identity: Option<Identity>) -> Result<usize, String> {
+        if self.protocol == Protocol::Http {
+            return self.http_verified(identity);
+        }
+
+        let status = match identity {
+            Some(identity) => {
+                self.identity = Some(identity);
+                self.stage = ServerStage::AuthSelectFinish;
+                AuthResult::Success
+            }
+            None => {
+                println!("auth failed for {}", self.client);
+                self.stage = ServerStage::Closing;
+                AuthResult::Failure
+            }
+        };
+
+        let reply = UserPassAuthReply::new(SubVersion::V1, status);
+        let data = encode_user_auth_reply(&reply).map_err(|e| e.to_string())?;
+
+        self.write_to_buffer(data, false)
+    }
+
     pub fn parse_auth_select_request(&self) -> Result<Option<AuthSelectRequest>, String> {
-        let cloned = self.receive_buffer.clone();
-        let data = cloned.as_slice();
+        let data = self.receive_buffer.as_slice();
         // parse packet and send
-        let request = parse_auth_select_request_packet(data)?;
+        let request = parse_auth_select_request_packet(data).map_err(|e| e.to_string())?;
         Ok(request)
     }
 
     pub fn handle_dst_request(&mut self) -> Result<Option<usize>, String> {
         let data = self.receive_buffer.as_slice();
-        let (request, address_len) = match parse_dst_service_request(data)? {
-            Some(result) => result,
-            None => return Ok(None),
+        match data.first() {
+            // no way to answer a client which does not speak socks5
+            Some(version) if *version != 5 =>
+                return Err(ProtocolError::UnsupportedVersion(*version).to_string()),
+            _ => {}
+        }
+
+        let (request, request_len) = match parse_dst_service_request(data) {
+            Ok(Some(result)) => result,
+            Ok(None) => return Ok(None),
+            Err(e) => {
+                println!("refuse dst request from {}:{}", self.session_name(), e);
+                self.receive_buffer.clear();
+                return self.refuse_dst_request(e.reply_type()).map(Some);
+            }
         };
 
-        check_version_type(request.version())?;
+        self.clear_receive_buffer(request_len);
+        self.requested = Some(Instant::now());
+
+        // domains are resolved off event loop, see `take_lookup` and `resolved`
+        let dst_address = match request.address() {
+            TargetAddr::Ip(address) => *address,
+            TargetAddr::Domain(domain, _) => {
+                self.lookup = Some(domain.clone());
+                self.pending_request = Some(request);
+                self.stage = ServerStage::Resolving;
+                return Ok(Some(0));
+            }
+        };
 
-        // check_cmd_operation(request.cmd())?;
-
-        let address_type = request.address_type();
-        let address = request.address();
-        let port = request.port();
-        //println!("proxy_port:{}", port);
-        // save address:port
-        let address_copy = String::from(&address);
-        let dst_address = transfer_address(address_copy, address_type)?;
-
-        // connect -- then return socket
-        // send reply
-
-        // 1. 检查版本 ---- 非5 直接断开
-        // 2. 检查cmd ---- 如果不为connect 直接断开
-        // 3. 连接远程服务 ---- 构造reply
-        // 4. 将响应写入buffer
-        // 5. 返回
-        let reply = match request.cmd() {
-            CmdType::Connect => {
-                // connect
-                let res = match connect_to_dst(&dst_address, port) {
-                    Ok(socket) => {
-                        self.dst_socket = Some(socket);
-                        ReplyType::Success
-                    }
-                    Err(e) => e
-                };
+        self.execute_dst_request(&request, dst_address).map(Some)
+    }
 
-                res
-            }
+    /// domain of a pending request is resolved, carry on with the request
+    pub fn resolved(&mut self, result: Result<Vec<IpAddr>, String>) -> Result<usize, String> {
+        let request = match self.pending_request.take() {
+            Some(request) if self.stage == ServerStage::Resolving => request,
+            _ => return Err("no dst request is waiting for resolving.".to_string()),
+        };
 
-            _ => ReplyType::CmdNotSupport
+        let addresses: Vec<SocketAddr> = match result {
+            Ok(ips) => ips.into_iter().map(|ip| SocketAddr::new(ip, request.port())).collect(),
+            Err(msg) => return self.refuse_resolving(&request, &msg),
         };
 
-        let address_type_copy = match address_type {
-            AddressType::Ipv4 => AddressType::Ipv4,
-            AddressType::Ipv6 => AddressType::Ipv6,
-            AddressType::Domain => AddressType::Domain,
+        match (request.cmd(), addresses.first()) {
+            (_, None) => self.refuse_resolving(&request, "no address found"),
+            // connect races all addresses, bind and udp only need one
+            (CmdType::Connect, Some(_)) => self.start_connect(addresses),
+            (_, Some(address)) => self.execute_dst_request(&request, *address),
+        }
+    }
+
+    fn refuse_resolving(&mut self, request: &DstServiceRequest, msg: &str) -> Result<usize, String> {
+        println!("refuse dst request from {} to {}:{}", self.session_name(), request.address(), msg);
+        self.refuse_dst_request(ReplyType::HostUnreachable)
+    }
+
+    fn execute_dst_request(&mut self, request: &DstServiceRequest, dst_address: SocketAddr)
+                           -> Result<usize, String> {
+        match request.cmd() {
+            CmdType::Connect => self.start_connect(vec![dst_address]),
+            CmdType::Bind => self.handle_bind_request(dst_address),
+            CmdType::Udp => self.handle_udp_request(dst_address),
+        }
+    }
+
+    /// race connects to destination addresses, reply is delayed until one of them wins
+    fn start_connect(&mut self, addresses: Vec<SocketAddr>) -> Result<usize, String> {
+        let (race, state) = ConnectRace::start(addresses, CONNECTION_ATTEMPT_DELAY, Instant::now());
+        self.race = Some(race);
+        self.stage = ServerStage::Connecting;
+
+        self.connect_state(state).map(|size| size.unwrap_or(0))
+    }
+
+    /// register connect attempts started since last call, returns their tokens
+    pub fn register_connect_attempts(&mut self, poll: &Poll) -> Vec<Token> {
+        let token = self.token;
+        let attempts = &mut self.attempts;
+        match &mut self.race {
+            Some(race) => race.register(poll, || {
+                *attempts += 1;
+                side_token(token, Side::Attempt(*attempts))
+            }),
+            None => Vec::new(),
+        }
+    }
+
+    /// socket of connect attempt `token` is ready, returns `None` while the race goes on.
+    /// success or failure reply is buffered, the winner becomes proxy socket of `Side::Upstream`.
+    pub fn connect_attempt_ready(&mut self, token: Token) -> Result<Option<usize>, String> {
+        let state = match &mut self.race {
+            Some(race) if self.stage == ServerStage::Connecting => race.ready(token, Instant::now()),
+            _ => return Err("no connect is in progress.".to_string()),
         };
 
-        let address_copy_2 = String::from(&address);
-        let dst_reply = DstServiceReply::new(
-            Version::Socks5, reply, address_type_copy, address_copy_2, port);
+        if let RaceState::Connected(_, _) = state {
+            self.dst_token = Some(side_token(self.token, Side::Upstream));
+        }
+        self.connect_state(state)
+    }
 
-        let data = encode_dst_service_reply(dst_reply)?;
+    /// start the next connect attempt when its delay is over
+    pub fn connect_timer(&mut self, now: Instant) -> Result<Option<usize>, String> {
+        let state = match &mut self.race {
+            Some(race) if self.stage == ServerStage::Connecting => race.timer(now),
+            _ => return Ok(None),
+        };
 
-        self.clear_receive_buffer(address_len + 6);
+        self.connect_state(state)
+    }
 
-        match self.write_to_buffer(data, false) {
-            Ok(size) => Ok(Some(size)),
-            Err(msg) => Err(msg),
+    /// when `connect_timer` should be called next
+    pub fn connect_deadline(&self) -> Option<Instant> {
+        self.race.as_ref().and_then(|race| race.deadline())
+    }
+
+    /// tokens of connect attempts which are closed, they may be registered
+    pub fn take_closed_attempts(&mut self) -> Vec<Token> {
+        match &mut self.race {
+            Some(race) => race.take_closed(),
+            None => Vec::new(),
         }
     }
 
-    pub fn clear_receive_buffer(&mut self, size: u8) {
-        let mut len = size.clone();
-        let buffer = &mut self.receive_buffer;
-        loop {
-            if len == 0 {
-                break;
+    fn connect_state(&mut self, state: RaceState) -> Result<Option<usize>, String> {
+        match state {
+            RaceState::Pending => Ok(None),
+            RaceState::Connected(socket, address) => {
+                let bound = socket.local_addr().map_err(|e| e.to_string())?;
+                println!("connect for {} to {} succeeded", self.session_name(), address);
+                self.dst_socket = Some(socket);
+                self.stage = ServerStage::RequestFinish;
+                match self.protocol {
+                    Protocol::Http => Ok(Some(self.http_connected())),
+                    Protocol::Socks4 => Ok(Some(self.buffer_socks4_reply(ReplyType::Success, &bound))),
+                    Protocol::Socks5 => self.buffer_dst_reply(ReplyType::Success, TargetAddr::from(bound)).map(Some),
+                }
+            }
+            RaceState::Failed(reply) => {
+                println!("connect for {} failed:{:?}", self.session_name(), reply);
+                self.refuse_dst_request(reply).map(Some)
             }
-
-            buffer.remove(0);
-            len = len - 1;
         }
     }
 
-    pub fn clear_send_buffer(&mut self, is_proxy: bool) {
-        let mut buffer = match is_proxy {
-            false => &mut self.send_buffer,
-            true => &mut self.dst_send_buffer,
+    /// listen for the peer client expects, the first reply carries listening address
+    fn handle_bind_request(&mut self, expected_peer: SocketAddr) -> Result<usize, String> {
+        let address = SocketAddr::new(self.local.ip(), 0);
+        let listener = match TcpListener::bind(&address) {
+            Ok(listener) => listener,
+            Err(e) => {
+                println!("bind for {} failed:{}", self.session_name(), e);
+                return self.refuse_dst_request(ReplyType::ServerFailure);
+            }
         };
 
-        loop {
-            if buffer.is_empty() {
-                break;
+        let bound = match listener.local_addr() {
+            Ok(bound) => bound,
+            Err(e) => {
+                println!("bind for {} failed:{}", self.session_name(), e);
+                return self.refuse_dst_request(ReplyType::ServerFailure);
             }
+        };
 
-            buffer.remove(0);
-        }
+        self.bind_listener = Some(listener);
+        self.bind_peer = Some(expected_peer);
+        self.stage = ServerStage::BindListening;
+
+        self.buffer_dst_reply(ReplyType::Success, TargetAddr::from(bound))
     }
 
-    pub fn write_to_buffer(&mut self, data: Vec<u8>, is_proxy: bool) -> Result<usize, String> {
-        let mut buffer = match is_proxy {
-            false => &mut self.send_buffer,
-            true => &mut self.dst_send_buffer,
+    /// open relay socket for udp associate, the reply carries its address
+    fn handle_udp_request(&mut self, expected_client: SocketAddr) -> Result<usize, String> {
+        let relay = match UdpRelay::bind(self.local.ip(), self.client.ip(), expected_client) {
+            Ok(relay) => relay,
+            Err(e) => {
+                println!("udp associate for {} failed:{}", self.session_name(), e);
+                return self.refuse_dst_request(ReplyType::ServerFailure);
+            }
+        };
+
+        let bound = match relay.local_addr() {
+            Ok(bound) => bound,
+            Err(e) => {
+                println!("udp associate for {} failed:{}", self.session_name(), e);
+                return self.refuse_dst_request(ReplyType::ServerFailure);
+            }
         };
 
-        let size: usize = data.len();
+        self.udp_relay = Some(relay);
+        self.stage = ServerStage::UdpAssociated;
+
+        self.buffer_dst_reply(ReplyType::Success, TargetAddr::from(bound))
+    }
 
-        for i in 0..data.len() {
-            buffer.push(*data.get(i).unwrap());
+    /// peer connected to bind listener, returns false when it is not the expected one.
+    /// the second reply is buffered and the peer becomes proxy socket.
+    pub fn accept_bind_peer(&mut self, socket: TcpStream, peer: SocketAddr) -> Result<bool, String> {
+        if self.stage != ServerStage::BindListening {
+            return Err("no bind request is waiting for peer.".to_string());
         }
 
-        Ok(size)
+        match self.bind_peer {
+            // client may not know the peer address, e.g. behind nat
+            Some(expected) if !expected.ip().is_unspecified() && expected.ip() != peer.ip() => {
+                println!("reject bind peer {} for {}", peer, self.session_name());
+                return Ok(false);
+            }
+            _ => {}
+        }
+
+        self.dst_socket = Some(socket);
+        self.bind_peer = None;
+        self.stage = ServerStage::RequestFinish;
+        self.buffer_dst_reply(ReplyType::Success, TargetAddr::from(peer))?;
+
+        Ok(true)
     }
 
-    pub fn receive_u8_data(&mut self, data: u8, is_proxy: bool) -> Result<usize, &str> {
-        let mut buffer = match is_proxy {
-            false => &mut self.receive_buffer,
-            true => &mut self.dst_receive_buffer,
+    fn session_name(&self) -> String {
+        match &self.identity {
+            Some(identity) => format!("{}@{}", identity, self.client),
+            None => self.client.to_string(),
+        }
+    }
+
+    /// buffer a failure reply and close the connection once it is sent
+    fn refuse_dst_request(&mut self, reply: ReplyType) -> Result<usize, String> {
+        if self.protocol == Protocol::Http {
+            let (status, reason) = http_status(&reply);
+            self.refuse_http(status, reason);
+            return Ok(self.send_buffer.len());
+        }
+
+        let unspecified = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+        let size = match self.protocol {
+            Protocol::Socks4 => self.buffer_socks4_reply(reply, &unspecified),
+            _ => self.buffer_dst_reply(reply, TargetAddr::from(unspecified))?,
         };
+        self.stage = ServerStage::Closing;
 
-        buffer.push(data);
+        Ok(size)
+    }
 
-        Ok(1)
+    pub fn clear_receive_buffer(&mut self, size: usize) {
+        self.receive_buffer.consume(size);
     }
 
-    pub fn write_to_socket(&mut self, socket: &mut TcpStream, is_proxy: bool)
-                           -> Result<usize, String> {
+    pub fn clear_send_buffer(&mut self, is_proxy: bool) {
+        match is_proxy {
+            false => self.send_buffer.clear(),
+            true => self.dst_send_buffer.clear(),
+        }
+    }
+
+    pub fn write_to_buffer(&mut self, data: Vec<u8>, is_proxy: bool) -> Result<usize, String> {
         let buffer = match is_proxy {
-            false => &self.send_buffer,
-            true => &self.dst_send_buffer,
+            false => &mut self.send_buffer,
+            true => &mut self.dst_send_buffer,
         };
 
-        if buffer.is_empty() {
-            return Ok(0);
-        }
+        buffer.extend_from_slice(&data);
 
+        Ok(data.len())
+    }
+
+    /// append data read from client, or from proxy socket when `is_proxy`
+    pub fn receive_data(&mut self, data: &[u8], is_proxy: bool) -> usize {
+        let buffer = match is_proxy {
+            false => &mut self.receive_buffer,
+            true => &mut self.dst_receive_buffer,
+        };
 
-        let data = buffer.as_slice();
-        let size = data.len();
-        let mut total = 0;
-        loop {
-            let size = match socket.write(&data[total..]) {
-                Ok(size) => Ok(size),
-                Err(e) => Err("err when write socket"),
-            }?;
+        buffer.extend_from_slice(data);
+        self.last_active = Instant::now();
 
-            if size == 0 {
-                break;
+        // reading stops here, it resumes only after draining to low-water mark
+        if self.buffered(is_proxy) >= self.limits.high_water {
+            match is_proxy {
+                false => self.client_paused = true,
+                true => self.proxy_paused = true,
             }
-            total = total + size;
         }
 
-        self.clear_send_buffer_with_size(total, is_proxy);
+        data.len()
+    }
 
-        Ok(size)
+    pub fn receive_u8_data(&mut self, data: u8, is_proxy: bool) -> Result<usize, &str> {
+        Ok(self.receive_data(&[data], is_proxy))
     }
 
-    pub fn clear_send_buffer_with_size(&mut self, size: usize, is_proxy: bool) {
-        let mut len = size.clone();
+    /// write buffered data until socket would block, returns the number written
+    pub fn write_to_socket<W: Write>(&mut self, socket: &mut W, is_proxy: bool)
+                                     -> Result<usize, String> {
         let buffer = match is_proxy {
             false => &mut self.send_buffer,
             true => &mut self.dst_send_buffer,
         };
-        loop {
-            if len == 0 {
-                break;
-            }
 
-            buffer.remove(0);
-            len = len - 1;
+        let size = buffer.write_to(socket).map_err(|e| format!("err when write socket: {}", e))?;
+        if size > 0 {
+            self.last_active = Instant::now();
+        }
+
+        Ok(size)
+    }
+
+    pub fn clear_send_buffer_with_size(&mut self, size: usize, is_proxy: bool) {
+        match is_proxy {
+            false => self.send_buffer.consume(size),
+            true => self.dst_send_buffer.consume(size),
         }
     }
 
-    fn buffer_dst_reply(&mut self, reply: ReplyType, address_type: AddressType
-                        , address: String, port: u16) -> Result<usize, String> {
-        let dst_reply = DstServiceReply::new(
-            Version::Socks5, reply, address_type, address, port);
+    fn buffer_dst_reply(&mut self, reply: ReplyType, address: TargetAddr) -> Result<usize, String> {
+        let dst_reply = DstServiceReply::new(Version::Socks5, reply, address);
 
-        let data = encode_dst_service_reply(dst_reply)?;
+        let data = encode_dst_service_reply(dst_reply).map_err(|e| e.to_string())?;
 
         self.write_to_buffer(data, false)
     }
 
+    /// data waiting to be written to client
+    pub fn send_buffer(&self) -> &[u8] {
+        self.send_buffer.as_slice()
+    }
+
     pub fn print_receive_buf_size(self) {
         println!("receive buf size:{}", self.receive_buffer.len());
     }
@@ -421,10 +751,271 @@ impl ChildHandler {
         self.stage == ServerStage::AuthSelectFinish
     }
 
+    /// a failure reply is buffered, connection should be closed after sending it
+    pub fn is_closing(&self) -> bool {
+        self.stage == ServerStage::Closing
+    }
+
     pub fn after_dst_request(&self) -> bool {
         self.stage == ReceiveContent
     }
 
+    /// high/low-water marks for the bytes buffered in each direction
+    pub fn set_buffer_limits(&mut self, limits: BufferLimits) {
+        self.limits = limits;
+    }
+
+    /// bytes read from client, or from proxy socket when `is_proxy`, which are not sent yet
+    pub fn buffered(&self, is_proxy: bool) -> usize {
+        match is_proxy {
+            false => self.receive_buffer.len() + self.dst_send_buffer.len(),
+            true => self.dst_receive_buffer.len() + self.send_buffer.len(),
+        }
+    }
+
+    /// more data may be read from the socket, false once its direction reached high-water mark
+    pub fn accepts_data(&self, is_proxy: bool) -> bool {
+        match is_proxy {
+            false => !self.client_paused,
+            true => !self.proxy_paused,
+        }
+    }
+
+    /// interest a socket should be registered with, `None` when it is unchanged.
+    /// a paused socket is readable again once its direction drains below low-water mark,
+    /// it is then always reregistered to pick up data left in the socket.
+    pub fn interest_change(&mut self, is_proxy: bool) -> Option<Ready> {
+        let buffered = self.buffered(is_proxy);
+        let paused = match is_proxy {
+            false => &mut self.client_paused,
+            true => &mut self.proxy_paused,
+        };
+
+        // reading stopped before the socket would block, so no new edge arrives
+        let mut resumed = false;
+        if buffered >= self.limits.high_water {
+            *paused = true;
+        } else if buffered <= self.limits.low_water && *paused {
+            *paused = false;
+            resumed = true;
+        }
+
+        let eof = match is_proxy {
+            false => self.client_eof,
+            true => self.proxy_eof,
+        };
+
+        let mut interest = Ready::empty();
+        if !*paused && !eof {
+            interest |= Ready::readable();
+        }
+
+        let pending = match is_proxy {
+            false => &self.send_buffer,
+            true => &self.dst_send_buffer,
+        };
+        if !pending.is_empty() {
+            interest |= Ready::writable();
+        }
+
+        let current = match is_proxy {
+            false => &mut self.client_interest,
+            true => &mut self.proxy_interest,
+        };
+
+        // reregistering polls readiness again, also when interest is unchanged
+        match *current == interest && !resumed {
+            true => None,
+            false => {
+                *current = interest;
+                Some(interest)
+            }
+        }
+    }
+
+    /// client, or proxy socket when `is_proxy`, finished sending
+    pub fn receive_eof(&mut self, is_proxy: bool) {
+        match is_proxy {
+            false => self.client_eof = true,
+            true => self.proxy_eof = true,
+        }
+    }
+
+    /// true once when the socket should be shut down for writing: its peer
+    /// finished sending and everything read from the peer is written
+    pub fn take_shutdown(&mut self, is_proxy: bool) -> bool {
+        let (peer_eof, pending, shutdown) = match is_proxy {
+            false => (self.proxy_eof, self.dst_receive_buffer.len() + self.send_buffer.len()
+                      , &mut self.client_shutdown),
+            true => (self.client_eof, self.receive_buffer.len() + self.dst_send_buffer.len()
+                     , &mut self.proxy_shutdown),
+        };
+
+        if !peer_eof || pending != 0 || *shutdown {
+            return false;
+        }
+
+        *shutdown = true;
+        true
+    }
+
+    /// both directions finished and are flushed, the pair can be closed
+    pub fn is_finished(&self) -> bool {
+        self.client_shutdown && self.proxy_shutdown
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+    }
+
+    /// data was relayed for this connection outside of its tcp sockets, e.g. udp
+    pub fn touch(&mut self, now: Instant) {
+        self.last_active = self.last_active.max(now);
+    }
+
+    /// the limit which expires first in current stage, a new stage may move it earlier
+    fn next_timeout(&self) -> (Instant, Timeout) {
+        let stage = match self.stage {
+            ServerStage::Detecting | ServerStage::Init | ServerStage::AuthSubNegotiation
+            | ServerStage::Authenticating | ServerStage::AuthSelectFinish =>
+                (self.created + self.timeouts.handshake, Timeout::Handshake),
+            ServerStage::Resolving | ServerStage::Connecting =>
+                (self.requested.unwrap_or(self.created) + self.timeouts.connect, Timeout::Connect),
+            _ => (self.last_active + self.timeouts.idle, Timeout::Idle),
+        };
+
+        let lifetime = (self.created + self.timeouts.max_lifetime, Timeout::Lifetime);
+        match lifetime.0 < stage.0 {
+            true => lifetime,
+            false => stage,
+        }
+    }
+
+    /// when `check_timeout` should be called next
+    pub fn deadline(&self) -> Instant {
+        self.next_timeout().0
+    }
+
+    /// returns the exceeded limit, a failure reply is buffered when protocol
+    /// has one for current stage and connection should be closed after sending it
+    pub fn check_timeout(&mut self, now: Instant) -> Option<Timeout> {
+        let (deadline, timeout) = self.next_timeout();
+        if deadline > now {
+            return None;
+        }
+
+        let reply = match (self.protocol, &self.stage) {
+            (Protocol::Http, ServerStage::Init) if !self.receive_buffer.is_empty() =>
+                Some(http_error_response(408, "Request Timeout")),
+            (Protocol::Http, ServerStage::Resolving) | (Protocol::Http, ServerStage::Connecting) =>
+                Some(http_error_response(504, "Gateway Timeout")),
+            (Protocol::Http, _) => None,
+            (Protocol::Socks4, ServerStage::Resolving) | (Protocol::Socks4, ServerStage::Connecting) => {
+                let unspecified = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+                Some(encode_socks4_reply(&ReplyType::TTLExpired, &unspecified))
+            }
+            (Protocol::Socks4, _) => None,
+            // a partial method selection is answered with no acceptable method
+            (_, ServerStage::Init) if !self.receive_buffer.is_empty() =>
+                encode_auth_select_reply(&AuthSelectReply::new(Socks5, AuthType::NonAccept)).ok(),
+            (_, ServerStage::AuthSubNegotiation) | (_, ServerStage::Authenticating) =>
+                encode_user_auth_reply(&UserPassAuthReply::new(SubVersion::V1, AuthResult::Failure)).ok(),
+            // the request never arrived in full, the destination was not tried
+            (_, ServerStage::AuthSelectFinish) => {
+                let unspecified = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+                let reply = DstServiceReply::new(Socks5, ReplyType::ServerFailure, TargetAddr::from(unspecified));
+                encode_dst_service_reply(reply).ok()
+            }
+            (_, ServerStage::Resolving) | (_, ServerStage::Connecting) => {
+                let unspecified = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+                let reply = DstServiceReply::new(Socks5, ReplyType::TTLExpired, TargetAddr::from(unspecified));
+                encode_dst_service_reply(reply).ok()
+            }
+            _ => None,
+        };
+
+        if let Some(reply) = reply {
+            self.send_buffer.clear();
+            self.send_buffer.extend_from_slice(&reply);
+        }
+
+        println!("{:?} timeout for {}", timeout, self.session_name());
+        self.race = None;
+        self.stage = ServerStage::Closing;
+
+        Some(timeout)
+    }
+
+    /// bind requests listen on the address client connected to
+    pub fn set_local_address(&mut self, local: SocketAddr) {
+        self.local = local;
+    }
+
+    /// listener created by a bind request, taken once to be registered
+    pub fn take_bind_listener(&mut self) -> Option<TcpListener> {
+        self.bind_listener.take()
+    }
+
+    pub fn set_bind_token(&mut self, token: Token) {
+        self.bind_token = Some(token);
+    }
+
+    pub fn get_bind_token(&self) -> Option<&Token> {
+        self.bind_token.as_ref()
+    }
+
+    /// relay created by a udp associate request, taken once to be registered
+    pub fn take_udp_relay(&mut self) -> Option<UdpRelay> {
+        self.udp_relay.take()
+    }
+
+    pub fn set_udp_token(&mut self, token: Token) {
+        self.udp_token = Some(token);
+    }
+
+    pub fn get_udp_token(&self) -> Option<&Token> {
+        self.udp_token.as_ref()
+    }
+
+    /// domain which should be resolved for a pending request, taken once
+    pub fn take_lookup(&mut self) -> Option<String> {
+        self.lookup.take()
+    }
+
+    /// credentials which should be verified for client, taken once
+    pub fn take_verification(&mut self) -> Option<UserPassAuthRequest> {
+        self.verifying.take()
+    }
+
+    pub fn is_authenticating(&self) -> bool {
+        self.stage == ServerStage::Authenticating
+    }
+
+    pub fn authenticator(&self) -> &Arc<dyn Authenticator> {
+        &self.authenticator
+    }
+
+    pub fn is_resolving(&self) -> bool {
+        self.stage == ServerStage::Resolving
+    }
+
+    pub fn is_connecting(&self) -> bool {
+        self.stage == ServerStage::Connecting
+    }
+
+    pub fn is_bind_listening(&self) -> bool {
+        self.stage == ServerStage::BindListening
+    }
+
+    /// identity of client, present once authentication finishes
+    pub fn identity(&self) -> Option<&Identity> {
+        self.identity.as_ref()
+    }
+
+    pub fn client(&self) -> &SocketAddr {
+        &self.client
+    }
+
     pub fn get_token(&self) -> &Token {
         &self.token
     }
@@ -470,28 +1061,41 @@ impl ChildHandler {
         self.dst_token = Some(dst_token);
     }
 
-    pub fn move_to_proxy(&mut self){
-        let data = self.receive_buffer.as_slice();
-        let size: usize = data.len();
-        let copy = Vec::<u8>::from(data);
-
-        for i in 0..copy.len() {
-            self.dst_send_buffer.push(*data.get(i).unwrap());
+    /// protocol of client, detected from its first bytes unless set before.
+    /// socks4 has no method selection and waits for the request right away
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.protocol = protocol;
+        if self.stage == ServerStage::Detecting {
+            self.stage = match protocol {
+                Protocol::Socks4 => ServerStage::AuthSelectFinish,
+                _ => ServerStage::Init,
+            };
         }
+    }
 
-        self.receive_buffer.clear();
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
     }
 
-    pub fn move_to_client(&mut self){
-        let data = self.dst_receive_buffer.as_slice();
-        let size: usize = data.len();
-        let copy = Vec::<u8>::from(data);
+    /// move data read from one side to the other, http messages are rewritten on the way
+    pub fn relay(&mut self) -> Result<(), String> {
+        if self.relays_http_messages() {
+            return self.handle_http().map(|_| ());
+        }
 
-        for i in 0..copy.len() {
-            self.send_buffer.push(*data.get(i).unwrap());
+        if self.forward {
+            self.move_to_proxy();
+            self.move_to_client();
         }
+        Ok(())
+    }
 
-        self.dst_receive_buffer.clear();
+    pub fn move_to_proxy(&mut self){
+        self.dst_send_buffer.append(&mut self.receive_buffer);
+    }
+
+    pub fn move_to_client(&mut self){
+        self.send_buffer.append(&mut self.dst_receive_buffer);
     }
 }
 
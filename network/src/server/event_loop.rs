@@ -0,0 +1,796 @@
+use std::collections::HashSet;
+use std::io::{ErrorKind, Read};
+use std::net::{Shutdown, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use mio::{Events, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+use mio::net::TcpListener;
+use crate::auth::{AnonymousAuthenticator, Authenticator, Identity, MemoryAuthenticator};
+use crate::buffer::BufferLimits;
+use crate::dns_cache::{DnsCacheConfig, DnsCacheStats};
+use crate::policy::{AuthPolicy, PolicyAuthenticator};
+use crate::registry::{Connection, Registry, Side, side_of, side_token};
+use crate::resolver::{Resolver, DEFAULT_RESOLVER_THREADS};
+use crate::timer::{TimerWheel, Timeouts};
+use crate::tokens::Tokens;
+use crate::verifier::{Verifier, DEFAULT_VERIFIER_THREADS};
+use super::ChildHandler;
+
+/// settings of a `Server`, see `Server::builder`
+pub struct ServerBuilder {
+    listen: Vec<(SocketAddr, Option<AuthPolicy>)>,
+    users: Option<Arc<dyn Authenticator>>,
+    policy: Option<AuthPolicy>,
+    authenticator: Option<Arc<dyn Authenticator>>,
+    limits: BufferLimits,
+    timeouts: Timeouts,
+    resolver_threads: usize,
+    verifier_threads: usize,
+    dns_cache: DnsCacheConfig,
+}
+
+impl ServerBuilder {
+    /// address to accept clients on, may be given more than once. socks5, socks4
+    /// and http proxy clients are told apart by what they send first
+    pub fn listen(mut self, address: SocketAddr) -> ServerBuilder {
+        self.listen.push((address, None));
+        self
+    }
+
+    /// like `listen`, clients of this address are negotiated by `policy` instead of the server-wide one
+    pub fn listen_with_policy(mut self, address: SocketAddr, policy: AuthPolicy) -> ServerBuilder {
+        self.listen.push((address, Some(policy)));
+        self
+    }
+
+    /// enable name/password auth with these users
+    pub fn users(mut self, users: Arc<dyn Authenticator>) -> ServerBuilder {
+        self.users = Some(users);
+        self
+    }
+
+    /// auth-methods allowed per client network on listeners without their own policy,
+    /// name/password is checked by `users`
+    pub fn policy(mut self, policy: AuthPolicy) -> ServerBuilder {
+        self.policy = Some(policy);
+        self
+    }
+
+    /// negotiate auth by this authenticator on every listener, `users` and policies are ignored
+    pub fn authenticator(mut self, authenticator: Arc<dyn Authenticator>) -> ServerBuilder {
+        self.authenticator = Some(authenticator);
+        self
+    }
+
+    pub fn buffer_limits(mut self, limits: BufferLimits) -> ServerBuilder {
+        self.limits = limits;
+        self
+    }
+
+    pub fn timeouts(mut self, timeouts: Timeouts) -> ServerBuilder {
+        self.timeouts = timeouts;
+        self
+    }
+
+    pub fn resolver_threads(mut self, threads: usize) -> ServerBuilder {
+        self.resolver_threads = threads;
+        self
+    }
+
+    /// threads checking name/password of clients
+    pub fn verifier_threads(mut self, threads: usize) -> ServerBuilder {
+        self.verifier_threads = threads;
+        self
+    }
+
+    pub fn dns_cache(mut self, config: DnsCacheConfig) -> ServerBuilder {
+        self.dns_cache = config;
+        self
+    }
+
+    /// bind listen addresses, the server accepts clients once it runs
+    pub fn build(self) -> Result<Server, String> {
+        if self.listen.is_empty() {
+            return Err("no listen address is given.".to_string());
+        }
+
+        let users = self.users.clone().unwrap_or_else(|| Arc::new(MemoryAuthenticator::new()));
+        let authenticator: Arc<dyn Authenticator> = match (self.authenticator.clone(), self.policy, self.users) {
+            (Some(authenticator), _, _) => authenticator,
+            // policy file decides allowed auth-methods per client network
+            (None, Some(policy), _) => Arc::new(PolicyAuthenticator::new(policy, users.clone())),
+            (None, None, Some(users)) => users,
+            (None, None, None) => Arc::new(AnonymousAuthenticator),
+        };
+
+        let poll = Poll::new().map_err(|e| format!("create poll err: {}", e))?;
+        // tokens of the server itself, connections get theirs from `Registry`
+        let mut token_generator = Tokens::new();
+
+        let mut listeners = Vec::new();
+        for (address, policy) in self.listen {
+            let listener = TcpListener::bind(&address)
+                .map_err(|e| format!("bind {} err: {}", address, e))?;
+            let token = token_generator.next();
+            poll.register(&listener, token, Ready::readable(), PollOpt::edge())
+                .map_err(|e| format!("register listener {} err: {}", address, e))?;
+            let authenticator: Arc<dyn Authenticator> = match (&self.authenticator, policy) {
+                (None, Some(policy)) => Arc::new(PolicyAuthenticator::new(policy, users.clone())),
+                _ => authenticator.clone(),
+            };
+            listeners.push((token, listener, authenticator));
+        }
+
+        let resolver = Resolver::with_cache(self.resolver_threads, self.dns_cache);
+        let resolver_token = token_generator.next();
+        poll.register(resolver.registration(), resolver_token, Ready::readable(), PollOpt::edge())
+            .map_err(|e| format!("register resolver err: {}", e))?;
+
+        let verifier = Verifier::new(self.verifier_threads);
+        let verifier_token = token_generator.next();
+        poll.register(verifier.registration(), verifier_token, Ready::readable(), PollOpt::edge())
+            .map_err(|e| format!("register verifier err: {}", e))?;
+
+        let (registration, readiness) = Registration::new2();
+        let shutdown_token = token_generator.next();
+        poll.register(&registration, shutdown_token, Ready::readable(), PollOpt::edge())
+            .map_err(|e| format!("register shutdown err: {}", e))?;
+
+        Ok(Server {
+            poll,
+            listeners,
+            limits: self.limits,
+            timeouts: self.timeouts,
+            resolver,
+            resolver_token,
+            verifier,
+            verifier_token,
+            shutdown_token,
+            _registration: registration,
+            shutdown: ShutdownHandle {
+                flag: Arc::new(AtomicBool::new(false)),
+                readiness,
+            },
+        })
+    }
+}
+
+/// stops a running `Server` from another thread
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    flag: Arc<AtomicBool>,
+    readiness: SetReadiness,
+}
+
+impl ShutdownHandle {
+    /// `run` returns soon after, open connections are closed
+    pub fn shutdown(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+        let _ = self.readiness.set_readiness(Ready::readable());
+    }
+}
+
+/// socks5 server accepting clients on one or more addresses, all
+/// connections are handled by `run` on a single thread.
+pub struct Server {
+    poll: Poll,
+    // authenticator of clients accepted by each listener
+    listeners: Vec<(Token, TcpListener, Arc<dyn Authenticator>)>,
+    limits: BufferLimits,
+    timeouts: Timeouts,
+    resolver: Resolver,
+    resolver_token: Token,
+    verifier: Verifier,
+    verifier_token: Token,
+    shutdown_token: Token,
+    // keeps shutdown readiness registered
+    _registration: Registration,
+    shutdown: ShutdownHandle,
+}
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder {
+            listen: Vec::new(),
+            users: None,
+            policy: None,
+            authenticator: None,
+            limits: BufferLimits::default(),
+            timeouts: Timeouts::default(),
+            resolver_threads: DEFAULT_RESOLVER_THREADS,
+            verifier_threads: DEFAULT_VERIFIER_THREADS,
+            dns_cache: DnsCacheConfig::default(),
+        }
+    }
+
+    /// addresses listeners are bound to, useful when a port is 0
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.listeners.iter()
+            .filter_map(|(_, listener, _)| listener.local_addr().ok())
+            .collect()
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// dns cache hit and miss counters, still updated once `run` owns the server
+    pub fn dns_stats(&self) -> DnsCacheStats {
+        self.resolver.cache().stats()
+    }
+
+    /// handle clients until shut down by a `ShutdownHandle`
+    pub fn run(self) -> Result<(), String> {
+        let Server {
+            poll, listeners, limits, timeouts, mut resolver, resolver_token, verifier, verifier_token
+            , shutdown_token, _registration, shutdown,
+        } = self;
+        let shutdown = shutdown.flag;
+
+        let mut events = Events::with_capacity(128);
+
+        // sockets and handler of every client connection
+        let mut registry = Registry::new();
+
+        // client tokens of connections racing connect attempts
+        let mut connecting = HashSet::<Token>::new();
+
+        // deadlines of client sockets, see `ChildHandler::check_timeout`
+        let mut timers = TimerWheel::<Token>::default();
+
+        let mut buffer = [0u8; 1024 * 256];
+
+        let mut terminate_tokens = Vec::<Token>::new();
+
+        loop {
+            if shutdown.load(Ordering::SeqCst) {
+                break;
+            }
+
+            for token in terminate_tokens.drain(..) {
+                connecting.remove(&side_token(token, Side::Client));
+                if let Some(connection) = registry.remove(token) {
+                    connection.close(&poll);
+                }
+            }
+
+            // wake up in time for the next connect attempt
+            let now = Instant::now();
+            connecting.retain(|token| registry.get(*token).is_some_and(|connection| connection.handler.is_connecting()));
+            let timeout = connecting.iter()
+                .filter_map(|token| registry.get(*token).and_then(|connection| connection.handler.connect_deadline()))
+                .min()
+                .map(|deadline| deadline.saturating_duration_since(now))
+                .unwrap_or(Duration::from_millis(100))
+                .min(Duration::from_millis(100));
+
+            if let Err(e) = poll.poll(&mut events, Some(timeout)) {
+                if e.kind() != ErrorKind::Interrupted {
+                    return Err(format!("poll err: {}", e));
+                }
+            }
+
+            let now = Instant::now();
+            let due: Vec<Token> = connecting.iter()
+                .filter(|token| registry.get(**token)
+                    .and_then(|connection| connection.handler.connect_deadline())
+                    .is_some_and(|deadline| deadline <= now))
+                .cloned()
+                .collect();
+
+            for token in timers.expired(now) {
+                // connection may be closed, or its slot reused
+                let connection = match registry.get_mut(token) {
+                    Some(connection) => connection,
+                    None => continue,
+                };
+
+                if let Some(relay) = &connection.udp_relay {
+                    connection.handler.touch(relay.last_active());
+                }
+
+                if connection.handler.check_timeout(now).is_none() {
+                    schedule_timeout(&mut timers, connection, now);
+                    continue;
+                }
+
+                // failure reply, if any, is sent on a best effort basis
+                let _ = connection.handler.write_to_socket(&mut connection.client, false);
+                terminate_tokens.push(token);
+            }
+
+            for token in due {
+                let connection = match registry.get_mut(token) {
+                    Some(connection) => connection,
+                    None => continue,
+                };
+
+                let result = connection.handler.connect_timer(now);
+                match connect_progress(result, &poll, connection, &mut connecting) {
+                    true => schedule_timeout(&mut timers, connection, now),
+                    false => terminate_tokens.push(token),
+                }
+            }
+
+            for event in events.iter() {
+                match event.token() {
+                    // checked at the top of the loop
+                    token if token == shutdown_token => {}
+                    token if listeners.iter().any(|(listener_token, _, _)| *listener_token == token) => {
+                        let (listener, authenticator) = match listeners.iter().find(|(listener_token, _, _)| *listener_token == token) {
+                            Some((_, listener, authenticator)) => (listener, authenticator),
+                            None => continue,
+                        };
+
+                        loop {
+                            let result = listener.accept();
+                            match result {
+                                Ok((socket, client)) => {
+                                    let connection = registry.insert_with(|token| {
+                                        if let Err(e) = poll.register(&socket, token
+                                                                      , Ready::readable() | Ready::writable()
+                                                                      , PollOpt::edge()) {
+                                            println!("register client socket err:{}", e);
+                                        }
+                                        let mut child = ChildHandler::new(&token, client, authenticator.clone());
+                                        if let Ok(local) = socket.local_addr() {
+                                            child.set_local_address(local);
+                                        }
+                                        child.set_timeouts(timeouts);
+                                        child.set_buffer_limits(limits);
+                                        Connection::new(child, socket)
+                                    });
+                                    schedule_timeout(&mut timers, connection, now);
+                                }
+                                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+
+                                Err(_) => break
+                            }
+                        }
+                    }
+                    token if token == resolver_token => {
+                        while let Some(resolved) = resolver.try_recv() {
+                            // connection may be closed while resolving
+                            let connection = match registry.get_mut(resolved.token) {
+                                Some(connection) => connection,
+                                None => continue,
+                            };
+
+                            if side_of(resolved.token) == Some(Side::UdpRelay) {
+                                if let Some(relay) = &mut connection.udp_relay {
+                                    relay.resolved(&resolved.domain, &resolved.result);
+                                }
+                                continue;
+                            }
+
+                            if !connection.handler.is_resolving() {
+                                continue;
+                            }
+
+                            match resolved_progress(resolved.result, &poll, connection, &mut connecting) {
+                                true => schedule_timeout(&mut timers, connection, now),
+                                false => terminate_tokens.push(resolved.token),
+                            }
+                        }
+                    }
+                    token if token == verifier_token => {
+                        while let Some(verified) = verifier.try_recv() {
+                            // connection may be closed while verifying
+                            let connection = match registry.get_mut(verified.token) {
+                                Some(connection) => connection,
+                                None => continue,
+                            };
+
+                            if !connection.handler.is_authenticating() {
+                                continue;
+                            }
+
+                            match verified_progress(verified.identity, &poll, connection, &mut resolver, &mut connecting) {
+                                true => schedule_timeout(&mut timers, connection, now),
+                                false => terminate_tokens.push(verified.token),
+                            }
+                        }
+                    }
+                    token => {
+                        // stale events of a closed connection find nothing
+                        let (side, connection) = match (side_of(token), registry.get_mut(token)) {
+                            (Some(side), Some(connection)) => (side, connection),
+                            _ => continue,
+                        };
+
+                        let keep = match side {
+                            // a connect attempt finished, successfully or not
+                            Side::Attempt(_) => {
+                                let result = connection.handler.connect_attempt_ready(token);
+                                connect_progress(result, &poll, connection, &mut connecting)
+                            }
+                            Side::UdpRelay => {
+                                if let Some(relay) = &mut connection.udp_relay {
+                                    relay.relay(&mut buffer);
+                                    for domain in relay.take_lookups() {
+                                        resolver.resolve(token, &domain);
+                                    }
+                                }
+                                true
+                            }
+                            Side::BindListener => bind_progress(&poll, connection),
+                            Side::Client => socket_ready(event.readiness(), false, &poll, connection
+                                                         , &mut buffer, &mut resolver, &mut connecting),
+                            Side::Upstream => socket_ready(event.readiness(), true, &poll, connection
+                                                           , &mut buffer, &mut resolver, &mut connecting),
+                        };
+
+                        if !keep {
+                            terminate_tokens.push(token);
+                            continue;
+                        }
+                        schedule_timeout(&mut timers, connection, now);
+
+                        let handler = &mut connection.handler;
+                        if let Some(credentials) = handler.take_verification() {
+                            let authenticator = handler.authenticator().clone();
+                            verifier.verify(*handler.get_token(), authenticator, *handler.client(), credentials);
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// put connection on the timer wheel again when its deadline comes before the
+/// entry it waits for, e.g. a connect timeout shorter than the handshake one.
+/// later deadlines are found when the earlier entry expires.
+fn schedule_timeout(timers: &mut TimerWheel<Token>, connection: &mut Connection, now: Instant) {
+    let deadline = connection.handler.deadline();
+    match connection.scheduled {
+        // entries up to now are already taken from the wheel
+        Some(scheduled) if scheduled > now && scheduled <= deadline => {}
+        _ => {
+            timers.schedule(deadline, *connection.handler.get_token());
+            connection.scheduled = Some(deadline);
+        }
+    }
+}
+
+/// read and relay data of a client or proxy socket which is ready, returns
+/// false when connection should be closed.
+fn socket_ready(readiness: Ready, is_proxy: bool, poll: &Poll, connection: &mut Connection
+                , buffer: &mut [u8], resolver: &mut Resolver, connecting: &mut HashSet<Token>) -> bool {
+    let handler = &mut connection.handler;
+    let socket = match is_proxy {
+        false => &mut connection.client,
+        true => match &mut connection.upstream {
+            Some(socket) => socket,
+            None => return true,
+        },
+    };
+
+    // flush data which did not fit into socket before
+    if readiness.is_writable() {
+        if let Err(msg) = handler.write_to_socket(socket, is_proxy) {
+            println!("write err msg:{:?}", msg);
+            return false;
+        }
+    }
+
+    if !readiness.is_readable() {
+        if pass_eof(connection) {
+            return false;
+        }
+        update_interest(poll, connection);
+        return true;
+    }
+
+    // stop at high-water mark, the rest is read once buffers drain
+    while handler.accepts_data(is_proxy) {
+        match socket.read(buffer) {
+            Ok(0) => {
+                // a relayed connection is half-closed, the other direction goes on
+                match handler.forward_to_proxy() {
+                    true => handler.receive_eof(is_proxy),
+                    false => return false,
+                }
+                break;
+            }
+            Ok(size) => {
+                handler.receive_data(&buffer[..size], is_proxy);
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+            Err(_) => return false,
+        }
+    }
+
+    if let Err(msg) = handler.handle() {
+        println!("read err msg:{:?}", msg);
+        return false;
+    }
+
+    handler_progress(poll, connection, resolver, connecting)
+}
+
+/// carry on with sub negotiation or a http request once credentials of client are
+/// verified, returns false when connection should be closed.
+fn verified_progress(identity: Option<Identity>, poll: &Poll, connection: &mut Connection
+                     , resolver: &mut Resolver, connecting: &mut HashSet<Token>) -> bool {
+    if let Err(msg) = connection.handler.verified(identity) {
+        println!("auth err msg:{:?}", msg);
+        return false;
+    }
+
+    // requests sent by client while waiting
+    if let Err(msg) = connection.handler.handle() {
+        println!("read err msg:{:?}", msg);
+        return false;
+    }
+
+    handler_progress(poll, connection, resolver, connecting)
+}
+
+/// relay and write what `ChildHandler::handle` left, register sockets and lookups
+/// of a new request. returns false when connection should be closed.
+fn handler_progress(poll: &Poll, connection: &mut Connection, resolver: &mut Resolver
+                    , connecting: &mut HashSet<Token>) -> bool {
+    let handler = &mut connection.handler;
+    handler.try_enable_forward();
+    if let Err(msg) = handler.relay() {
+        println!("relay err msg:{:?}", msg);
+        return false;
+    }
+
+    // replies and relayed data, a failure reply is the last thing sent
+    if let Err(msg) = handler.write_to_socket(&mut connection.client, false) {
+        println!("write err msg:{:?}", msg);
+        return false;
+    }
+    if handler.is_closing() {
+        return false;
+    }
+
+    if let Some(upstream) = &mut connection.upstream {
+        if let Err(msg) = handler.write_to_socket(upstream, true) {
+            println!("write err msg:{:?}", msg);
+            return false;
+        }
+    }
+
+    if let Some(domain) = handler.take_lookup() {
+        resolver.resolve(*handler.get_token(), &domain);
+    }
+
+    register_handler_sockets(poll, connection);
+    register_connect_attempts(poll, connection, connecting);
+    if pass_eof(connection) {
+        return false;
+    }
+    update_interest(poll, connection);
+
+    true
+}
+
+/// carry on with a request once its destination is resolved, returns false
+/// when connection should be closed.
+fn resolved_progress(result: Result<Vec<std::net::IpAddr>, String>, poll: &Poll, connection: &mut Connection
+                     , connecting: &mut HashSet<Token>) -> bool {
+    if let Err(msg) = connection.handler.resolved(result) {
+        println!("resolve err msg:{:?}", msg);
+        return false;
+    }
+
+    if connection.handler.write_to_socket(&mut connection.client, false).is_err() || connection.handler.is_closing() {
+        return false;
+    }
+
+    register_handler_sockets(poll, connection);
+    register_connect_attempts(poll, connection, connecting);
+    update_interest(poll, connection);
+
+    true
+}
+
+/// accept the peer a bind request waits for, returns false when connection should be closed.
+fn bind_progress(poll: &Poll, connection: &mut Connection) -> bool {
+    let listener = match &connection.bind_listener {
+        Some(listener) => listener,
+        None => return true,
+    };
+
+    let mut accepted = false;
+    while let Ok((socket, peer)) = listener.accept() {
+        match connection.handler.accept_bind_peer(socket, peer) {
+            Ok(true) => {
+                accepted = true;
+                break;
+            }
+            // unexpected peer is dropped, keep waiting
+            Ok(false) => continue,
+            Err(msg) => {
+                println!("bind err msg:{:?}", msg);
+                break;
+            }
+        }
+    }
+
+    if !accepted {
+        return true;
+    }
+
+    if let Some(listener) = connection.bind_listener.take() {
+        let _ = poll.deregister(&listener);
+    }
+
+    register_handler_sockets(poll, connection);
+
+    // second reply, then data sent by client while waiting
+    let handler = &mut connection.handler;
+    if handler.write_to_socket(&mut connection.client, false).is_err() {
+        return false;
+    }
+    handler.try_enable_forward();
+
+    if handler.relay().is_err() {
+        return false;
+    }
+    if let Some(upstream) = &mut connection.upstream {
+        if handler.write_to_socket(upstream, true).is_err() {
+            return false;
+        }
+    }
+    update_interest(poll, connection);
+
+    true
+}
+
+/// register sockets opened by handler for its request: proxy socket of
+/// bind, bind listener and udp relay.
+fn register_handler_sockets(poll: &Poll, connection: &mut Connection) {
+    let handler = &mut connection.handler;
+    let child_token = *handler.get_token();
+
+    // handler gave up its upstream, e.g. to reach another origin
+    if !handler.proxy_inited() {
+        if let Some(upstream) = connection.upstream.take() {
+            let _ = poll.deregister(&upstream);
+        }
+    }
+
+    if let Some(listener) = handler.take_bind_listener() {
+        let bind_token = side_token(child_token, Side::BindListener);
+        if let Err(e) = poll.register(&listener, bind_token, Ready::readable(), PollOpt::edge()) {
+            println!("register bind listener err:{}", e);
+        }
+        handler.set_bind_token(bind_token);
+        connection.bind_listener = Some(listener);
+    }
+
+    if let Some(relay) = handler.take_udp_relay() {
+        let udp_token = side_token(child_token, Side::UdpRelay);
+        if let Err(e) = poll.register(relay.socket(), udp_token, Ready::readable(), PollOpt::edge()) {
+            println!("register udp relay err:{}", e);
+        }
+        handler.set_udp_token(udp_token);
+        connection.udp_relay = Some(relay);
+    }
+
+    if let Some(proxy_socket) = handler.get_proxy_socket() {
+        let proxy_token = side_token(child_token, Side::Upstream);
+
+        // first register write event
+        if let Err(e) = poll.register(&proxy_socket, proxy_token
+                                      , Ready::readable() | Ready::writable()
+                                      , PollOpt::edge()) {
+            println!("register proxy socket err:{}", e);
+        }
+
+        handler.set_proxy_inited(true);
+        handler.set_dst_token(proxy_token);
+        connection.upstream = Some(proxy_socket);
+    }
+}
+
+/// shut down a socket for writing once its peer finished sending and everything
+/// is relayed, returns true when both directions are finished
+fn pass_eof(connection: &mut Connection) -> bool {
+    if connection.upstream.is_none() {
+        return false;
+    }
+
+    let handler = &mut connection.handler;
+    for (socket, is_proxy) in [(Some(&connection.client), false), (connection.upstream.as_ref(), true)] {
+        if !handler.take_shutdown(is_proxy) {
+            continue;
+        }
+
+        if let Some(socket) = socket {
+            if let Err(e) = socket.shutdown(Shutdown::Write) {
+                println!("shutdown err:{}", e);
+            }
+        }
+    }
+
+    handler.is_finished()
+}
+
+/// switch interest of connection sockets when their buffers ask for it, see `ChildHandler::interest_change`
+fn update_interest(poll: &Poll, connection: &mut Connection) {
+    let handler = &mut connection.handler;
+    let child_token = *handler.get_token();
+    if let Some(interest) = handler.interest_change(false) {
+        if let Err(e) = poll.reregister(&connection.client, child_token, interest, PollOpt::edge()) {
+            println!("reregister client socket err:{}", e);
+        }
+    }
+
+    // proxy socket may not be connected yet
+    if let Some(socket) = &connection.upstream {
+        if let Some(interest) = handler.interest_change(true) {
+            let proxy_token = side_token(child_token, Side::Upstream);
+            if let Err(e) = poll.reregister(socket, proxy_token, interest, PollOpt::edge()) {
+                println!("reregister proxy socket err:{}", e);
+            }
+        }
+    }
+}
+
+/// register connect attempts handler started, the connection then waits for their timers
+fn register_connect_attempts(poll: &Poll, connection: &mut Connection, connecting: &mut HashSet<Token>) {
+    connection.handler.register_connect_attempts(poll);
+    if connection.handler.is_connecting() {
+        connecting.insert(*connection.handler.get_token());
+    }
+}
+
+/// carry on after a connect attempt finished or its timer fired, the winning
+/// attempt becomes proxy socket. returns false when connection should be closed.
+fn connect_progress(result: Result<Option<usize>, String>, poll: &Poll, connection: &mut Connection
+                    , connecting: &mut HashSet<Token>) -> bool {
+    // closed attempts are dropped with the race, their tokens need no cleanup
+    connection.handler.take_closed_attempts();
+
+    match result {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            register_connect_attempts(poll, connection, connecting);
+            return true;
+        }
+        Err(msg) => {
+            println!("connect err msg:{:?}", msg);
+            return false;
+        }
+    }
+
+    // winner is registered as connect attempt, it moves to the upstream side
+    let handler = &mut connection.handler;
+    if let Some(socket) = handler.get_proxy_socket() {
+        let proxy_token = side_token(*handler.get_token(), Side::Upstream);
+        if let Err(e) = poll.reregister(&socket, proxy_token, Ready::readable() | Ready::writable()
+                                        , PollOpt::edge()) {
+            println!("reregister proxy socket err:{}", e);
+            return false;
+        }
+        handler.set_proxy_inited(true);
+        connection.upstream = Some(socket);
+    }
+
+    if handler.write_to_socket(&mut connection.client, false).is_err() || handler.is_closing() {
+        return false;
+    }
+
+    // data sent by client before the reply
+    handler.try_enable_forward();
+    if let Err(msg) = handler.relay() {
+        println!("relay err msg:{:?}", msg);
+        return false;
+    }
+    if let Some(upstream) = &mut connection.upstream {
+        if handler.write_to_socket(upstream, true).is_err() {
+            return false;
+        }
+    }
+    update_interest(poll, connection);
+
+    true
+}
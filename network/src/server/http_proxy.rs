@@ -0,0 +1,485 @@
+use std::net::{IpAddr, SocketAddr};
+use std::time::Instant;
+use base64ct::{Base64, Encoding};
+use mio::Ready;
+use protocol::packet::{AuthType, CmdType, DstServiceRequest, ReplyType, ServerStage, SubVersion
+                       , TargetAddr, UserPassAuthRequest, Version};
+use crate::auth::Identity;
+use crate::buffer::Buffer;
+use crate::http::{Headers, HttpError, HttpEvent, HttpParser, PacketType, Request};
+use super::{ChildHandler, Protocol};
+
+/// headers meant for the proxy itself, they are not forwarded
+const PROXY_HEADERS: [&str; 3] = ["proxy-connection", "proxy-authorization", "host"];
+
+/// http messages relayed for a connection, one request and its response at a time
+#[derive(Debug)]
+pub(super) struct HttpExchange {
+    requests: HttpParser,
+    responses: HttpParser,
+    /// body of a request is being forwarded
+    forwarding_request: bool,
+    /// a request is forwarded and its response is not finished yet
+    awaiting_response: bool,
+    /// rewritten request head waiting for upstream to connect
+    pending_head: Option<Vec<u8>>,
+    /// authority of the origin upstream is connected to, e.g. `example.com:80`
+    origin: Option<String>,
+    /// a `CONNECT` request was taken, bytes are relayed as they are from then on
+    tunnel: bool,
+    /// first request, held while its `Proxy-Authorization` is verified
+    authenticating: Option<Request>,
+}
+
+impl Default for HttpExchange {
+    fn default() -> HttpExchange {
+        HttpExchange {
+            requests: HttpParser::new(PacketType::Request),
+            responses: HttpParser::new(PacketType::Response),
+            forwarding_request: false,
+            awaiting_response: false,
+            pending_head: None,
+            origin: None,
+            tunnel: false,
+            authenticating: None,
+        }
+    }
+}
+
+impl ChildHandler {
+    /// parse requests of client and relay messages of both sides, a request is only
+    /// parsed after the response of the previous one is finished
+    pub(super) fn handle_http(&mut self) -> Result<usize, String> {
+        loop {
+            if self.stage == ServerStage::Closing || self.stage == ServerStage::Authenticating || self.http.tunnel {
+                return Ok(0);
+            }
+
+            let mut progress = false;
+            if !self.http.forwarding_request && !self.http.awaiting_response {
+                progress |= self.parse_http_request()?;
+            }
+
+            if self.stage == ServerStage::RequestFinish {
+                progress |= self.relay_http_request()?;
+                progress |= self.relay_http_response()?;
+            }
+
+            if !progress {
+                break;
+            }
+        }
+
+        self.http_upstream_eof();
+
+        Ok(self.send_buffer.len())
+    }
+
+    /// take a request head from client and start connecting to its origin,
+    /// returns false while the head is incomplete
+    fn parse_http_request(&mut self) -> Result<bool, String> {
+        let (request, len) = match self.http.requests.parse(self.receive_buffer.as_slice()) {
+            Ok(Some((HttpEvent::Request(request), len))) => (request, len),
+            Ok(_) => return Ok(false),
+            Err(HttpError::HeadTooLarge) => {
+                self.refuse_http(431, "Request Header Fields Too Large");
+                return Ok(false);
+            }
+            Err(e) => return self.bad_http_request(&e.to_string()),
+        };
+
+        self.receive_buffer.consume(len);
+        self.requested = Some(Instant::now());
+
+        if !self.http_authenticate(&request.headers) {
+            println!("http auth failed for {}", self.client);
+            self.refuse_http(407, "Proxy Authentication Required");
+            return Ok(false);
+        }
+
+        if self.stage == ServerStage::Authenticating {
+            self.http.authenticating = Some(request);
+            return Ok(false);
+        }
+
+        self.take_http_request(request)
+    }
+
+    /// start relaying a request of an authenticated client
+    fn take_http_request(&mut self, request: Request) -> Result<bool, String> {
+        let (method, target) = (request.method.as_str(), request.target.as_str());
+        if method == "CONNECT" {
+            return self.start_tunnel(target);
+        }
+
+        let (address, authority, path) = match split_target(target) {
+            Ok(result) => result,
+            Err(msg) => return self.bad_http_request(&msg),
+        };
+
+        println!("http {} {} for {}", method, target, self.session_name());
+
+        // origin-form request line, host header follows the target
+        let mut headers = Headers::new();
+        headers.append("Host", authority);
+        for (name, value) in request.headers.iter() {
+            if !PROXY_HEADERS.iter().any(|header| name.eq_ignore_ascii_case(header)) {
+                headers.append(name, value);
+            }
+        }
+        let rewritten = Request {
+            method: request.method.clone(),
+            target: path,
+            version: request.version,
+            headers,
+        }.encode();
+
+        let origin = authority.to_ascii_lowercase();
+        let reuse = self.stage == ServerStage::RequestFinish && !self.proxy_eof
+            && self.http.origin.as_ref() == Some(&origin);
+        if !reuse {
+            self.reset_upstream();
+        }
+
+        self.http.responses.expect_response(method);
+        self.http.forwarding_request = true;
+        self.http.awaiting_response = true;
+        self.http.pending_head = Some(rewritten);
+        if reuse {
+            return Ok(true);
+        }
+
+        // keep-alive connection to another origin is dropped, see `reset_upstream`
+        self.http.origin = Some(origin);
+        self.connect_origin(address)?;
+
+        Ok(true)
+    }
+
+    /// `CONNECT host:port`, once upstream is connected the connection forwards
+    /// raw bytes like a socks5 connect does, anything after the head belongs to the tunnel
+    fn start_tunnel(&mut self, target: &str) -> Result<bool, String> {
+        let address = match split_authority(target, None) {
+            Ok(address) => address,
+            Err(msg) => return self.bad_http_request(&msg),
+        };
+
+        println!("http CONNECT {} for {}", target, self.session_name());
+        self.reset_upstream();
+        self.http.tunnel = true;
+        self.connect_origin(address)?;
+
+        Ok(true)
+    }
+
+    /// connect like a socks5 connect request, domains are resolved first
+    fn connect_origin(&mut self, address: TargetAddr) -> Result<(), String> {
+        match address {
+            TargetAddr::Ip(address) => {
+                self.start_connect(vec![address])?;
+            }
+            TargetAddr::Domain(domain, port) => {
+                self.lookup = Some(domain.clone());
+                self.pending_request = Some(DstServiceRequest::new(
+                    Version::Socks5, CmdType::Connect, 0, TargetAddr::Domain(domain, port)));
+                self.stage = ServerStage::Resolving;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// http messages are parsed and rewritten, false for socks5 and established tunnels
+    pub(super) fn relays_http_messages(&self) -> bool {
+        self.protocol == Protocol::Http && !self.http.tunnel
+    }
+
+    /// upstream is connected, a tunnel is confirmed to client while a request
+    /// head is relayed instead
+    pub(super) fn http_connected(&mut self) -> usize {
+        if !self.http.tunnel {
+            return 0;
+        }
+
+        self.send_buffer.extend_from_slice(b"HTTP/1.1 200 Connection established\r\n\r\n");
+        self.send_buffer.len()
+    }
+
+    /// forward rewritten head and body of current request to upstream
+    fn relay_http_request(&mut self) -> Result<bool, String> {
+        let mut progress = false;
+        if let Some(head) = self.http.pending_head.take() {
+            self.dst_send_buffer.extend_from_slice(&head);
+            progress = true;
+        }
+
+        while self.http.forwarding_request {
+            match self.http.requests.parse(self.receive_buffer.as_slice()).map_err(|e| e.to_string())? {
+                Some((HttpEvent::Body, size)) => transfer(&mut self.receive_buffer, &mut self.dst_send_buffer, size),
+                Some((HttpEvent::End, _)) => self.http.forwarding_request = false,
+                Some(_) => {}
+                None => break,
+            }
+            progress = true;
+        }
+
+        Ok(progress)
+    }
+
+    /// forward response of current request to client, its head is relayed as received
+    fn relay_http_response(&mut self) -> Result<bool, String> {
+        let mut progress = false;
+
+        // origin sends nothing unasked, such data stays buffered
+        while self.http.awaiting_response {
+            let event = match self.http.responses.parse(self.dst_receive_buffer.as_slice()) {
+                Ok(event) => event,
+                Err(e) if self.http.responses.is_idle() => {
+                    println!("bad http response for {}:{}", self.session_name(), e);
+                    self.refuse_http(502, "Bad Gateway");
+                    return Ok(false);
+                }
+                Err(e) => return Err(e.to_string()),
+            };
+
+            match event {
+                // protocol is switched, e.g. to websocket, bytes are relayed as they are from then on
+                Some((HttpEvent::Response(response), size)) if response.status == 101 => {
+                    println!("http switching protocols for {}", self.session_name());
+                    transfer(&mut self.dst_receive_buffer, &mut self.send_buffer, size);
+                    self.http.tunnel = true;
+                    return Ok(true);
+                }
+                Some((HttpEvent::Response(_), size)) | Some((HttpEvent::Body, size)) =>
+                    transfer(&mut self.dst_receive_buffer, &mut self.send_buffer, size),
+                // an interim response is followed by the final one
+                Some((HttpEvent::End, _)) => self.http.awaiting_response = self.http.responses.expects_response(),
+                Some(_) => {}
+                None => break,
+            }
+            progress = true;
+        }
+
+        Ok(progress)
+    }
+
+    /// origin closed its connection, which may end a response or the keep-alive connection
+    fn http_upstream_eof(&mut self) {
+        if !self.proxy_eof || self.stage != ServerStage::RequestFinish || !self.dst_receive_buffer.is_empty() {
+            return;
+        }
+
+        match (self.http.responses.is_idle(), self.http.awaiting_response) {
+            // a response read until close ends, eof is passed on to client, see `take_shutdown`
+            (false, _) => {
+                if let Err(e) = self.http.responses.eof() {
+                    println!("origin closed for {}:{}", self.session_name(), e);
+                    return;
+                }
+                if let Ok(Some((HttpEvent::End, _))) = self.http.responses.parse(&[]) {
+                    self.http.awaiting_response = false;
+                }
+            }
+            (true, true) if self.send_buffer.is_empty() => {
+                println!("origin closed without response for {}", self.session_name());
+                self.refuse_http(502, "Bad Gateway");
+            }
+            // idle keep-alive connection, next request connects again
+            (true, false) if !self.client_eof => {
+                self.reset_upstream();
+                self.stage = ServerStage::ContentFinish;
+            }
+            _ => {}
+        }
+    }
+
+    /// drop upstream and everything relayed with it, event loop closes its socket
+    /// once it sees `proxy_inited` is false
+    fn reset_upstream(&mut self) {
+        self.race = None;
+        self.dst_socket = None;
+        self.dst_token = None;
+        self.proxy_inited = false;
+        self.forward = false;
+        self.dst_send_buffer.clear();
+        self.dst_receive_buffer.clear();
+        self.proxy_eof = false;
+        self.proxy_shutdown = false;
+        self.proxy_paused = false;
+        self.proxy_interest = Ready::readable() | Ready::writable();
+        self.http.responses = HttpParser::new(PacketType::Response);
+        self.http.origin = None;
+    }
+
+    /// identity of client is decided by its first request, `Proxy-Authorization`
+    /// carries name/password the way sub negotiation of socks5 does. name/password
+    /// is verified off the event loop, the request is then held in `Authenticating`
+    fn http_authenticate(&mut self, headers: &Headers) -> bool {
+        if self.identity.is_some() {
+            return true;
+        }
+
+        let credentials = match headers.get("proxy-authorization").and_then(basic_credentials) {
+            Some(Ok(credentials)) => Some(credentials),
+            Some(Err(msg)) => {
+                println!("refuse credentials of {}:{}", self.client, msg);
+                return false;
+            }
+            None => None,
+        };
+
+        let offered = match credentials {
+            Some(_) => vec![AuthType::Non, AuthType::NamePassword],
+            None => vec![AuthType::Non],
+        };
+
+        let identity = match (self.authenticator.select_method(&self.client, &offered), credentials) {
+            (Some(AuthType::NamePassword), Some(credentials)) => {
+                self.verifying = Some(credentials);
+                self.stage = ServerStage::Authenticating;
+                return true;
+            }
+            (Some(method), credentials) =>
+                self.authenticator.authenticate(&self.client, &method, credentials.as_ref()),
+            (None, _) => None,
+        };
+
+        match identity {
+            Some(identity) => {
+                self.identity = Some(identity);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// go on with the request held by `http_authenticate`
+    pub(super) fn http_verified(&mut self, identity: Option<Identity>) -> Result<usize, String> {
+        self.stage = ServerStage::Init;
+        match (identity, self.http.authenticating.take()) {
+            (Some(identity), Some(request)) => {
+                self.identity = Some(identity);
+                self.take_http_request(request)?;
+            }
+            _ => {
+                println!("http auth failed for {}", self.client);
+                self.refuse_http(407, "Proxy Authentication Required");
+            }
+        }
+
+        Ok(self.send_buffer.len())
+    }
+
+    fn bad_http_request(&mut self, msg: &str) -> Result<bool, String> {
+        println!("bad http request from {}:{}", self.session_name(), msg);
+        self.refuse_http(400, "Bad Request");
+        Ok(false)
+    }
+
+    /// buffer an error response and close the connection once it is sent
+    pub(super) fn refuse_http(&mut self, status: u16, reason: &str) {
+        self.send_buffer.extend_from_slice(&http_error_response(status, reason));
+        self.race = None;
+        self.stage = ServerStage::Closing;
+    }
+}
+
+/// response without body sent by the proxy itself, the connection is closed after it
+pub(super) fn http_error_response(status: u16, reason: &str) -> Vec<u8> {
+    let challenge = match status {
+        407 => "Proxy-Authenticate: Basic realm=\"rsocks\"\r\n",
+        _ => "",
+    };
+
+    format!("HTTP/1.1 {} {}\r\n{}Content-Length: 0\r\nConnection: close\r\n\r\n", status, reason, challenge)
+        .into_bytes()
+}
+
+/// http status telling client why its origin can not be reached
+pub(super) fn http_status(reply: &ReplyType) -> (u16, &'static str) {
+    match reply {
+        ReplyType::TTLExpired => (504, "Gateway Timeout"),
+        ReplyType::ConnectionNotAllowed => (403, "Forbidden"),
+        _ => (502, "Bad Gateway"),
+    }
+}
+
+/// move `size` bytes from the front of `from` to the back of `to`
+fn transfer(from: &mut Buffer, to: &mut Buffer, size: usize) {
+    if size == from.len() {
+        to.append(from);
+        return;
+    }
+
+    to.extend_from_slice(&from.as_slice()[..size]);
+    from.consume(size);
+}
+
+/// destination, authority and origin-form of an absolute-form target, e.g.
+/// `http://example.com:8080/index.html?q` is `example.com:8080` and `/index.html?q`
+fn split_target(target: &str) -> Result<(TargetAddr, &str, String), String> {
+    let rest = match target.get(..7) {
+        Some(scheme) if scheme.eq_ignore_ascii_case("http://") => &target[7..],
+        _ => return Err(format!("only absolute http target is supported:{}", target)),
+    };
+
+    let end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    let (authority, path) = rest.split_at(end);
+    let path = match path.find('#') {
+        Some(fragment) => &path[..fragment],
+        None => path,
+    };
+    let path = match path.starts_with('/') {
+        true => path.to_string(),
+        false => format!("/{}", path),
+    };
+
+    if authority.contains('@') {
+        return Err("user info in target is not supported.".to_string());
+    }
+
+    let address = split_authority(authority, Some(80))?;
+    Ok((address, authority, path))
+}
+
+/// destination of `host:port`, the port may only be left out when there is a default
+fn split_authority(authority: &str, default_port: Option<u16>) -> Result<TargetAddr, String> {
+    // ipv6 literal is enclosed in brackets
+    let (host, port) = match authority.rfind(':') {
+        Some(colon) if !authority[colon..].contains(']') => (&authority[..colon], &authority[colon + 1..]),
+        _ => (authority, ""),
+    };
+    let port = match (port, default_port) {
+        ("", Some(port)) => port,
+        ("", None) => return Err(format!("port is missing:{}", authority)),
+        (port, _) => port.parse::<u16>().map_err(|_| format!("port is not correct:{}", port))?,
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return Err("host is missing.".to_string());
+    }
+
+    Ok(match host.parse::<IpAddr>() {
+        Ok(ip) => TargetAddr::Ip(SocketAddr::new(ip, port)),
+        Err(_) => TargetAddr::Domain(host.to_string(), port),
+    })
+}
+
+/// name and password of `Basic` credentials, an error when either does not
+/// fit the 255 bytes rfc 1929 allows, it could only be checked truncated
+fn basic_credentials(value: &str) -> Option<Result<UserPassAuthRequest, String>> {
+    let (scheme, encoded) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+
+    let decoded = Base64::decode_vec(encoded.trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (name, password) = decoded.split_once(':')?;
+
+    if name.len() > 255 || password.len() > 255 {
+        return Some(Err("name or password is longer than 255 bytes.".to_string()));
+    }
+
+    Some(Ok(UserPassAuthRequest::new(SubVersion::V1, name.to_string(), password.to_string())))
+}
@@ -0,0 +1,56 @@
+use std::net::SocketAddr;
+use std::time::Instant;
+use protocol::packet::{AuthType, CmdType, DstServiceRequest, ReplyType, ServerStage, TargetAddr, Version
+                       , encode_socks4_reply, parse_socks4_request};
+use super::ChildHandler;
+
+impl ChildHandler {
+    /// socks4 has no method selection, the request comes first and only connect is served
+    pub(super) fn handle_socks4_request(&mut self) -> Result<Option<usize>, String> {
+        let (request, request_len) = match parse_socks4_request(self.receive_buffer.as_slice()) {
+            Ok(Some(result)) => result,
+            Ok(None) => return Ok(None),
+            Err(e) => {
+                println!("refuse socks4 request from {}:{}", self.session_name(), e);
+                self.receive_buffer.clear();
+                return self.refuse_dst_request(e.reply_type()).map(Some);
+            }
+        };
+
+        self.clear_receive_buffer(request_len);
+        self.requested = Some(Instant::now());
+
+        // user id is not a credential, clients which must authenticate can not use socks4
+        let identity = self.authenticator.select_method(&self.client, &[AuthType::Non])
+            .and_then(|method| self.authenticator.authenticate(&self.client, &method, None));
+        match identity {
+            Some(identity) => self.identity = Some(identity),
+            None => {
+                println!("socks4 request of user id:{} from {} not allowed", request.user_id(), self.client);
+                return self.refuse_dst_request(ReplyType::ConnectionNotAllowed).map(Some);
+            }
+        }
+
+        if let CmdType::Bind = request.cmd() {
+            return self.refuse_dst_request(ReplyType::CmdNotSupport).map(Some);
+        }
+
+        match request.address() {
+            TargetAddr::Ip(address) => self.start_connect(vec![*address]).map(Some),
+            TargetAddr::Domain(domain, _) => {
+                // resolved like a socks5 connect, see `resolved`
+                self.lookup = Some(domain.clone());
+                self.pending_request = Some(DstServiceRequest::new(
+                    Version::Socks5, CmdType::Connect, 0, request.address().clone()));
+                self.stage = ServerStage::Resolving;
+                Ok(Some(0))
+            }
+        }
+    }
+
+    pub(super) fn buffer_socks4_reply(&mut self, reply: ReplyType, address: &SocketAddr) -> usize {
+        let data = encode_socks4_reply(&reply, address);
+        self.send_buffer.extend_from_slice(&data);
+        data.len()
+    }
+}
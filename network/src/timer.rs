@@ -0,0 +1,122 @@
+use std::time::{Duration, Instant};
+
+/// default resolution of the timer wheel, the event loop wakes up at least this often
+pub const DEFAULT_TICK: Duration = Duration::from_millis(100);
+
+/// default number of slots, one round of the wheel is `tick * slots`
+pub const DEFAULT_SLOTS: usize = 512;
+
+/// time limits of a connection
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timeouts {
+    /// from accept until the request is received
+    pub handshake: Duration,
+    /// from the request until destination is resolved and connected
+    pub connect: Duration,
+    /// without data in either direction once relaying
+    pub idle: Duration,
+    /// whole life of a connection
+    pub max_lifetime: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Timeouts {
+        Timeouts {
+            handshake: Duration::from_secs(10),
+            connect: Duration::from_secs(10),
+            idle: Duration::from_secs(5 * 60),
+            max_lifetime: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+/// which limit of `Timeouts` is exceeded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Timeout {
+    Handshake,
+    Connect,
+    Idle,
+    Lifetime,
+}
+
+/// hashed timer wheel.
+///
+/// an item is kept in the slot of its deadline tick, deadlines further away
+/// than one round stay in their slot until the round they belong to.
+/// expired items are only a hint, owners check their real deadline.
+pub struct TimerWheel<T> {
+    tick: Duration,
+    origin: Instant,
+    // ticks since origin which are processed
+    current: u64,
+    slots: Vec<Vec<(Instant, T)>>,
+    len: usize,
+}
+
+impl<T> TimerWheel<T> {
+    pub fn new(tick: Duration, slots: usize, now: Instant) -> TimerWheel<T> {
+        TimerWheel {
+            tick: tick.max(Duration::from_millis(1)),
+            origin: now,
+            current: 0,
+            slots: (0..slots.max(1)).map(|_| Vec::new()).collect(),
+            len: 0,
+        }
+    }
+
+    pub fn schedule(&mut self, deadline: Instant, item: T) {
+        // a deadline in the past fires on next call to `expired`
+        let tick = self.tick_of(deadline).max(self.current);
+        let slot = (tick % self.slots.len() as u64) as usize;
+        self.slots[slot].push((deadline, item));
+        self.len += 1;
+    }
+
+    /// remove and return items whose deadline is reached
+    pub fn expired(&mut self, now: Instant) -> Vec<T> {
+        let target = self.tick_of(now);
+        let rounds = self.slots.len() as u64;
+
+        // after a long pause every slot is visited once
+        if target > self.current + rounds {
+            self.current = target - rounds;
+        }
+
+        let mut result = Vec::new();
+        loop {
+            let slot = (self.current % rounds) as usize;
+            let (due, kept): (Vec<_>, Vec<_>) = self.slots[slot].drain(..)
+                .partition(|(deadline, _)| *deadline <= now);
+            self.slots[slot] = kept;
+            self.len -= due.len();
+            result.extend(due.into_iter().map(|(_, item)| item));
+
+            // the slot of now may still get items due later in this tick
+            if self.current >= target {
+                break;
+            }
+            self.current += 1;
+        }
+
+        result
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn tick_of(&self, time: Instant) -> u64 {
+        let elapsed = time.saturating_duration_since(self.origin);
+        (elapsed.as_nanos() / self.tick.as_nanos()) as u64
+    }
+}
+
+impl<T> Default for TimerWheel<T> {
+    fn default() -> TimerWheel<T> {
+        TimerWheel::new(DEFAULT_TICK, DEFAULT_SLOTS, Instant::now())
+    }
+}
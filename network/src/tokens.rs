@@ -1,5 +1,6 @@
 use mio::Token;
 
+/// tokens of sockets owned by the server itself, connections get theirs from `Registry`
 pub struct Tokens {
     count: usize
 }
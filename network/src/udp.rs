@@ -0,0 +1,187 @@
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Instant;
+use mio::net::UdpSocket;
+use protocol::packet::{UdpRequestHeader, TargetAddr, parse_udp_request_header, encode_udp_request_header};
+use protocol::reassembly::Reassembler;
+
+/// datagrams kept per association while their destination is resolved
+pub const MAX_PENDING_DATAGRAMS: usize = 64;
+
+/// relay socket of one udp association, lives as long as the controlling tcp connection.
+///
+/// datagrams from client carry a header with the destination, datagrams from
+/// anywhere else are wrapped with a header naming their source and sent to client.
+pub struct UdpRelay {
+    socket: UdpSocket,
+    client_ip: IpAddr,
+    client: Option<SocketAddr>,
+    reassembler: Reassembler,
+    // domain, port and user data
+    pending: Vec<(String, u16, Vec<u8>)>,
+    lookups: Vec<String>,
+    last_active: Instant,
+}
+
+impl UdpRelay {
+    /// `client_ip` is the address of tcp connection, `expected` is DST.ADDR and DST.PORT
+    /// of the request, which are zero when client does not know them yet.
+    pub fn bind(local: IpAddr, client_ip: IpAddr, expected: SocketAddr) -> Result<UdpRelay, Error> {
+        let socket = UdpSocket::bind(&SocketAddr::new(local, 0))?;
+
+        // datagrams are only accepted from the host which made the request
+        let client = match expected.port() {
+            0 => None,
+            port => Some(SocketAddr::new(client_ip, port)),
+        };
+
+        Ok(UdpRelay {
+            socket,
+            client_ip,
+            client,
+            reassembler: Reassembler::default(),
+            pending: Vec::new(),
+            lookups: Vec::new(),
+            last_active: Instant::now(),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        self.socket.local_addr()
+    }
+
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    /// when a datagram was last received from either side
+    pub fn last_active(&self) -> Instant {
+        self.last_active
+    }
+
+    /// relay all datagrams waiting on socket, returns the number relayed
+    pub fn relay(&mut self, buffer: &mut [u8]) -> usize {
+        self.reassembler.expire(Instant::now());
+
+        let mut count = 0;
+        loop {
+            let (size, from) = match self.socket.recv_from(buffer) {
+                Ok(result) => result,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    println!("udp relay receive err:{}", e);
+                    break;
+                }
+            };
+            self.last_active = Instant::now();
+
+            let result = match self.is_client(&from) {
+                true => self.forward_to_target(&buffer[..size]),
+                false => self.forward_to_client(&buffer[..size], from),
+            };
+
+            // a datagram which can not be relayed is dropped
+            match result {
+                Ok(_) => count += 1,
+                Err(msg) => println!("drop datagram from {}:{}", from, msg),
+            }
+        }
+
+        count
+    }
+
+    fn is_client(&mut self, from: &SocketAddr) -> bool {
+        match self.client {
+            Some(client) => client == *from,
+            // the first datagram from client host tells its port
+            None if from.ip() == self.client_ip => {
+                self.client = Some(*from);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn forward_to_target(&mut self, data: &[u8]) -> Result<usize, String> {
+        let (header, offset) = parse_udp_request_header(data).map_err(|e| e.to_string())?;
+
+        // fragments are kept until the whole datagram arrives
+        let (address, data) = match self.reassembler.push(&header, &data[offset..], Instant::now()) {
+            Some(result) => result,
+            None => return Ok(0),
+        };
+
+        let (domain, port) = match address {
+            TargetAddr::Ip(target) => return self.send_to(&data, &target),
+            TargetAddr::Domain(domain, port) => (domain, port),
+        };
+
+        if self.pending.len() >= MAX_PENDING_DATAGRAMS {
+            return Err("too many datagrams are waiting for resolving.".to_string());
+        }
+
+        if !self.pending.iter().any(|(pending, _, _)| *pending == domain) {
+            self.lookups.push(domain.clone());
+        }
+        self.pending.push((domain, port, data));
+
+        Ok(0)
+    }
+
+    /// domains which should be resolved for pending datagrams, taken once
+    pub fn take_lookups(&mut self) -> Vec<String> {
+        self.lookups.drain(..).collect()
+    }
+
+    /// send datagrams waiting for `domain`, they are dropped when it can not be resolved
+    pub fn resolved(&mut self, domain: &str, result: &Result<Vec<IpAddr>, String>) -> usize {
+        let (waiting, pending) = self.pending.drain(..)
+            .partition::<Vec<_>, _>(|(pending, _, _)| pending == domain);
+        self.pending = pending;
+
+        // prefer the family relay socket is bound to
+        let is_ipv4 = self.socket.local_addr().map(|local| local.is_ipv4()).unwrap_or(true);
+        let ip = match result {
+            Ok(ips) => ips.iter().find(|ip| ip.is_ipv4() == is_ipv4).or_else(|| ips.first()),
+            Err(_) => None,
+        };
+
+        let ip = match ip {
+            Some(ip) => *ip,
+            None => {
+                println!("drop {} datagrams to {}: not resolved", waiting.len(), domain);
+                return 0;
+            }
+        };
+
+        let mut count = 0;
+        for (_, port, data) in waiting {
+            match self.send_to(&data, &SocketAddr::new(ip, port)) {
+                Ok(_) => count += 1,
+                Err(msg) => println!("drop datagram to {}:{}", domain, msg),
+            }
+        }
+
+        count
+    }
+
+    fn forward_to_client(&self, data: &[u8], from: SocketAddr) -> Result<usize, String> {
+        let client = match self.client {
+            Some(client) => client,
+            None => return Err("client address is unknown.".to_string()),
+        };
+
+        let header = UdpRequestHeader::new(0, TargetAddr::from(from));
+        let mut datagram = encode_udp_request_header(&header).map_err(|e| e.to_string())?;
+        datagram.extend_from_slice(data);
+
+        self.send_to(&datagram, &client)
+    }
+
+    fn send_to(&self, data: &[u8], target: &SocketAddr) -> Result<usize, String> {
+        match self.socket.send_to(data, target) {
+            Ok(size) => Ok(size),
+            Err(e) => Err(format!("send to {} failed: {}", target, e)),
+        }
+    }
+}
@@ -1,17 +1,34 @@
 mod unit_test {
-    use crate::server::ChildHandler;
+    use crate::server::{ChildHandler, Protocol, Server, connect_error_reply, detect_protocol};
     use crate::http;
     use crate::http::*;
     use mio::Token;
     use crate::tokens::Tokens;
+    use crate::auth::*;
+    use crate::policy::*;
+    use std::sync::Arc;
+    use std::net::SocketAddr;
+    use protocol::packet::{AuthType, SubVersion, UserPassAuthRequest, ReplyType, TargetAddr
+                           , UdpRequestHeader, parse_dst_service_reply, parse_udp_request_header
+                           , encode_udp_request_header};
+    use crate::udp::UdpRelay;
+    use crate::resolver::Resolver;
+    use crate::workers::WorkerPool;
+    use crate::happy_eyeballs::{ConnectRace, RaceState, interleave};
+    use crate::buffer::{Buffer, BufferLimits};
+    use crate::timer::{TimerWheel, Timeout, Timeouts};
+    use mio::Ready;
+    use crate::dns_cache::{DnsCache, DnsCacheConfig};
+    use crate::registry::{Connection, Registry, Side, RESERVED_TOKENS, side_of, side_token};
+    use std::time::{Duration, Instant};
 
     #[test]
     fn handle_init_test() {
         let mut child_handler = ChildHandler::new_test(&Token(0));
         // let bytes = &[5, 1, 0];
-        child_handler.receive_u8_data(5, false);
-        child_handler.receive_u8_data(1, false);
-        child_handler.receive_u8_data(0, false);
+        child_handler.receive_u8_data(5, false).unwrap();
+        child_handler.receive_u8_data(1, false).unwrap();
+        child_handler.receive_u8_data(0, false).unwrap();
 
         let size = child_handler.handle_init_stage();
 
@@ -23,6 +40,1468 @@ mod unit_test {
         }
     }
 
+    #[test]
+    fn handle_dst_request_with_unsupported_cmd() {
+        let mut child_handler = ChildHandler::new_test(&Token(0));
+        for byte in [5 as u8, 9, 0, 1, 127, 0, 0, 1, 0, 80].iter() {
+            child_handler.receive_u8_data(*byte, false).unwrap();
+        }
+
+        let size = child_handler.handle_dst_request();
+
+        match size {
+            Ok(Some(len)) => {
+                assert_eq!(10, len);
+                assert!(child_handler.is_closing());
+            }
+            _ => unreachable!()
+        }
+    }
+
+    fn bind_handler(expected_peer: [u8; 4]) -> ChildHandler {
+        let mut child_handler = ChildHandler::new_test(&Token(0));
+        child_handler.set_local_address("127.0.0.1:1080".parse().unwrap());
+        for byte in [5 as u8, 1, 0].iter() {
+            child_handler.receive_u8_data(*byte, false).unwrap();
+        }
+        child_handler.handle().unwrap();
+        child_handler.clear_send_buffer(false);
+
+        let request = [5 as u8, 2, 0, 1];
+        for byte in request.iter().chain(expected_peer.iter()).chain([0 as u8, 0].iter()) {
+            child_handler.receive_u8_data(*byte, false).unwrap();
+        }
+        child_handler
+    }
+
+    fn accept_bind_peer(child_handler: &mut ChildHandler, listener: &mio::net::TcpListener)
+                        -> Result<bool, String> {
+        let address = listener.local_addr().unwrap();
+        let _peer = std::net::TcpStream::connect(address).unwrap();
+        loop {
+            match listener.accept() {
+                Ok((socket, peer)) => return child_handler.accept_bind_peer(socket, peer),
+                Err(_) => std::thread::sleep(std::time::Duration::from_millis(10)),
+            }
+        }
+    }
+
+    #[test]
+    fn handle_bind_request_success() {
+        let mut child_handler = bind_handler([0, 0, 0, 0]);
+
+        assert_eq!(Ok(2), child_handler.handle());
+        assert_eq!(10, child_handler.send_buffer().len());
+        assert!(child_handler.is_bind_listening());
+
+        let reply = parse_dst_service_reply(child_handler.send_buffer()).unwrap().unwrap();
+        let listener = child_handler.take_bind_listener().unwrap();
+        assert_eq!(&ReplyType::Success, reply.reply());
+        assert_eq!(&TargetAddr::from(listener.local_addr().unwrap()), reply.address());
+
+        child_handler.clear_send_buffer(false);
+        assert_eq!(Ok(true), accept_bind_peer(&mut child_handler, &listener));
+        assert!(!child_handler.is_bind_listening());
+        assert!(child_handler.get_proxy_socket().is_some());
+
+        let reply = parse_dst_service_reply(child_handler.send_buffer()).unwrap().unwrap();
+        assert_eq!(&ReplyType::Success, reply.reply());
+        assert_eq!("127.0.0.1".parse::<std::net::IpAddr>().unwrap(), match reply.address() {
+            TargetAddr::Ip(address) => address.ip(),
+            _ => unreachable!(),
+        });
+    }
+
+    #[test]
+    fn handle_bind_request_rejects_unexpected_peer() {
+        let mut child_handler = bind_handler([10, 0, 0, 9]);
+        child_handler.handle().unwrap();
+        let listener = child_handler.take_bind_listener().unwrap();
+
+        assert_eq!(Ok(false), accept_bind_peer(&mut child_handler, &listener));
+        assert!(child_handler.is_bind_listening());
+        assert!(child_handler.get_proxy_socket().is_none());
+    }
+
+    #[test]
+    fn handle_udp_request_success() {
+        let mut child_handler = ChildHandler::new_test(&Token(0));
+        child_handler.set_local_address("127.0.0.1:1080".parse().unwrap());
+        for byte in [5 as u8, 1, 0, 5, 3, 0, 1, 0, 0, 0, 0, 0, 0].iter() {
+            child_handler.receive_u8_data(*byte, false).unwrap();
+        }
+        child_handler.handle().unwrap();
+        child_handler.clear_send_buffer(false);
+
+        assert_eq!(Ok(2), child_handler.handle());
+        let reply = parse_dst_service_reply(child_handler.send_buffer()).unwrap().unwrap();
+        let relay = child_handler.take_udp_relay().unwrap();
+        assert_eq!(&ReplyType::Success, reply.reply());
+        assert_eq!(&TargetAddr::from(relay.local_addr().unwrap()), reply.address());
+
+        // control connection carries nothing after the reply
+        child_handler.receive_u8_data(1, false).unwrap();
+        assert_eq!(Ok(0), child_handler.handle());
+        assert!(!child_handler.forward_to_proxy());
+    }
+
+    fn relay_until(relay: &mut UdpRelay, count: usize) {
+        let mut buffer = [0 as u8; 1024];
+        let mut relayed = 0;
+        while relayed < count {
+            relayed += relay.relay(&mut buffer);
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn udp_relay_round_trip() {
+        let localhost: std::net::IpAddr = "127.0.0.1".parse().unwrap();
+        let mut relay = UdpRelay::bind(localhost, localhost, "0.0.0.0:0".parse().unwrap()).unwrap();
+        let relay_address = relay.local_addr().unwrap();
+
+        let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let target = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let target_address = target.local_addr().unwrap();
+
+        let header = UdpRequestHeader::new(0, TargetAddr::from(target_address));
+        let mut datagram = encode_udp_request_header(&header).unwrap();
+        datagram.extend_from_slice(b"ping");
+        client.send_to(&datagram, relay_address).unwrap();
+        relay_until(&mut relay, 1);
+
+        let mut buffer = [0 as u8; 64];
+        let (size, from) = target.recv_from(&mut buffer).unwrap();
+        assert_eq!(b"ping", &buffer[..size]);
+        assert_eq!(relay_address, from);
+
+        target.send_to(b"pong", relay_address).unwrap();
+        relay_until(&mut relay, 1);
+
+        let (size, _) = client.recv_from(&mut buffer).unwrap();
+        let (header, offset) = parse_udp_request_header(&buffer[..size]).unwrap();
+        assert_eq!(&TargetAddr::from(target_address), header.address());
+        assert_eq!(b"pong", &buffer[offset..size]);
+    }
+
+    fn connect_handler(port: u16) -> ChildHandler {
+        let mut child_handler = ChildHandler::new_test(&Token(0));
+        for byte in [5 as u8, 1, 0].iter() {
+            child_handler.receive_u8_data(*byte, false).unwrap();
+        }
+        child_handler.handle().unwrap();
+        child_handler.clear_send_buffer(false);
+
+        let port = port.to_be_bytes();
+        for byte in [5 as u8, 1, 0, 1, 127, 0, 0, 1, port[0], port[1]].iter() {
+            child_handler.receive_u8_data(*byte, false).unwrap();
+        }
+        child_handler.handle().unwrap();
+        child_handler
+    }
+
+    fn finish_connect(child_handler: &mut ChildHandler) -> ReplyType {
+        let poll = mio::Poll::new().unwrap();
+        let mut events = mio::Events::with_capacity(16);
+        while child_handler.is_connecting() {
+            child_handler.register_connect_attempts(&poll);
+            poll.poll(&mut events, Some(Duration::from_millis(10))).unwrap();
+            for event in events.iter() {
+                if child_handler.is_connecting() {
+                    child_handler.connect_attempt_ready(event.token()).unwrap();
+                }
+            }
+            child_handler.connect_timer(Instant::now()).unwrap();
+        }
+
+        let reply = parse_dst_service_reply(child_handler.send_buffer()).unwrap().unwrap();
+        reply.reply().clone()
+    }
+
+    #[test]
+    fn handle_connect_reply_after_connected() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut child_handler = connect_handler(listener.local_addr().unwrap().port());
+
+        assert!(child_handler.is_connecting());
+        assert!(child_handler.send_buffer().is_empty());
+
+        assert_eq!(ReplyType::Success, finish_connect(&mut child_handler));
+        child_handler.try_enable_forward();
+        assert!(child_handler.forward_to_proxy());
+    }
+
+    #[test]
+    fn handle_connect_refused() {
+        let port = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let mut child_handler = connect_handler(port);
+
+        assert_eq!(ReplyType::ConnectionRefuse, finish_connect(&mut child_handler));
+        assert!(child_handler.is_closing());
+    }
+
+    fn refused_address() -> SocketAddr {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    fn race(addresses: Vec<SocketAddr>) -> RaceState {
+        let poll = mio::Poll::new().unwrap();
+        let mut tokens = Tokens::new();
+        let mut events = mio::Events::with_capacity(16);
+        let delay = Duration::from_millis(50);
+        let (mut race, mut state) = ConnectRace::start(addresses, delay, Instant::now());
+        while let RaceState::Pending = state {
+            race.register(&poll, || tokens.next());
+            poll.poll(&mut events, Some(Duration::from_millis(10))).unwrap();
+            for event in events.iter() {
+                if let RaceState::Pending = state {
+                    state = race.ready(event.token(), Instant::now());
+                }
+            }
+            if let RaceState::Pending = state {
+                state = race.timer(Instant::now());
+            }
+        }
+        state
+    }
+
+    #[test]
+    fn connect_race_falls_back_to_next_address() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let target = listener.local_addr().unwrap();
+
+        match race(vec![refused_address(), target]) {
+            RaceState::Connected(_, address) => assert_eq!(target, address),
+            _ => panic!("connect should succeed"),
+        }
+    }
+
+    #[test]
+    fn connect_race_reports_failure_of_all_addresses() {
+        match race(vec![refused_address(), refused_address()]) {
+            RaceState::Failed(reply) => assert_eq!(ReplyType::ConnectionRefuse, reply),
+            _ => panic!("connect should fail"),
+        }
+    }
+
+    #[test]
+    fn interleave_address_families() {
+        let addresses: Vec<SocketAddr> = ["[::1]:1", "[::2]:1", "10.0.0.1:1", "10.0.0.2:1", "[::3]:1"]
+            .iter().map(|address| address.parse().unwrap()).collect();
+        let expected: Vec<SocketAddr> = ["[::1]:1", "10.0.0.1:1", "[::2]:1", "10.0.0.2:1", "[::3]:1"]
+            .iter().map(|address| address.parse().unwrap()).collect();
+
+        assert_eq!(expected, interleave(addresses));
+
+        let addresses: Vec<SocketAddr> = ["10.0.0.1:1", "10.0.0.2:1", "[::1]:1"]
+            .iter().map(|address| address.parse().unwrap()).collect();
+        let expected: Vec<SocketAddr> = ["[::1]:1", "10.0.0.1:1", "10.0.0.2:1"]
+            .iter().map(|address| address.parse().unwrap()).collect();
+
+        assert_eq!(expected, interleave(addresses));
+    }
+
+    #[test]
+    fn connect_race_tries_ipv6_first() {
+        let ipv4 = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let ipv6 = std::net::TcpListener::bind("[::1]:0").unwrap();
+        let target = ipv6.local_addr().unwrap();
+
+        match race(vec![ipv4.local_addr().unwrap(), target]) {
+            RaceState::Connected(_, address) => assert_eq!(target, address),
+            _ => panic!("connect should succeed"),
+        }
+    }
+
+    fn domain_handler(cmd: u8) -> ChildHandler {
+        let mut child_handler = ChildHandler::new_test(&Token(7));
+        for byte in [5 as u8, 1, 0, 5, cmd, 0, 3, 9].iter().chain(b"localhost").chain([0 as u8, 80].iter()) {
+            child_handler.receive_u8_data(*byte, false).unwrap();
+        }
+        child_handler.handle().unwrap();
+        child_handler.clear_send_buffer(false);
+        child_handler.handle().unwrap();
+        child_handler
+    }
+
+    #[test]
+    fn handle_dst_request_waits_for_resolving() {
+        let mut child_handler = domain_handler(1);
+
+        assert!(child_handler.is_resolving());
+        assert!(child_handler.send_buffer().is_empty());
+        assert_eq!(Some("localhost".to_string()), child_handler.take_lookup());
+        assert_eq!(None, child_handler.take_lookup());
+
+        let ip = "127.0.0.1".parse().unwrap();
+        assert_eq!(Ok(0), child_handler.resolved(Ok(vec![ip])));
+        assert!(child_handler.is_connecting());
+        assert!(child_handler.resolved(Ok(vec![ip])).is_err());
+    }
+
+    #[test]
+    fn handle_dst_request_resolving_failed() {
+        let mut child_handler = domain_handler(3);
+
+        assert_eq!(Ok(10), child_handler.resolved(Err("lookup failed".to_string())));
+        assert!(child_handler.is_closing());
+        let reply = parse_dst_service_reply(child_handler.send_buffer()).unwrap().unwrap();
+        assert_eq!(&ReplyType::HostUnreachable, reply.reply());
+    }
+
+    #[test]
+    fn worker_pool_delivers_results() {
+        let pool = WorkerPool::new("doubler", 2, || |number: usize| number * 2);
+        for number in 1..4 {
+            pool.submit(number);
+        }
+
+        let mut results = Vec::new();
+        while results.len() < 3 {
+            match pool.try_recv() {
+                Some(result) => results.push(result),
+                None => std::thread::sleep(Duration::from_millis(10)),
+            }
+        }
+        results.sort();
+        assert_eq!(vec![2, 4, 6], results);
+        assert_eq!(None, pool.try_recv());
+    }
+
+    #[test]
+    fn resolver_delivers_results() {
+        let mut resolver = Resolver::new(2);
+        resolver.resolve(Token(3), "localhost");
+        resolver.resolve(Token(4), "no-such-host.invalid");
+
+        let mut results = Vec::new();
+        while results.len() < 2 {
+            match resolver.try_recv() {
+                Some(resolved) => results.push(resolved),
+                None => std::thread::sleep(std::time::Duration::from_millis(10)),
+            }
+        }
+        results.sort_by_key(|resolved| resolved.token.0);
+
+        assert_eq!("localhost", results[0].domain);
+        assert!(results[0].result.as_ref().unwrap().iter().any(|ip| ip.is_loopback()));
+        assert_eq!(Token(4), results[1].token);
+        assert!(results[1].result.is_err());
+    }
+
+    #[test]
+    fn resolver_hands_both_families_to_connect_race() {
+        let mut resolver = Resolver::new(1);
+        resolver.resolve(Token(3), "localhost");
+        let resolved = loop {
+            match resolver.try_recv() {
+                Some(resolved) => break resolved,
+                None => std::thread::sleep(Duration::from_millis(10)),
+            }
+        };
+        let ips = resolved.result.unwrap();
+        assert!(ips.iter().any(|ip| ip.is_ipv4()));
+        assert!(ips.iter().any(|ip| ip.is_ipv6()));
+
+        let poll = mio::Poll::new().unwrap();
+        let mut child_handler = domain_handler(1);
+        child_handler.take_lookup();
+        child_handler.resolved(Ok(ips)).unwrap();
+        let mut attempts = child_handler.register_connect_attempts(&poll);
+        child_handler.connect_timer(Instant::now() + Duration::from_secs(1)).unwrap();
+        attempts.extend(child_handler.register_connect_attempts(&poll));
+
+        assert_eq!(2, attempts.len());
+        assert_eq!(None, child_handler.connect_deadline());
+    }
+
+    #[test]
+    fn resolver_answers_from_cache() {
+        let mut resolver = Resolver::new(1);
+        resolver.resolve(Token(3), "localhost");
+        while resolver.try_recv().is_none() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        // a cached domain is answered without waiting for a worker
+        resolver.resolve(Token(5), "LocalHost");
+        let resolved = resolver.try_recv().unwrap();
+        assert_eq!(Token(5), resolved.token);
+        assert!(resolved.result.unwrap().iter().any(|ip| ip.is_loopback()));
+        assert_eq!(1, resolver.cache().hits());
+        assert_eq!(1, resolver.cache().misses());
+    }
+
+    #[test]
+    fn dns_cache_clamps_ttl() {
+        let mut cache = DnsCache::new(DnsCacheConfig::default());
+        let now = Instant::now();
+        let ips = vec!["10.0.0.1".parse().unwrap()];
+
+        cache.insert("short.test", ips.clone(), Some(Duration::from_secs(1)), now);
+        cache.insert("long.test", ips.clone(), Some(Duration::from_secs(86400)), now);
+        cache.insert("unknown.test", ips.clone(), None, now);
+
+        // min ttl keeps a 1 second record for 5 seconds
+        assert_eq!(Some(Some(ips.clone())), cache.get("short.test", now + Duration::from_secs(3)));
+        assert_eq!(None, cache.get("short.test", now + Duration::from_secs(5)));
+        assert_eq!(None, cache.get("long.test", now + Duration::from_secs(3600)));
+        assert_eq!(Some(Some(ips)), cache.get("unknown.test", now + Duration::from_secs(59)));
+        assert_eq!(None, cache.get("unknown.test", now + Duration::from_secs(60)));
+        assert_eq!(2, cache.hits());
+        assert_eq!(3, cache.misses());
+    }
+
+    #[test]
+    fn dns_cache_negative_entries() {
+        let mut cache = DnsCache::new(DnsCacheConfig::default());
+        let now = Instant::now();
+
+        cache.insert_negative("missing.test", Some(Duration::from_secs(3600)), now);
+        cache.insert_negative("gone.test", Some(Duration::from_secs(10)), now);
+
+        assert_eq!(Some(None), cache.get("missing.test", now + Duration::from_secs(29)));
+        assert_eq!(None, cache.get("missing.test", now + Duration::from_secs(30)));
+        assert_eq!(None, cache.get("gone.test", now + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn dns_cache_is_bounded() {
+        let config = DnsCacheConfig { capacity: 2, ..DnsCacheConfig::default() };
+        let mut cache = DnsCache::new(config);
+        let now = Instant::now();
+        let ips = vec!["10.0.0.1".parse().unwrap()];
+
+        cache.insert("a.test", ips.clone(), Some(Duration::from_secs(100)), now);
+        cache.insert("b.test", ips.clone(), Some(Duration::from_secs(10)), now);
+        cache.insert("c.test", ips.clone(), Some(Duration::from_secs(50)), now);
+
+        // the entry expiring first makes room
+        assert_eq!(2, cache.len());
+        assert!(cache.get("b.test", now).is_none());
+        assert!(cache.get("a.test", now).is_some());
+        assert!(cache.get("c.test", now).is_some());
+    }
+
+    /// writer taking `limit` bytes, then it would block
+    struct Blocking {
+        written: Vec<u8>,
+        limit: usize,
+    }
+
+    impl std::io::Write for Blocking {
+        fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+            let size = data.len().min(self.limit - self.written.len()).min(3);
+            if size == 0 {
+                return Err(std::io::Error::from(std::io::ErrorKind::WouldBlock));
+            }
+            self.written.extend_from_slice(&data[..size]);
+            Ok(size)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn buffer_consume_and_append() {
+        let mut buffer = Buffer::new();
+        buffer.extend_from_slice(b"hello ");
+        buffer.consume(2);
+        assert_eq!(b"llo ", buffer.as_slice());
+
+        let mut other = Buffer::new();
+        other.extend_from_slice(b"world");
+        buffer.append(&mut other);
+        assert_eq!(b"llo world", buffer.as_slice());
+        assert!(other.is_empty());
+
+        buffer.consume(100);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn buffer_compacts_consumed_space() {
+        let mut buffer = Buffer::new();
+        let data: Vec<u8> = (0..200 * 1024).map(|i| i as u8).collect();
+        buffer.extend_from_slice(&data);
+        buffer.consume(150 * 1024);
+        buffer.extend_from_slice(b"end");
+
+        assert_eq!(50 * 1024 + 3, buffer.len());
+        assert_eq!(&data[150 * 1024..], &buffer.as_slice()[..50 * 1024]);
+        assert_eq!(b"end", &buffer.as_slice()[50 * 1024..]);
+    }
+
+    #[test]
+    fn buffer_writes_until_would_block() {
+        let mut buffer = Buffer::new();
+        buffer.extend_from_slice(b"0123456789");
+        let mut writer = Blocking { written: Vec::new(), limit: 7 };
+
+        assert_eq!(7, buffer.write_to(&mut writer).unwrap());
+        assert_eq!(b"0123456", writer.written.as_slice());
+        assert_eq!(b"789", buffer.as_slice());
+    }
+
+    #[test]
+    fn handler_relays_in_bulk() {
+        let mut child_handler = ChildHandler::new_test(&Token(0));
+        child_handler.receive_data(b"request", false);
+        child_handler.move_to_proxy();
+
+        let mut writer = Blocking { written: Vec::new(), limit: 4 };
+        assert_eq!(Ok(4), child_handler.write_to_socket(&mut writer, true));
+        writer.limit = 100;
+        assert_eq!(Ok(3), child_handler.write_to_socket(&mut writer, true));
+        assert_eq!(b"request", writer.written.as_slice());
+    }
+
+    #[test]
+    fn buffer_limits_need_low_below_high() {
+        assert!(BufferLimits::new(8, 4).is_ok());
+        assert!(BufferLimits::new(8, 8).is_err());
+        assert!(BufferLimits::new(0, 0).is_err());
+    }
+
+    #[test]
+    fn handler_pauses_reading_above_high_water() {
+        let mut child_handler = ChildHandler::new_test(&Token(0));
+        child_handler.set_buffer_limits(BufferLimits::new(8, 4).unwrap());
+
+        child_handler.receive_data(b"0123456789", false);
+        child_handler.move_to_proxy();
+        assert!(!child_handler.accepts_data(false));
+        assert_eq!(Some(Ready::empty()), child_handler.interest_change(false));
+        assert_eq!(None, child_handler.interest_change(true));
+
+        // still paused between the marks
+        let mut writer = Blocking { written: Vec::new(), limit: 3 };
+        child_handler.write_to_socket(&mut writer, true).unwrap();
+        assert_eq!(None, child_handler.interest_change(false));
+        assert!(!child_handler.accepts_data(false));
+
+        writer.limit = 7;
+        child_handler.write_to_socket(&mut writer, true).unwrap();
+        assert_eq!(Some(Ready::readable()), child_handler.interest_change(false));
+        assert!(child_handler.accepts_data(false));
+
+        writer.limit = 10;
+        child_handler.write_to_socket(&mut writer, true).unwrap();
+        assert_eq!(Some(Ready::readable()), child_handler.interest_change(true));
+    }
+
+    #[test]
+    fn handler_rearms_reading_drained_while_paused() {
+        let mut child_handler = ChildHandler::new_test(&Token(0));
+        child_handler.set_buffer_limits(BufferLimits::new(8, 4).unwrap());
+        assert_eq!(Some(Ready::readable()), child_handler.interest_change(false));
+
+        // drained before interest was updated, socket still holds unread data
+        child_handler.receive_data(b"0123456789", false);
+        child_handler.move_to_proxy();
+        let mut writer = Blocking { written: Vec::new(), limit: 10 };
+        child_handler.write_to_socket(&mut writer, true).unwrap();
+
+        assert_eq!(Some(Ready::readable()), child_handler.interest_change(false));
+        assert!(child_handler.accepts_data(false));
+        assert_eq!(None, child_handler.interest_change(false));
+    }
+
+    #[test]
+    fn handler_passes_eof_after_flush() {
+        let mut child_handler = ChildHandler::new_test(&Token(0));
+        child_handler.receive_data(b"last words", false);
+        child_handler.move_to_proxy();
+        child_handler.receive_eof(false);
+
+        // data toward proxy is written first
+        assert!(!child_handler.take_shutdown(true));
+        let mut writer = Blocking { written: Vec::new(), limit: 100 };
+        child_handler.write_to_socket(&mut writer, true).unwrap();
+        assert!(child_handler.take_shutdown(true));
+        assert!(!child_handler.take_shutdown(true));
+
+        // the other direction is still open
+        assert!(!child_handler.take_shutdown(false));
+        assert!(!child_handler.is_finished());
+        assert_eq!(Some(Ready::empty()), child_handler.interest_change(false));
+
+        child_handler.receive_eof(true);
+        assert!(child_handler.take_shutdown(false));
+        assert!(child_handler.is_finished());
+    }
+
+    #[test]
+    fn timer_wheel_expires_in_order() {
+        let now = Instant::now();
+        let mut wheel = TimerWheel::new(Duration::from_millis(10), 4, now);
+        wheel.schedule(now + Duration::from_millis(25), 1);
+        wheel.schedule(now + Duration::from_millis(5), 2);
+        // more than one round away
+        wheel.schedule(now + Duration::from_millis(95), 3);
+        wheel.schedule(now - Duration::from_millis(5), 4);
+
+        assert_eq!(vec![4], wheel.expired(now));
+        assert_eq!(vec![2], wheel.expired(now + Duration::from_millis(10)));
+        assert!(wheel.expired(now + Duration::from_millis(24)).is_empty());
+        assert_eq!(vec![1], wheel.expired(now + Duration::from_millis(30)));
+        assert!(wheel.expired(now + Duration::from_millis(60)).is_empty());
+        assert_eq!(1, wheel.len());
+        assert_eq!(vec![3], wheel.expired(now + Duration::from_secs(10)));
+        assert!(wheel.is_empty());
+    }
+
+    fn short_timeouts() -> Timeouts {
+        Timeouts {
+            handshake: Duration::from_secs(1),
+            connect: Duration::from_secs(2),
+            idle: Duration::from_secs(3),
+            max_lifetime: Duration::from_secs(60),
+        }
+    }
+
+    #[test]
+    fn handshake_timeout_replies_failure() {
+        let mut child_handler = ChildHandler::new_test(&Token(0));
+        child_handler.set_timeouts(short_timeouts());
+        for byte in [5 as u8, 1, 0].iter() {
+            child_handler.receive_u8_data(*byte, false).unwrap();
+        }
+        child_handler.handle().unwrap();
+        child_handler.clear_send_buffer(false);
+
+        let now = Instant::now();
+        assert_eq!(None, child_handler.check_timeout(now));
+        assert_eq!(Some(Timeout::Handshake), child_handler.check_timeout(now + Duration::from_secs(2)));
+        assert!(child_handler.is_closing());
+        let reply = parse_dst_service_reply(child_handler.send_buffer()).unwrap().unwrap();
+        assert_eq!(&ReplyType::ServerFailure, reply.reply());
+    }
+
+    #[test]
+    fn connect_timeout_replies_ttl_expired() {
+        let mut child_handler = domain_handler(1);
+        child_handler.set_timeouts(short_timeouts());
+        assert!(child_handler.is_resolving());
+
+        let now = Instant::now();
+        assert_eq!(Some(Timeout::Connect), child_handler.check_timeout(now + Duration::from_secs(3)));
+        let reply = parse_dst_service_reply(child_handler.send_buffer()).unwrap().unwrap();
+        assert_eq!(&ReplyType::TTLExpired, reply.reply());
+    }
+
+    #[test]
+    fn silent_client_times_out_without_reply() {
+        let mut child_handler = ChildHandler::new_test(&Token(0));
+        child_handler.set_timeouts(short_timeouts());
+
+        let deadline = child_handler.deadline();
+        assert_eq!(Some(Timeout::Handshake), child_handler.check_timeout(deadline));
+        assert!(child_handler.send_buffer().is_empty());
+    }
+
+    #[test]
+    fn idle_timeout_follows_activity() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut child_handler = connect_handler(listener.local_addr().unwrap().port());
+        child_handler.set_timeouts(short_timeouts());
+        assert_eq!(ReplyType::Success, finish_connect(&mut child_handler));
+
+        let later = Instant::now() + Duration::from_secs(2);
+        child_handler.touch(later);
+        assert_eq!(later + Duration::from_secs(3), child_handler.deadline());
+        assert_eq!(None, child_handler.check_timeout(later + Duration::from_secs(2)));
+        assert_eq!(Some(Timeout::Idle), child_handler.check_timeout(later + Duration::from_secs(3)));
+    }
+
+    #[test]
+    fn server_connect_timeout_fires_before_handshake_timeout() {
+        use std::io::{Read, Write};
+
+        // connects to a listener whose backlog is full hang
+        let target = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let target_address = target.local_addr().unwrap();
+        let mut queued = Vec::new();
+        while let Ok(socket) = std::net::TcpStream::connect_timeout(&target_address, Duration::from_millis(100)) {
+            queued.push(socket);
+        }
+
+        let server = Server::builder()
+            .listen("127.0.0.1:0".parse().unwrap())
+            .timeouts(Timeouts {
+                handshake: Duration::from_secs(5),
+                connect: Duration::from_millis(300),
+                idle: Duration::from_secs(5),
+                max_lifetime: Duration::from_secs(60),
+            })
+            .build()
+            .unwrap();
+        let address = server.local_addrs()[0];
+        let shutdown = server.shutdown_handle();
+        let server_thread = std::thread::spawn(move || server.run());
+
+        let mut client = std::net::TcpStream::connect(address).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        client.write_all(&[5, 1, 0]).unwrap();
+        let mut reply = [0u8; 2];
+        client.read_exact(&mut reply).unwrap();
+
+        let port = target_address.port().to_be_bytes();
+        let requested = Instant::now();
+        client.write_all(&[5, 1, 0, 1, 127, 0, 0, 1, port[0], port[1]]).unwrap();
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).unwrap();
+
+        // ttl expired
+        assert_eq!(6, reply[1]);
+        assert!(requested.elapsed() < Duration::from_secs(2));
+        shutdown.shutdown();
+        assert_eq!(Ok(()), server_thread.join().unwrap());
+    }
+
+    #[test]
+    fn server_relays_until_shutdown() {
+        use std::io::{Read, Write};
+
+        let echo = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let echo_port = echo.local_addr().unwrap().port();
+        let echo_thread = std::thread::spawn(move || {
+            let (mut socket, _) = echo.accept().unwrap();
+            let mut data = [0u8; 5];
+            socket.read_exact(&mut data).unwrap();
+            socket.write_all(&data).unwrap();
+        });
+
+        let server = Server::builder()
+            .listen("127.0.0.1:0".parse().unwrap())
+            .build()
+            .unwrap();
+        let address = server.local_addrs()[0];
+        let shutdown = server.shutdown_handle();
+        let server_thread = std::thread::spawn(move || server.run());
+
+        let mut client = std::net::TcpStream::connect(address).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.write_all(&[5, 1, 0]).unwrap();
+        let mut reply = [0u8; 2];
+        client.read_exact(&mut reply).unwrap();
+        assert_eq!([5, 0], reply);
+
+        let port = echo_port.to_be_bytes();
+        client.write_all(&[5, 1, 0, 1, 127, 0, 0, 1, port[0], port[1]]).unwrap();
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).unwrap();
+        assert_eq!(0, reply[1]);
+
+        client.write_all(b"hello").unwrap();
+        let mut data = [0u8; 5];
+        client.read_exact(&mut data).unwrap();
+        assert_eq!(b"hello", &data);
+        echo_thread.join().unwrap();
+
+        shutdown.shutdown();
+        assert_eq!(Ok(()), server_thread.join().unwrap());
+    }
+
+    #[test]
+    fn server_counts_dns_cache_hits() {
+        use std::io::{Read, Write};
+
+        let target = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = target.local_addr().unwrap().port().to_be_bytes();
+        let server = Server::builder()
+            .listen("127.0.0.1:0".parse().unwrap())
+            .build()
+            .unwrap();
+        let address = server.local_addrs()[0];
+        let shutdown = server.shutdown_handle();
+        let stats = server.dns_stats();
+        let server_thread = std::thread::spawn(move || server.run());
+
+        for _ in 0..2 {
+            let mut client = std::net::TcpStream::connect(address).unwrap();
+            client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            client.write_all(&[5, 1, 0]).unwrap();
+            let mut reply = [0u8; 2];
+            client.read_exact(&mut reply).unwrap();
+
+            client.write_all(&[5, 1, 0, 3, 9]).unwrap();
+            client.write_all(b"localhost").unwrap();
+            client.write_all(&port).unwrap();
+            let mut reply = [0u8; 10];
+            client.read_exact(&mut reply).unwrap();
+            assert_eq!(0, reply[1]);
+        }
+
+        assert_eq!(1, stats.hits());
+        assert_eq!(1, stats.misses());
+        shutdown.shutdown();
+        assert_eq!(Ok(()), server_thread.join().unwrap());
+    }
+
+    #[test]
+    fn server_negotiates_by_listener_policy() {
+        use std::io::{Read, Write};
+
+        let server = Server::builder()
+            .listen("127.0.0.1:0".parse().unwrap())
+            .listen_with_policy("127.0.0.1:0".parse().unwrap(), AuthPolicy::new(vec![AuthType::NamePassword]))
+            .build()
+            .unwrap();
+        let addresses = server.local_addrs();
+        let shutdown = server.shutdown_handle();
+        let server_thread = std::thread::spawn(move || server.run());
+
+        let mut replies = Vec::new();
+        for address in addresses {
+            let mut client = std::net::TcpStream::connect(address).unwrap();
+            client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            client.write_all(&[5, 1, 0]).unwrap();
+            let mut reply = [0u8; 2];
+            client.read_exact(&mut reply).unwrap();
+            replies.push(reply);
+        }
+
+        assert_eq!(vec![[5, 0], [5, 0xff]], replies);
+        shutdown.shutdown();
+        assert_eq!(Ok(()), server_thread.join().unwrap());
+    }
+
+    #[test]
+    fn server_verifies_passwords_off_event_loop() {
+        use std::io::{Read, Write};
+
+        let target = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = target.local_addr().unwrap().port().to_be_bytes();
+        let mut users = MemoryAuthenticator::new();
+        users.add_user("user", "secret");
+        let server = Server::builder()
+            .listen("127.0.0.1:0".parse().unwrap())
+            .users(Arc::new(users))
+            .build()
+            .unwrap();
+        let address = server.local_addrs()[0];
+        let shutdown = server.shutdown_handle();
+        let server_thread = std::thread::spawn(move || server.run());
+
+        let mut replies = Vec::new();
+        for password in [&b"secret"[..], &b"wrong!"[..]].iter() {
+            let mut client = std::net::TcpStream::connect(address).unwrap();
+            client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            client.write_all(&[5, 1, 2]).unwrap();
+            let mut reply = [0u8; 2];
+            client.read_exact(&mut reply).unwrap();
+            assert_eq!([5, 2], reply);
+
+            // request is sent before the auth reply arrives
+            client.write_all(&[1, 4]).unwrap();
+            client.write_all(b"user").unwrap();
+            client.write_all(&[6]).unwrap();
+            client.write_all(password).unwrap();
+            client.write_all(&[5, 1, 0, 1, 127, 0, 0, 1, port[0], port[1]]).unwrap();
+            let mut reply = [0u8; 2];
+            client.read_exact(&mut reply).unwrap();
+            replies.push(reply);
+
+            // connect reply follows a successful login, a failed one is closed
+            let mut rest = [0u8; 10];
+            match reply[1] {
+                0 => {
+                    client.read_exact(&mut rest).unwrap();
+                    assert_eq!(0, rest[1]);
+                }
+                _ => assert_eq!(0, client.read(&mut rest).unwrap()),
+            }
+        }
+
+        assert_eq!(vec![[1, 0], [1, 1]], replies);
+        shutdown.shutdown();
+        assert_eq!(Ok(()), server_thread.join().unwrap());
+    }
+
+    #[test]
+    fn server_needs_listen_address() {
+        assert!(Server::builder().build().is_err());
+    }
+
+    fn register_connection(registry: &mut Registry, listener: &std::net::TcpListener) -> Token {
+        let socket = mio::net::TcpStream::connect(&listener.local_addr().unwrap()).unwrap();
+        let connection = registry.insert_with(|token| Connection::new(ChildHandler::new_test(&token), socket));
+        *connection.handler.get_token()
+    }
+
+    #[test]
+    fn registry_tokens_tell_sides_apart() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut registry = Registry::new();
+        let token = register_connection(&mut registry, &listener);
+        let upstream = side_token(token, Side::Upstream);
+
+        assert!(token.0 >= RESERVED_TOKENS);
+        assert_eq!(None, side_of(Token(1)));
+        assert_eq!(Some(Side::Client), side_of(token));
+        assert_eq!(Some(Side::Upstream), side_of(upstream));
+        assert_eq!(Some(Side::Attempt(3)), side_of(side_token(token, Side::Attempt(3))));
+        assert_eq!(token, side_token(upstream, Side::Client));
+
+        // every side finds the same entry
+        assert_eq!(Some(&token), registry.get(upstream).map(|connection| connection.handler.get_token()));
+        assert!(registry.get(Token(1)).is_none());
+    }
+
+    #[test]
+    fn registry_reuses_slots_with_new_generation() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut registry = Registry::new();
+        let first = register_connection(&mut registry, &listener);
+        assert_eq!(1, registry.len());
+
+        assert!(registry.remove(side_token(first, Side::Upstream)).is_some());
+        assert!(registry.remove(first).is_none());
+        assert!(registry.is_empty());
+
+        let second = register_connection(&mut registry, &listener);
+        assert_ne!(first, second);
+        assert!(registry.get(first).is_none());
+        assert!(registry.get_mut(second).is_some());
+
+        // slot is reused instead of growing
+        let third = register_connection(&mut registry, &listener);
+        assert!(first.0 ^ second.0 < RESERVED_TOKENS);
+        assert!(second.0 ^ third.0 >= RESERVED_TOKENS);
+    }
+
+    fn http_handler(request: &[u8], authenticator: Arc<dyn Authenticator>) -> ChildHandler {
+        let mut child_handler = ChildHandler::new(&Token(0), client(), authenticator);
+        child_handler.set_protocol(Protocol::Http);
+        child_handler.receive_data(request, false);
+        child_handler.handle().unwrap();
+        child_handler
+    }
+
+    fn wait_connected(child_handler: &mut ChildHandler) {
+        let poll = mio::Poll::new().unwrap();
+        let mut events = mio::Events::with_capacity(16);
+        while child_handler.is_connecting() {
+            child_handler.register_connect_attempts(&poll);
+            poll.poll(&mut events, Some(Duration::from_millis(10))).unwrap();
+            for event in events.iter() {
+                if child_handler.is_connecting() {
+                    child_handler.connect_attempt_ready(event.token()).unwrap();
+                }
+            }
+            child_handler.connect_timer(Instant::now()).unwrap();
+        }
+    }
+
+    #[test]
+    fn http_request_rewritten_to_origin_form() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let origin = listener.local_addr().unwrap();
+        let request = format!("GET http://{}/hello?x=1 HTTP/1.1\r\nHost: {}\r\n\
+                               Proxy-Connection: keep-alive\r\nAccept: */*\r\n\r\n", origin, origin);
+        let mut child_handler = http_handler(request.as_bytes(), Arc::new(AnonymousAuthenticator));
+
+        assert!(child_handler.is_connecting());
+        wait_connected(&mut child_handler);
+        child_handler.try_enable_forward();
+        child_handler.relay().unwrap();
+
+        // nothing is answered by the proxy itself
+        assert!(child_handler.send_buffer().is_empty());
+        let mut upstream = Vec::new();
+        child_handler.write_to_socket(&mut upstream, true).unwrap();
+        let expected = format!("GET /hello?x=1 HTTP/1.1\r\nHost: {}\r\nAccept: */*\r\n\r\n", origin);
+        assert_eq!(expected, String::from_utf8(upstream).unwrap());
+    }
+
+    #[test]
+    fn http_head_response_keeps_connection() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let origin = listener.local_addr().unwrap();
+        let request = format!("HEAD http://{}/ HTTP/1.1\r\n\r\n", origin);
+        let mut child_handler = http_handler(request.as_bytes(), Arc::new(AnonymousAuthenticator));
+        wait_connected(&mut child_handler);
+        child_handler.try_enable_forward();
+        child_handler.relay().unwrap();
+        child_handler.write_to_socket(&mut Vec::new(), true).unwrap();
+
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n";
+        child_handler.receive_data(response, true);
+        let next = format!("GET http://{}/next HTTP/1.1\r\n\r\n", origin);
+        child_handler.receive_data(next.as_bytes(), false);
+        child_handler.relay().unwrap();
+
+        // response ends with its head, next request goes to the same upstream
+        assert_eq!(&response[..], child_handler.send_buffer());
+        let mut upstream = Vec::new();
+        child_handler.write_to_socket(&mut upstream, true).unwrap();
+        assert!(String::from_utf8(upstream).unwrap().starts_with("GET /next HTTP/1.1\r\n"));
+    }
+
+    #[test]
+    fn http_request_to_domain_waits_for_resolving() {
+        let request = b"GET http://localhost:8080/ HTTP/1.1\r\nHost: localhost:8080\r\n\r\n";
+        let mut child_handler = http_handler(request, Arc::new(AnonymousAuthenticator));
+
+        assert!(child_handler.is_resolving());
+        assert_eq!(Some("localhost".to_string()), child_handler.take_lookup());
+    }
+
+    #[test]
+    fn http_request_needs_proxy_authorization() {
+        let mut users = MemoryAuthenticator::new();
+        users.add_user("user", "secret");
+        let users: Arc<dyn Authenticator> = Arc::new(users);
+
+        let request = b"GET http://127.0.0.1:8080/ HTTP/1.1\r\nHost: 127.0.0.1:8080\r\n\r\n";
+        let child_handler = http_handler(request, users.clone());
+        let reply = String::from_utf8_lossy(child_handler.send_buffer()).to_string();
+        assert!(reply.starts_with("HTTP/1.1 407 "));
+        assert!(reply.contains("Proxy-Authenticate: Basic"));
+        assert!(child_handler.is_closing());
+
+        // "user:secret"
+        let request = b"GET http://127.0.0.1:8080/ HTTP/1.1\r\nHost: 127.0.0.1:8080\r\n\
+                        Proxy-Authorization: Basic dXNlcjpzZWNyZXQ=\r\n\r\n";
+        let mut child_handler = http_handler(request, users);
+        assert!(child_handler.is_authenticating());
+        verify(&mut child_handler).unwrap();
+        assert!(child_handler.send_buffer().is_empty());
+        assert!(child_handler.is_connecting());
+        assert_eq!(Some(&Identity::User("user".to_string())), child_handler.identity());
+    }
+
+    #[test]
+    fn http_request_refuses_long_credentials() {
+        use base64ct::{Base64, Encoding};
+
+        let name = "a".repeat(256);
+        let mut users = MemoryAuthenticator::new();
+        users.add_user(&name, "secret");
+
+        let encoded = Base64::encode_string(format!("{}:secret", name).as_bytes());
+        let request = format!("GET http://127.0.0.1:8080/ HTTP/1.1\r\nHost: 127.0.0.1:8080\r\n\
+                               Proxy-Authorization: Basic {}\r\n\r\n", encoded);
+        let child_handler = http_handler(request.as_bytes(), Arc::new(users));
+        let reply = String::from_utf8_lossy(child_handler.send_buffer()).to_string();
+        assert!(reply.starts_with("HTTP/1.1 407 "));
+        assert!(child_handler.is_closing());
+        assert_eq!(None, child_handler.identity());
+    }
+
+    #[test]
+    fn http_request_malformed() {
+        let child_handler = http_handler(b"GET /relative HTTP/1.1\r\n\r\n", Arc::new(AnonymousAuthenticator));
+        let reply = String::from_utf8_lossy(child_handler.send_buffer()).to_string();
+
+        assert!(reply.starts_with("HTTP/1.1 400 "));
+        assert!(child_handler.is_closing());
+    }
+
+    #[test]
+    fn http_connect_becomes_tunnel() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let origin = listener.local_addr().unwrap();
+        let request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n\x16\x03\x01", origin, origin);
+        let mut child_handler = http_handler(request.as_bytes(), Arc::new(AnonymousAuthenticator));
+
+        assert!(child_handler.is_connecting());
+        wait_connected(&mut child_handler);
+        assert_eq!(b"HTTP/1.1 200 Connection established\r\n\r\n", child_handler.send_buffer());
+
+        // bytes after the head and later ones are not parsed as http
+        child_handler.try_enable_forward();
+        child_handler.receive_data(b"GET x\r\n\r\n", false);
+        child_handler.handle().unwrap();
+        child_handler.relay().unwrap();
+        let mut upstream = Vec::new();
+        child_handler.write_to_socket(&mut upstream, true).unwrap();
+        assert_eq!(b"\x16\x03\x01GET x\r\n\r\n", &upstream[..]);
+    }
+
+    #[test]
+    fn http_connect_refused() {
+        let request = format!("CONNECT {} HTTP/1.1\r\n\r\n", refused_address());
+        let mut child_handler = http_handler(request.as_bytes(), Arc::new(AnonymousAuthenticator));
+        wait_connected(&mut child_handler);
+
+        let reply = String::from_utf8_lossy(child_handler.send_buffer()).to_string();
+        assert!(reply.starts_with("HTTP/1.1 502 "));
+        assert!(child_handler.is_closing());
+
+        // authority-form needs a port
+        let child_handler = http_handler(b"CONNECT example.com HTTP/1.1\r\n\r\n", Arc::new(AnonymousAuthenticator));
+        assert!(String::from_utf8_lossy(child_handler.send_buffer()).starts_with("HTTP/1.1 400 "));
+    }
+
+    #[test]
+    fn detect_protocol_from_first_byte() {
+        assert_eq!(Ok(None), detect_protocol(&[]));
+        assert_eq!(Ok(Some(Protocol::Socks5)), detect_protocol(&[5, 1, 0]));
+        assert_eq!(Ok(Some(Protocol::Socks4)), detect_protocol(&[4, 1]));
+        assert_eq!(Ok(Some(Protocol::Http)), detect_protocol(b"GET http://a/ HTTP/1.1"));
+        assert!(detect_protocol(&[0x16, 3, 1]).is_err());
+    }
+
+    #[test]
+    fn handle_socks4a_connect() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port().to_be_bytes();
+        let mut child_handler = ChildHandler::new_test(&Token(0));
+        let request = [4 as u8, 1, port[0], port[1], 0, 0, 0, 1, b'm', b'e', 0];
+        child_handler.receive_data(&request, false);
+        child_handler.receive_data(b"localhost\0", false);
+        child_handler.handle().unwrap();
+
+        assert_eq!(Protocol::Socks4, child_handler.protocol());
+        assert_eq!(Some("localhost".to_string()), child_handler.take_lookup());
+        child_handler.resolved(Ok(vec!["127.0.0.1".parse().unwrap()])).unwrap();
+        wait_connected(&mut child_handler);
+
+        assert_eq!(&[0 as u8, 90], &child_handler.send_buffer()[..2]);
+        child_handler.try_enable_forward();
+        assert!(child_handler.forward_to_proxy());
+    }
+
+    #[test]
+    fn handle_socks4_refused_when_users_configured() {
+        let mut users = MemoryAuthenticator::new();
+        users.add_user("user", "secret");
+
+        let mut child_handler = ChildHandler::new(&Token(0), client(), Arc::new(users));
+        child_handler.receive_data(&[4, 1, 0, 80, 127, 0, 0, 1, 0], false);
+        child_handler.handle().unwrap();
+
+        assert_eq!(&[0 as u8, 91, 0, 0, 0, 0, 0, 0], child_handler.send_buffer());
+        assert!(child_handler.is_closing());
+    }
+
+    #[test]
+    fn server_relays_http_exchange() {
+        use std::io::{Read, Write};
+
+        let origin = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let origin_address = origin.local_addr().unwrap();
+        let origin_thread = std::thread::spawn(move || {
+            let (mut socket, _) = origin.accept().unwrap();
+            let mut request = Vec::new();
+            let mut data = [0u8; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let size = socket.read(&mut data).unwrap();
+                request.extend_from_slice(&data[..size]);
+            }
+            socket.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello").unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let server = Server::builder()
+            .listen("127.0.0.1:0".parse().unwrap())
+            .build()
+            .unwrap();
+        let address = server.local_addrs()[0];
+        let shutdown = server.shutdown_handle();
+        let server_thread = std::thread::spawn(move || server.run());
+
+        let mut client = std::net::TcpStream::connect(address).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        write!(client, "GET http://{}/hello HTTP/1.1\r\nHost: {}\r\n\r\n", origin_address, origin_address).unwrap();
+
+        let expected = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello";
+        let mut response = vec![0u8; expected.len()];
+        client.read_exact(&mut response).unwrap();
+        assert_eq!(&expected[..], &response[..]);
+        assert!(origin_thread.join().unwrap().starts_with("GET /hello HTTP/1.1\r\n"));
+
+        shutdown.shutdown();
+        assert_eq!(Ok(()), server_thread.join().unwrap());
+    }
+
+    #[test]
+    fn connect_error_reply_kinds() {
+        use std::io::{Error, ErrorKind};
+
+        assert_eq!(ReplyType::ConnectionRefuse, connect_error_reply(&Error::from(ErrorKind::ConnectionRefused)));
+        assert_eq!(ReplyType::NetWorkUnReachable, connect_error_reply(&Error::from(ErrorKind::NetworkUnreachable)));
+        assert_eq!(ReplyType::HostUnreachable, connect_error_reply(&Error::from(ErrorKind::HostUnreachable)));
+        assert_eq!(ReplyType::TTLExpired, connect_error_reply(&Error::from(ErrorKind::TimedOut)));
+        assert_eq!(ReplyType::ServerFailure, connect_error_reply(&Error::from(ErrorKind::Other)));
+    }
+
+    #[test]
+    fn handle_dst_request_with_other_version() {
+        let mut child_handler = ChildHandler::new_test(&Token(0));
+        child_handler.receive_u8_data(4, false).unwrap();
+
+        let result = child_handler.handle_dst_request();
+
+        assert!(result.is_err());
+    }
+
+    fn client() -> SocketAddr {
+        "10.0.0.1:50000".parse().unwrap()
+    }
+
+    fn auth_handler() -> ChildHandler {
+        let mut users = MemoryAuthenticator::new();
+        users.add_user("user", "secret");
+
+        let mut child_handler = ChildHandler::new(&Token(0), client(), Arc::new(users));
+        for byte in [5 as u8, 2, 0, 2].iter() {
+            child_handler.receive_u8_data(*byte, false).unwrap();
+        }
+        child_handler.handle().unwrap();
+        child_handler
+    }
+
+    /// check credentials the way the verifier threads do
+    fn verify(child_handler: &mut ChildHandler) -> Result<usize, String> {
+        let credentials = child_handler.take_verification().unwrap();
+        let identity = child_handler.authenticator()
+            .authenticate(&client(), &AuthType::NamePassword, Some(&credentials));
+        child_handler.verified(identity)
+    }
+
+    #[test]
+    fn handle_user_auth_success() {
+        let mut child_handler = auth_handler();
+        for byte in [1 as u8, 4, 117, 115, 101, 114, 6, 115, 101, 99, 114, 101, 116].iter() {
+            child_handler.receive_u8_data(*byte, false).unwrap();
+        }
+
+        assert_eq!(Ok(0), child_handler.handle());
+        assert!(child_handler.is_authenticating());
+        assert_eq!(None, child_handler.identity());
+        let size = verify(&mut child_handler);
+
+        assert_eq!(Ok(2), size);
+        assert!(child_handler.before_dst_request());
+        assert_eq!(Some(&Identity::User("user".to_string())), child_handler.identity());
+    }
+
+    #[test]
+    fn handle_user_auth_failed() {
+        let mut child_handler = auth_handler();
+        for byte in [1 as u8, 4, 117, 115, 101, 114, 3, 98, 97, 100].iter() {
+            child_handler.receive_u8_data(*byte, false).unwrap();
+        }
+
+        assert_eq!(Ok(0), child_handler.handle());
+        let size = verify(&mut child_handler);
+
+        assert_eq!(Ok(2), size);
+        assert!(child_handler.is_closing());
+        assert_eq!(None, child_handler.identity());
+    }
+
+    #[test]
+    fn handle_init_without_name_password_when_users_configured() {
+        let users = MemoryAuthenticator::new();
+        let mut child_handler = ChildHandler::new(&Token(0), client(), Arc::new(users));
+        for byte in [5 as u8, 1, 0].iter() {
+            child_handler.receive_u8_data(*byte, false).unwrap();
+        }
+
+        assert_eq!(Ok(2), child_handler.handle());
+        assert!(child_handler.is_closing());
+        assert_eq!(None, child_handler.identity());
+    }
+
+    #[test]
+    fn handle_init_replies_non_accept() {
+        let mut child_handler = ChildHandler::new_test(&Token(0));
+        for byte in [5 as u8, 1, 2].iter() {
+            child_handler.receive_u8_data(*byte, false).unwrap();
+        }
+
+        assert_eq!(Ok(2), child_handler.handle());
+        assert!(child_handler.is_closing());
+
+        assert_eq!(&[5, 0xff], child_handler.send_buffer());
+    }
+
+    fn lan_policy() -> AuthPolicy {
+        let mut policy = AuthPolicy::new(vec![AuthType::NamePassword]);
+        policy.add_rule("10.0.0.0/8".parse().unwrap(), vec![AuthType::Non]);
+        policy
+    }
+
+    #[test]
+    fn cidr_contains() {
+        let lan: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(lan.contains(&"10.1.2.3".parse().unwrap()));
+        assert!(lan.contains(&"::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!lan.contains(&"11.0.0.1".parse().unwrap()));
+        assert!(!lan.contains(&"::1".parse().unwrap()));
+
+        let net: Cidr = "192.168.1.128/25".parse().unwrap();
+        assert!(net.contains(&"192.168.1.200".parse().unwrap()));
+        assert!(!net.contains(&"192.168.1.100".parse().unwrap()));
+
+        let host: Cidr = "fd00::1".parse().unwrap();
+        assert!(host.contains(&"fd00::1".parse().unwrap()));
+        assert!(!host.contains(&"fd00::2".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("example.com/8".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn policy_selects_methods_by_client_network() {
+        let policy = lan_policy();
+        let offered = [AuthType::Non, AuthType::NamePassword];
+
+        assert_eq!(Some(AuthType::Non), policy.select(&"10.0.0.1".parse().unwrap(), &offered));
+        assert_eq!(Some(AuthType::NamePassword), policy.select(&"8.8.8.8".parse().unwrap(), &offered));
+        assert_eq!(None, policy.select(&"8.8.8.8".parse().unwrap(), &[AuthType::Non]));
+    }
+
+    #[test]
+    fn policy_authenticator_handles_init() {
+        let mut users = MemoryAuthenticator::new();
+        users.add_user("user", "secret");
+        let authenticator: Arc<dyn Authenticator> =
+            Arc::new(PolicyAuthenticator::new(lan_policy(), Arc::new(users)));
+
+        let mut lan = ChildHandler::new(&Token(0), client(), authenticator.clone());
+        let remote = "8.8.8.8:50000".parse().unwrap();
+        let mut wan = ChildHandler::new(&Token(1), remote, authenticator.clone());
+        for byte in [5 as u8, 1, 0].iter() {
+            lan.receive_u8_data(*byte, false).unwrap();
+            wan.receive_u8_data(*byte, false).unwrap();
+        }
+
+        assert_eq!(Ok(2), lan.handle());
+        assert!(lan.before_dst_request());
+        assert_eq!(Some(&Identity::Anonymous), lan.identity());
+
+        assert_eq!(Ok(2), wan.handle());
+        assert!(wan.is_closing());
+        assert_eq!(None, authenticator.authenticate(&remote, &AuthType::Non, None));
+    }
+
+    #[test]
+    fn policy_load_from_file() {
+        let path = std::env::temp_dir().join(format!("rsocks-policy-{}", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        std::fs::write(&path, "# lan\n10.0.0.0/8 none,password\n* password\n").unwrap();
+
+        let policy = AuthPolicy::load(&path).unwrap();
+        assert_eq!(&[AuthType::Non, AuthType::NamePassword][..]
+                   , policy.methods_for(&"10.0.0.1".parse().unwrap()));
+        assert_eq!(&[AuthType::NamePassword][..], policy.methods_for(&"8.8.8.8".parse().unwrap()));
+
+        // without defaults, clients outside the listed networks need a password
+        std::fs::write(&path, "10.0.0.0/8 none\n").unwrap();
+        let policy = AuthPolicy::load(&path).unwrap();
+        assert_eq!(&[AuthType::NamePassword][..], policy.methods_for(&"8.8.8.8".parse().unwrap()));
+
+        std::fs::write(&path, "* kerberos\n").unwrap();
+        assert!(AuthPolicy::load(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn handle_init_sets_anonymous_identity() {
+        let mut child_handler = ChildHandler::new_test(&Token(0));
+        for byte in [5 as u8, 2, 2, 0].iter() {
+            child_handler.receive_u8_data(*byte, false).unwrap();
+        }
+
+        assert_eq!(Ok(2), child_handler.handle());
+        assert!(child_handler.before_dst_request());
+        assert_eq!(Some(&Identity::Anonymous), child_handler.identity());
+    }
+
+    #[test]
+    fn memory_authenticator_select_and_verify() {
+        let mut users = MemoryAuthenticator::new();
+        users.add_user("user", "secret");
+
+        let offered = [AuthType::Non, AuthType::NamePassword];
+        assert_eq!(Some(AuthType::NamePassword), users.select_method(&client(), &offered));
+        assert_eq!(None, users.select_method(&client(), &[AuthType::Non]));
+
+        let good = UserPassAuthRequest::new(SubVersion::V1, "user".to_string(), "secret".to_string());
+        let bad = UserPassAuthRequest::new(SubVersion::V1, "user".to_string(), "guess".to_string());
+        assert_eq!(Some(Identity::User("user".to_string()))
+                   , users.authenticate(&client(), &AuthType::NamePassword, Some(&good)));
+        assert_eq!(None, users.authenticate(&client(), &AuthType::NamePassword, Some(&bad)));
+        assert_eq!(None, users.authenticate(&client(), &AuthType::Non, None));
+    }
+
+    #[test]
+    fn file_authenticator_load_and_reload() {
+        let path = std::env::temp_dir().join(format!("rsocks-users-{}", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+
+        let mut users = UsersFile::open(&path).unwrap();
+        users.add("user", "secret").unwrap();
+        users.save().unwrap();
+
+        let authenticator = FileAuthenticator::load(&path).unwrap();
+        let request = UserPassAuthRequest::new(SubVersion::V1, "other".to_string(), "pass".to_string());
+        assert_eq!(None, authenticator.authenticate(&client(), &AuthType::NamePassword, Some(&request)));
+
+        users.add("other", "pass").unwrap();
+        users.save().unwrap();
+        authenticator.reload().unwrap();
+        assert_eq!(Some(Identity::User("other".to_string()))
+                   , authenticator.authenticate(&client(), &AuthType::NamePassword, Some(&request)));
+
+        std::fs::write(&path, "broken line\n").unwrap();
+        assert!(authenticator.reload().is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn users_file_rejects_plaintext_password() {
+        let path = std::env::temp_dir().join(format!("rsocks-plain-{}", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        std::fs::write(&path, "# users\nuser:secret\n").unwrap();
+
+        assert!(UsersFile::load(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn users_file_add_remove_and_verify() {
+        let mut users = UsersFile::open("/nonexistent/rsocks-users").unwrap();
+        assert!(users.names().is_empty());
+
+        users.add("user", "secret").unwrap();
+        assert!(users.verify("user", "secret"));
+        assert!(!users.verify("user", "wrong"));
+        assert!(users.add("bad:name", "secret").is_err());
+        // would be read back as a comment or as two lines
+        assert!(users.add("#user", "secret").is_err());
+        assert!(users.add("us\ner", "secret").is_err());
+        assert!(users.add("us\rer", "secret").is_err());
+        assert!(!users.verify("#user", "secret"));
+        assert!(users.add("user", "").is_err());
+
+        assert!(users.remove("user"));
+        assert!(!users.remove("user"));
+        assert!(!users.verify("user", "secret"));
+    }
+
+    #[test]
+    fn hash_password_is_salted() {
+        let first = hash_password("secret").unwrap();
+        let second = hash_password("secret").unwrap();
+
+        assert_ne!(first, second);
+        assert!(first.starts_with("$argon2id$"));
+        assert!(verify_password(&first, "secret"));
+        assert!(!verify_password("secret", "secret"));
+    }
+
     #[test]
     fn set_token_success() {
         let mut child_handler = ChildHandler::new_test(&Token(0));
@@ -139,265 +1618,225 @@ mod unit_test {
     }
 
     #[test]
-    fn parse_http_headers_failure_data_not_enough() {
-        let data = [72 as u8, 111, 115, 116, 58, 32, 49, 50, 55, 46,
-            48, 46, 48, 46, 49, 58, 49, 48, 56, 57, 13, 10, 85, 115, 101, 114, 45, 65, 103, 101, 110,
-            116, 58, 32, 77, 111, 122, 105, 108, 108, 97, 47, 53, 46, 48, 32, 40, 77, 97, 99, 105, 110,
-            116, 111, 115, 104, 59, 32, 73, 110, 116, 101, 108, 32, 77, 97, 99, 32, 79, 83, 32, 88,
-            32, 49, 48, 46, 49, 52, 59, 32, 114, 118, 58, 55, 48, 46, 48, 41, 32, 71, 101, 99, 107,
-            111, 47, 50, 48, 49, 48, 48, 49, 48, 49, 32, 70, 105, 114, 101, 102, 111, 120, 47, 55,
-            48, 46, 48, 13, 10];
-
-
-        let headers = parse_http_headers(&data, &PacketType::Request);
-
-        match headers {
-            Err(e) => {
-                assert_eq!("data not enough when parse http headers", e);
-            }
+    fn parse_http_header_keeps_spaces_in_value() {
+        let data = String::from("User-Agent:  Mozilla/5.0 (X11; Linux x86_64) ");
 
-            _ => unreachable!()
-        }
+        assert_eq!(Ok(("User-Agent".to_string(), "Mozilla/5.0 (X11; Linux x86_64)".to_string())),
+                   parse_http_header(&data));
+        assert!(parse_http_header(&String::from("Bad Name: value")).is_err());
     }
 
     #[test]
-    fn parse_http_headers_fail_data_not_enough() {
-        let data = [72 as u8, 111, 115, 116, 58, 32, 49, 50, 55, 46,
-            48, 46, 48, 46, 49, 58, 49, 48, 56, 57, 13, 10, 85, 115, 101, 114, 45, 65, 103, 101, 110,
-            116, 58, 32, 77, 111, 122, 105, 108, 108, 97, 47, 53, 46, 48, 32, 40, 77, 97, 99, 105, 110,
-            116, 111, 115, 104, 59, 32, 73, 110, 116, 101, 108, 32, 77, 97, 99, 32, 79, 83, 32, 88,
-            32, 49, 48, 46, 49, 52, 59, 32, 114, 118, 58, 55, 48, 46, 48, 41, 32, 71, 101, 99, 107,
-            111, 47, 50, 48, 49, 48, 48, 49, 48, 49, 32, 70, 105, 114, 101, 102, 111, 120, 47, 55,
-            48, 46, 48, 13, 10];
-
-
-        let headers = parse_http_headers(&data, &PacketType::Request);
-
-        match headers {
-            Err(msg) => {
-                assert_eq!("data not enough when parse http headers", msg);
-            }
-
-            _ => unreachable!()
-        }
+    fn parse_line_bare_line_feed() {
+        assert_eq!(Ok(("".to_string(), 1)), http::parse_line(b"\n"));
+        assert_eq!(Ok(("GET".to_string(), 4)), http::parse_line(b"GET\n"));
     }
 
     #[test]
-    fn parse_http_headers_fail_header_format_error() {
-        let data = [72 as u8, 111, 115, 116, 59, 32, 49, 50, 55, 46,
-            48, 46, 48, 46, 49, 59, 49, 48, 56, 57, 13, 10, 85, 115, 101, 114, 45, 65, 103, 101, 110,
-            116, 58, 32, 77, 111, 122, 105, 108, 108, 97, 47, 53, 46, 48, 32, 40, 77, 97, 99, 105, 110,
-            116, 111, 115, 104, 59, 32, 73, 110, 116, 101, 108, 32, 77, 97, 99, 32, 79, 83, 32, 88,
-            32, 49, 48, 46, 49, 52, 59, 32, 114, 118, 58, 55, 48, 46, 48, 41, 32, 71, 101, 99, 107,
-            111, 47, 50, 48, 49, 48, 48, 49, 48, 49, 32, 70, 105, 114, 101, 102, 111, 120, 47, 55,
-            48, 46, 48, 13, 10, 13, 10];
-
-        let headers = parse_http_headers(&data, &PacketType::Request);
+    fn parse_chunk_size_success() {
+        let data = [49 as u8, 100, 102, 49];
+        let chunk_size = parse_chunk_size(&data);
 
-        match headers {
-            Err(msg) => {
-                assert_eq!("header formatter error.", msg);
-            }
 
-            _ => unreachable!()
-        }
+        assert_eq!(Ok(7665), chunk_size);
+        assert_eq!(Ok(0x1DF1), parse_chunk_size(b"1DF1"));
+        assert_eq!(Ok(16), parse_chunk_size(b"10 ;name=value"));
+        assert!(parse_chunk_size(b"").is_err());
+        assert!(parse_chunk_size(b"1g").is_err());
+        assert!(parse_chunk_size(b"10000000000000000").is_err());
     }
 
-    #[test]
-    fn parse_http_headers_success_with_others() {
-        let data = [72 as u8, 111, 115, 116, 58, 32, 49, 50, 55, 46,
-            48, 46, 48, 46, 49, 58, 49, 48, 56, 57, 13, 10, 85, 115, 101, 114, 45, 65, 103, 101, 110,
-            116, 58, 32, 77, 111, 122, 105, 108, 108, 97, 47, 53, 46, 48, 32, 40, 77, 97, 99, 105, 110,
-            116, 111, 115, 104, 59, 32, 73, 110, 116, 101, 108, 32, 77, 97, 99, 32, 79, 83, 32, 88,
-            32, 49, 48, 46, 49, 52, 59, 32, 114, 118, 58, 55, 48, 46, 48, 41, 32, 71, 101, 99, 107,
-            111, 47, 50, 48, 49, 48, 48, 49, 48, 49, 32, 70, 105, 114, 101, 102, 111, 120, 47, 55,
-            48, 46, 48, 13, 10, 13, 10];
-
-
-        let headers = parse_http_headers(&data, &PacketType::Request);
-
-        match headers {
-            Ok((transfer_type, offset)) => {
-                assert_eq!(HttpParseState::OtherRequest, transfer_type);
-                assert_eq!(120, offset);
+    /// events of `parser` over `data` given `step` bytes at a time, like partial reads
+    fn parse_in_steps(parser: &mut HttpParser, data: &[u8], step: usize) -> Vec<(HttpEvent, usize)> {
+        let mut events = Vec::new();
+        let mut received = 0;
+        let mut consumed = 0;
+        while consumed < data.len() || !parser.is_idle() {
+            match parser.parse(&data[consumed..received]).unwrap() {
+                Some((event, size)) => {
+                    consumed += size;
+                    let end = event == HttpEvent::End;
+                    events.push((event, size));
+                    if end && consumed == data.len() {
+                        break;
+                    }
+                }
+                None if received < data.len() => received = (received + step).min(data.len()),
+                None => break,
             }
-
-            Err(_) => unreachable!()
         }
+        events
     }
 
     #[test]
-    fn parse_http_headers_success_with_content_length() {
-        let data = [67 as u8, 111, 110, 116, 101, 110, 116, 45, 108, 101, 110, 103,
-            116, 104, 58, 32, 49, 48, 48, 48, 13, 10, 85, 115, 101, 114, 45, 65, 103, 101, 110,
-            116, 58, 32, 77, 111, 122, 105, 108, 108, 97, 47, 53, 46, 48, 32, 40, 77, 97, 99, 105, 110,
-            116, 111, 115, 104, 59, 32, 73, 110, 116, 101, 108, 32, 77, 97, 99, 32, 79, 83, 32, 88,
-            32, 49, 48, 46, 49, 52, 59, 32, 114, 118, 58, 55, 48, 46, 48, 41, 32, 71, 101, 99, 107,
-            111, 47, 50, 48, 49, 48, 48, 49, 48, 49, 32, 70, 105, 114, 101, 102, 111, 120, 47, 55,
-            48, 46, 48, 13, 10, 13, 10];
-
-
-        let headers = parse_http_headers(&data, &PacketType::Request);
-
-        match headers {
-            Ok((transfer_type, offset)) => {
-                assert_eq!(120, offset);
-                match transfer_type {
-                    HttpParseState::ContentLength(len) => assert_eq!(1000, len),
-                    _ => unreachable!()
-                }
-            }
-
-            _ => unreachable!()
+    fn http_parser_request_head() {
+        let data = b"POST /form?a=1 HTTP/1.1\r\nHost: example.com\r\nuser-agent: curl/8.0 (x86_64)\r\n\
+                     Accept: */*\r\nACCEPT: text/html\r\nContent-Length: 3\r\n\r\nabcGET / HTTP/1.0\r\n\r\n";
+        let mut parser = HttpParser::new(PacketType::Request);
+
+        let events = parse_in_steps(&mut parser, data, 7);
+        let request = match &events[0] {
+            (HttpEvent::Request(request), _) => request,
+            _ => unreachable!(),
+        };
+        assert_eq!("POST", request.method);
+        assert_eq!("/form?a=1", request.target);
+        assert_eq!(HttpVersion::Http11, request.version);
+        assert_eq!(Some("curl/8.0 (x86_64)"), request.headers.get("User-Agent"));
+        assert_eq!(vec!["*/*", "text/html"], request.headers.get_all("accept").collect::<Vec<_>>());
+        let names: Vec<&str> = request.headers.iter().map(|(name, _)| name).collect();
+        assert_eq!(vec!["Host", "user-agent", "Accept", "ACCEPT", "Content-Length"], names);
+
+        assert_eq!((HttpEvent::Body, 3), events[1]);
+        assert_eq!((HttpEvent::End, 0), events[2]);
+        match &events[3] {
+            (HttpEvent::Request(request), 18) => assert_eq!(HttpVersion::Http10, request.version),
+            _ => unreachable!(),
         }
+        assert_eq!((HttpEvent::End, 0), events[4]);
     }
 
     #[test]
-    fn parse_http_headers_success_with_transfer_encoding() {
-        let data = [67 as u8, 111, 110, 116, 101, 110, 116, 45, 108, 101, 110, 103,
-            116, 104, 58, 32, 49, 48, 48, 48, 13, 10, 85, 115, 101, 114, 45, 65, 103, 101, 110,
-            116, 58, 32, 77, 111, 122, 105, 108, 108, 97, 47, 53, 46, 48, 32, 40, 77, 97, 99, 105, 110,
-            116, 111, 115, 104, 59, 32, 73, 110, 116, 101, 108, 32, 77, 97, 99, 32, 79, 83, 32, 88,
-            32, 49, 48, 46, 49, 52, 59, 32, 114, 118, 58, 55, 48, 46, 48, 41, 32, 71, 101, 99, 107,
-            111, 47, 50, 48, 49, 48, 48, 49, 48, 49, 32, 70, 105, 114, 101, 102, 111, 120, 47, 55,
-            48, 46, 48, 13, 10, 84, 114, 97, 110, 115, 102, 101, 114, 45, 69, 110, 99, 111, 100, 105,
-            110, 103, 58, 32, 99, 104, 117, 110, 107, 101, 100, 13, 10, 13, 10];
-
-
-        let headers = parse_http_headers(&data, &PacketType::Request);
-
-        match headers {
-            Ok((transfer_type, offset)) => {
-                assert_eq!(148, offset);
-                assert_eq!(HttpParseState::TransferEncoding, transfer_type);
+    fn http_parser_chunked_response_with_trailers() {
+        let data = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip, chunked\r\n\r\n\
+                     A;ext=1\r\n0123456789\r\n0\r\nDigest: abc\r\n\r\n";
+        let mut parser = HttpParser::new(PacketType::Response);
+
+        let events = parse_in_steps(&mut parser, data, 1);
+        match &events[0] {
+            (HttpEvent::Response(response), _) => {
+                assert_eq!(200, response.status);
+                assert_eq!("OK", response.reason);
             }
-
-            _ => unreachable!()
+            _ => unreachable!(),
         }
-    }
-
-    #[test]
-    fn read_with_content_length_success() {
-        let data = [67 as u8, 111, 110, 116, 101, 110, 116, 45, 108, 101, 110, 103,
-            116, 104, 58, 32, 49, 48, 48, 48, 13, 10, 85, 115, 101, 114, 45, 65, 103, 101, 110,
-            116, 58, 32, 77, 111, 122, 105, 108, 108, 97, 47, 53, 46, 48, 32, 40, 77, 97, 99, 105, 110,
-            116, 111, 115, 104, 59, 32, 73, 110, 116, 101, 108, 32, 77, 97, 99, 32, 79, 83, 32, 88,
-            32, 49, 48, 46, 49, 52, 59, 32, 114, 118, 58, 55, 48, 46, 48, 41, 32, 71, 101, 99, 107,
-            111, 47, 50, 48, 49, 48, 48, 49, 48, 49, 32, 70, 105, 114, 101, 102, 111, 120, 47, 55,
-            48, 46, 48, 13, 10, 13, 10];
 
+        // body bytes cover chunk framing and trailer as received
+        let body: usize = events.iter().filter(|(event, _)| *event == HttpEvent::Body).map(|(_, size)| size).sum();
+        let head_len = data.windows(4).position(|window| window == b"\r\n\r\n").unwrap() + 4;
+        assert_eq!(data.len() - head_len, body);
 
-        let result = read_with_length(&data, 10);
-
-        match result {
-            Ok(HttpResult::End(offset)) => {
-                assert_eq!(10, offset);
-            }
-
-            _ => unreachable!()
-        }
+        let mut trailers = Headers::new();
+        trailers.append("Digest", "abc");
+        let tail: Vec<HttpEvent> = events.iter().rev().take(2).map(|(event, _)| event.clone()).collect();
+        assert_eq!(vec![HttpEvent::End, HttpEvent::Trailers(trailers)], tail);
+        assert!(parser.is_idle());
     }
 
     #[test]
-    fn read_with_length_failed() {
-        let data = [67 as u8, 111, 110, 116, 101, 110, 116, 45, 108, 101, 110, 103,
-            116, 104, 58, 32, 49, 48, 48, 48, 13, 10, 85, 115, 101, 114, 45, 65, 103, 101, 110,
-            116, 58, 32, 77, 111, 122, 105, 108, 108, 97, 47, 53, 46, 48, 32, 40, 77, 97, 99, 105, 110,
-            116, 111, 115, 104, 59, 32, 73, 110, 116, 101, 108, 32, 77, 97, 99, 32, 79, 83, 32, 88,
-            32, 49, 48, 46, 49, 52, 59, 32, 114, 118, 58, 55, 48, 46, 48, 41, 32, 71, 101, 99, 107,
-            111, 47, 50, 48, 49, 48, 48, 49, 48, 49, 32, 70, 105, 114, 101, 102, 111, 120, 47, 55,
-            48, 46, 48, 13, 10, 13, 10];
-
-
-        let result = read_with_length(&data, 150);
-
-        match result {
-            Ok(result) => {
-                assert_eq!(HttpResult::DataNotEnough, result);
-            }
-
-            _ => unreachable!()
+    fn http_parser_accepts_bare_line_feed() {
+        let data = b"POST / HTTP/1.1\nHost: example.com\r\nTransfer-Encoding: chunked\n\n\
+                     5\nhello\r\n3;ext\r\nabc\n0\n\n";
+        let mut parser = HttpParser::new(PacketType::Request);
+
+        let events = parse_in_steps(&mut parser, data, 3);
+        match &events[0] {
+            (HttpEvent::Request(request), 63) => assert_eq!(Some("example.com"), request.headers.get("host")),
+            _ => unreachable!(),
         }
+        let body: usize = events.iter().filter(|(event, _)| *event == HttpEvent::Body).map(|(_, size)| size).sum();
+        assert_eq!(data.len() - 63, body);
+        assert_eq!(HttpEvent::End, events.last().unwrap().0);
+        assert!(parser.is_idle());
     }
 
     #[test]
-    fn read_util_socket_closed_success() {
-        let data = [0 as u8, 12, 3, 4];
-        let result = read_util_close(&data, true);
+    fn http_parser_response_until_close() {
+        let mut parser = HttpParser::new(PacketType::Response);
+        let data = b"HTTP/1.0 200\r\nServer: test\r\n\r\nhello";
 
-        match result {
-            Ok(HttpResult::End(size)) => assert_eq!(4, size),
-            _ => unreachable!()
+        match parser.parse(data) {
+            Ok(Some((HttpEvent::Response(response), 30))) => assert_eq!("", response.reason),
+            _ => unreachable!(),
         }
+        assert_eq!(Ok(Some((HttpEvent::Body, 5))), parser.parse(&data[30..]));
+        assert_eq!(Ok(None), parser.parse(&[]));
+        assert_eq!(Ok(()), parser.eof());
+        assert_eq!(Ok(Some((HttpEvent::End, 0))), parser.parse(&[]));
+
+        // a body with a length is truncated by eof
+        let mut parser = HttpParser::new(PacketType::Response);
+        parser.parse(b"HTTP/1.1 200 OK\r\nContent-Length: 9\r\n\r\n").unwrap();
+        assert_eq!(Err(HttpError::Truncated), parser.eof());
     }
 
-    #[test]
-    fn read_util_socket_closed_failed() {
-        let data = [0 as u8, 12, 3, 4];
-        let result = read_util_close(&data, false);
-
-        match result {
-            Ok(result) => {
-                assert_eq!(HttpResult::DataNotEnough, result);
+    /// status and size of the body of each response in `data`
+    fn response_bodies(parser: &mut HttpParser, data: &[u8]) -> Vec<(u16, usize)> {
+        let mut result = Vec::new();
+        let mut consumed = 0;
+        while let Some((event, size)) = parser.parse(&data[consumed..]).unwrap() {
+            match event {
+                HttpEvent::Response(response) => result.push((response.status, 0)),
+                HttpEvent::Body => result.last_mut().unwrap().1 += size,
+                _ => {}
             }
-            _ => unreachable!()
+            consumed += size;
         }
+        result
     }
 
     #[test]
-    fn parse_chunk_size_success() {
-        let data = [49 as u8, 100, 102, 49];
-        let chunk_size = parse_chunk_size(&data);
-
-
-        assert_eq!(7665, chunk_size)
-    }
-
-    #[test]
-    fn parse_chunk_not_end() {
-        let data = [50 as u8, 51, 13, 10, 84, 104, 105, 115, 32, 105, 115
-            , 32, 116, 104, 101, 32, 100, 97, 116, 97, 32, 105, 110, 32, 116
-            , 104, 101, 32, 102, 105, 114, 115, 116, 32, 99, 104, 117, 110, 107, 13, 10];
-
-        let result = parse_chunk(&data);
-
-        match result {
-            Ok(Kind::Continue(end)) => {
-                assert_eq!(end, 41);
-            }
-            _ => unreachable!()
+    fn http_parser_response_body_rules() {
+        let mut parser = HttpParser::new(PacketType::Response);
+        for method in ["HEAD", "GET", "GET", "CONNECT", "GET"].iter() {
+            parser.expect_response(method);
         }
+
+        let data = b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n\
+                     HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 304 Not Modified\r\nContent-Length: 5\r\n\r\n\
+                     HTTP/1.1 204 No Content\r\nTransfer-Encoding: chunked\r\n\r\n\
+                     HTTP/1.1 200 Connection established\r\n\r\n\
+                     HTTP/1.1 404 Not Found\r\nContent-Length: 3\r\n\r\nabc";
+
+        assert_eq!(vec![(200, 0), (100, 0), (304, 0), (204, 0), (200, 0), (404, 3)], response_bodies(&mut parser, data));
+        assert!(!parser.expects_response());
+
+        // interim response keeps the request waiting for its final one
+        parser.expect_response("POST");
+        response_bodies(&mut parser, b"HTTP/1.1 103 Early Hints\r\nLink: </a>\r\n\r\n");
+        assert!(parser.expects_response());
+        response_bodies(&mut parser, b"HTTP/1.1 201 Created\r\nContent-Length: 0\r\n\r\n");
+        assert!(!parser.expects_response());
     }
 
     #[test]
-    fn parse_chunk_to_end_success() {
-        let data = [48 as u8, 13, 10, 13, 10];
-
-        let result = parse_chunk(&data);
-
-        match result {
-            Ok(Kind::End(end)) => {
-                assert_eq!(end, 5);
-            }
-            _ => unreachable!()
-        }
+    fn http_parser_content_length_conflicts_with_transfer_encoding() {
+        let request = b"POST / HTTP/1.1\r\nContent-Length: 4\r\nTransfer-Encoding: chunked\r\n\r\n";
+        let response = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nContent-Length: 4\r\n\r\n";
+
+        assert!(HttpParser::new(PacketType::Request).parse(request).is_err());
+        assert!(HttpParser::new(PacketType::Response).parse(response).is_err());
+        // no body is read for head whatever the headers say
+        let mut parser = HttpParser::new(PacketType::Response);
+        parser.expect_response("HEAD");
+        assert_eq!(vec![(200, 0)], response_bodies(&mut parser, response));
     }
 
     #[test]
-    fn parse_chunk_end_success() {
-       let data = [13 as u8, 10];
-       let result = parse_chunk_end(&data);
-
-        match result {
-            Ok(offset) => {
-                assert_eq!(2, offset);
+    fn http_parser_errors() {
+        let cases: [&[u8]; 7] = [
+            b"GET / HTTP/1.1\r\nContent-Length: 1x\r\n\r\n",
+            b"GET / HTTP/1.1\r\nContent-Length: 1, 2\r\n\r\n",
+            b"GET / HTTP/1.1\r\nA: b\r\n c\r\n\r\n",
+            b"GET / HTTP/1.1\r\n: empty\r\n\r\n",
+            b"GET / HTTP/2.0\r\n\r\n",
+            b"GET  / HTTP/1.1\r\n\r\n",
+            b"GET / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n",
+        ];
+        for data in cases.iter() {
+            match HttpParser::new(PacketType::Request).parse(data) {
+                Err(HttpError::Malformed(_)) => {}
+                other => panic!("{:?} should be malformed: {:?}", String::from_utf8_lossy(data), other),
             }
-
-            _ => unreachable!()
         }
-    }
 
-    fn get_end_of_chunks_success(){
+        let mut parser = HttpParser::new(PacketType::Response);
+        assert!(parser.parse(b"HTTP/1.1 20 OK\r\n\r\n").is_err());
+        let large = vec![b'a'; MAX_HEAD_SIZE];
+        assert_eq!(Err(HttpError::HeadTooLarge), parser.parse(&large));
 
+        let mut parser = HttpParser::new(PacketType::Response);
+        parser.parse(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n").unwrap();
+        assert!(parser.parse(b"zz\r\n").is_err());
     }
 }
\ No newline at end of file
@@ -0,0 +1,69 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use mio::{Registration, Token};
+use protocol::packet::{AuthType, UserPassAuthRequest};
+use crate::auth::{Authenticator, Identity};
+use crate::workers::WorkerPool;
+
+/// default number of password verifying threads
+pub const DEFAULT_VERIFIER_THREADS: usize = 2;
+
+struct Check {
+    token: Token,
+    authenticator: Arc<dyn Authenticator>,
+    client: SocketAddr,
+    credentials: UserPassAuthRequest,
+}
+
+/// result of a check, `token` is the one given to `Verifier::verify`
+pub struct Verified {
+    pub token: Token,
+    pub identity: Option<Identity>,
+}
+
+/// verify name/password credentials on a thread pool, a password hash takes
+/// long enough to stall every other connection of the event loop.
+///
+/// `registration` becomes readable when results are waiting, the event loop
+/// should then take all of them with `try_recv`.
+pub struct Verifier {
+    pool: WorkerPool<Check, Verified>,
+}
+
+impl Verifier {
+    pub fn new(threads: usize) -> Verifier {
+        Verifier {
+            pool: WorkerPool::new("verifier", threads, || verify),
+        }
+    }
+
+    pub fn registration(&self) -> &Registration {
+        self.pool.registration()
+    }
+
+    /// queue credentials of client, the identity is delivered with the same token
+    pub fn verify(&self, token: Token, authenticator: Arc<dyn Authenticator>, client: SocketAddr
+                  , credentials: UserPassAuthRequest) {
+        self.pool.submit(Check {
+            token,
+            authenticator,
+            client,
+            credentials,
+        });
+    }
+
+    /// next finished check, readiness is cleared once nothing is left
+    pub fn try_recv(&self) -> Option<Verified> {
+        self.pool.try_recv()
+    }
+}
+
+fn verify(check: Check) -> Verified {
+    let identity = check.authenticator.authenticate(
+        &check.client, &AuthType::NamePassword, Some(&check.credentials));
+
+    Verified {
+        token: check.token,
+        identity,
+    }
+}
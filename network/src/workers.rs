@@ -0,0 +1,105 @@
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use mio::{Ready, Registration, SetReadiness};
+
+/// run blocking jobs on a thread pool next to the event loop.
+///
+/// `registration` becomes readable when results are waiting, the event loop
+/// should then take all of them with `try_recv`. workers stop when the pool
+/// is dropped.
+pub struct WorkerPool<J, R> {
+    jobs: Option<Sender<J>>,
+    results: Receiver<R>,
+    registration: Registration,
+    readiness: SetReadiness,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl<J: Send + 'static, R: Send + 'static> WorkerPool<J, R> {
+    /// threads are named `name-index`, `init` runs once on each of them and
+    /// returns the function doing its jobs
+    pub fn new<I, F>(name: &str, threads: usize, init: I) -> WorkerPool<J, R>
+        where I: Fn() -> F + Send + Sync + 'static, F: FnMut(J) -> R {
+        let (jobs, queue) = channel::<J>();
+        let (sender, results) = channel::<R>();
+        let (registration, readiness) = Registration::new2();
+        let queue = Arc::new(Mutex::new(queue));
+        let init = Arc::new(init);
+
+        let workers = (0..threads.max(1)).map(|index| {
+            let queue = queue.clone();
+            let sender = sender.clone();
+            let readiness = readiness.clone();
+            let init = init.clone();
+            thread::Builder::new()
+                .name(format!("{}-{}", name, index))
+                .spawn(move || work_loop(queue, sender, readiness, init()))
+                .unwrap_or_else(|e| panic!("spawn {} thread failed: {}", name, e))
+        }).collect();
+
+        WorkerPool {
+            jobs: Some(jobs),
+            results,
+            registration,
+            readiness,
+            workers,
+        }
+    }
+
+    pub fn registration(&self) -> &Registration {
+        &self.registration
+    }
+
+    /// queue a job, its result is delivered by `try_recv`
+    pub fn submit(&self, job: J) {
+        if let Some(jobs) = &self.jobs {
+            // workers only stop when pool is dropped
+            let _ = jobs.send(job);
+        }
+    }
+
+    /// make registration readable, for results the owner has without a worker
+    pub fn wake(&self) {
+        let _ = self.readiness.set_readiness(Ready::readable());
+    }
+
+    /// next finished job, readiness is cleared once nothing is left
+    pub fn try_recv(&self) -> Option<R> {
+        match self.results.try_recv() {
+            Ok(result) => Some(result),
+            Err(_) => {
+                let _ = self.readiness.set_readiness(Ready::empty());
+                // a result may arrive between the check and clearing readiness
+                self.results.try_recv().ok()
+            }
+        }
+    }
+}
+
+impl<J, R> Drop for WorkerPool<J, R> {
+    fn drop(&mut self) {
+        self.jobs.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn work_loop<J, R, F: FnMut(J) -> R>(queue: Arc<Mutex<Receiver<J>>>, results: Sender<R>
+                                      , readiness: SetReadiness, mut work: F) {
+    loop {
+        let job = match queue.lock() {
+            Ok(queue) => match queue.recv() {
+                Ok(job) => job,
+                Err(_) => return,
+            },
+            Err(_) => return,
+        };
+
+        if results.send(work(job)).is_err() {
+            return;
+        }
+        let _ = readiness.set_readiness(Ready::readable());
+    }
+}
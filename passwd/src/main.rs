@@ -0,0 +1,77 @@
+extern crate network;
+
+use network::auth::UsersFile;
+use std::io::{stdin, BufRead};
+use std::process::exit;
+
+/// manage users of the name/password auth-method
+///
+///     rsocks-passwd <users-file> add <name>     password is read from stdin
+///     rsocks-passwd <users-file> remove <name>
+///     rsocks-passwd <users-file> list
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 3 {
+        usage();
+    }
+
+    let path = &args[1];
+    let result = match (args[2].as_str(), args.get(3)) {
+        ("add", Some(name)) if args.len() == 4 => add(path, name),
+        ("remove", Some(name)) if args.len() == 4 => remove(path, name),
+        ("list", None) => list(path),
+        _ => usage(),
+    };
+
+    if let Err(msg) = result {
+        eprintln!("{}", msg);
+        exit(1);
+    }
+}
+
+fn add(path: &str, name: &str) -> Result<(), String> {
+    let mut users = UsersFile::open(path)?;
+    let password = read_password()?;
+
+    users.add(name, &password)?;
+    users.save()?;
+
+    println!("user {} saved.", name);
+    Ok(())
+}
+
+fn remove(path: &str, name: &str) -> Result<(), String> {
+    let mut users = UsersFile::load(path)?;
+    if !users.remove(name) {
+        return Err(format!("user {} does not exist.", name));
+    }
+
+    users.save()?;
+
+    println!("user {} removed.", name);
+    Ok(())
+}
+
+fn list(path: &str) -> Result<(), String> {
+    let users = UsersFile::load(path)?;
+    for name in users.names() {
+        println!("{}", name);
+    }
+
+    Ok(())
+}
+
+fn read_password() -> Result<String, String> {
+    eprintln!("password:");
+
+    let mut line = String::new();
+    match stdin().lock().read_line(&mut line) {
+        Ok(_) => Ok(line.trim_end_matches(['\r', '\n']).to_string()),
+        Err(e) => Err(format!("read password failed: {}", e)),
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("usage: rsocks-passwd <users-file> add <name> | remove <name> | list");
+    exit(2);
+}
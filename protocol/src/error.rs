@@ -0,0 +1,64 @@
+use std::error::Error;
+use std::fmt;
+use crate::packet::ReplyType;
+
+/// error raised when parsing or encoding socks5 packets
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProtocolError {
+    /// packet ends before the field at this offset
+    Truncated(usize),
+    /// field at this offset has an invalid value
+    Malformed(usize, &'static str),
+    /// a field which must be present is empty
+    MissingField(&'static str),
+    UnsupportedVersion(u8),
+    UnsupportedCommand(u8),
+    UnsupportedAddressType(u8),
+    UnsupportedReplyType(u8),
+    /// value has no representation on the wire
+    Unencodable(&'static str),
+    /// textual address can not be parsed
+    InvalidAddress(String),
+}
+
+impl ProtocolError {
+    /// reply which should be sent back to client for this error
+    pub fn reply_type(&self) -> ReplyType {
+        match self {
+            ProtocolError::UnsupportedCommand(_) => ReplyType::CmdNotSupport,
+            ProtocolError::UnsupportedAddressType(_) => ReplyType::AddressTypeNotSupport,
+            _ => ReplyType::ServerFailure,
+        }
+    }
+
+    /// move offsets of an error raised on a sub slice starting at `base`
+    pub fn offset_by(self, base: usize) -> ProtocolError {
+        match self {
+            ProtocolError::Truncated(offset) => ProtocolError::Truncated(base + offset),
+            ProtocolError::Malformed(offset, reason) => ProtocolError::Malformed(base + offset, reason),
+            other => other,
+        }
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProtocolError::Truncated(offset) => write!(f, "packet truncated at offset {}", offset),
+            ProtocolError::Malformed(offset, reason) =>
+                write!(f, "malformed packet at offset {}: {}", offset, reason),
+            ProtocolError::MissingField(field) => write!(f, "missing field: {}", field),
+            ProtocolError::UnsupportedVersion(version) =>
+                write!(f, "unsupported version: {:#04x}", version),
+            ProtocolError::UnsupportedCommand(cmd) => write!(f, "unsupported command: {:#04x}", cmd),
+            ProtocolError::UnsupportedAddressType(addr_type) =>
+                write!(f, "unsupported address type: {:#04x}", addr_type),
+            ProtocolError::UnsupportedReplyType(reply) =>
+                write!(f, "unsupported reply type: {:#04x}", reply),
+            ProtocolError::Unencodable(what) => write!(f, "can not encode {}", what),
+            ProtocolError::InvalidAddress(address) => write!(f, "invalid address: {}", address),
+        }
+    }
+}
+
+impl Error for ProtocolError {}
@@ -1,4 +1,7 @@
 pub mod packet;
+pub mod error;
+pub mod reassembly;
 mod test;
+#[cfg(test)]
 mod unit_test;
 
@@ -5,6 +5,10 @@ use crate::packet::AuthType::*;
 use crate::packet::SubVersion::V0;
 use std::borrow::Borrow;
 use std::ops::BitAnd;
+use std::net::{SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use std::fmt;
+use crate::error::ProtocolError;
 
 /// this packet is for authentication method
 /// selecting request when client finishes connecting.
@@ -15,7 +19,7 @@ pub struct AuthSelectRequest {
     methods: Vec<AuthType>,
 }
 
-pub fn parse_auth_select_request_packet(data: &[u8]) -> Result<Option<AuthSelectRequest>, String> {
+pub fn parse_auth_select_request_packet(data: &[u8]) -> Result<Option<AuthSelectRequest>, ProtocolError> {
     let len = data.len();
     if len < 2 {
         return Ok(None);
@@ -25,11 +29,11 @@ pub fn parse_auth_select_request_packet(data: &[u8]) -> Result<Option<AuthSelect
     let mut version = parse_version(data.get(0).cloned())?;
 
     // num of methods
-    let n_methods = data.get(1).cloned().unwrap();
+    let n_methods = data[1];
     let num = n_methods;
 
     // verify data len
-    let total: usize = usize::from(2 + n_methods);
+    let total: usize = 2 + usize::from(n_methods);
     if data.len() < total {
         return Ok(None);
     }
@@ -37,7 +41,7 @@ pub fn parse_auth_select_request_packet(data: &[u8]) -> Result<Option<AuthSelect
     let mut i = 0;
     let mut methods = Vec::<AuthType>::new();
     while i < num {
-        let index = usize::from(2 + i);
+        let index = 2 + usize::from(i);
         let method = parse_auth_type(data.get(index).cloned())?;
 
         methods.push(method);
@@ -53,7 +57,7 @@ pub fn parse_auth_select_request_packet(data: &[u8]) -> Result<Option<AuthSelect
     Ok(Some(result))
 }
 
-pub fn encode_auth_select_request(request: AuthSelectRequest) -> Result<Vec<u8>, &'static str> {
+pub fn encode_auth_select_request(request: AuthSelectRequest) -> Result<Vec<u8>, ProtocolError> {
     let mut data = Vec::<u8>::new();
 
     let version_num = encode_version(&request.version)?;
@@ -101,7 +105,7 @@ pub struct AuthSelectReply {
     method: AuthType,
 }
 
-pub fn parse_auth_select_reply_packet(data: &[u8]) -> Result<Option<AuthSelectReply>, &'static str> {
+pub fn parse_auth_select_reply_packet(data: &[u8]) -> Result<Option<AuthSelectReply>, ProtocolError> {
     let len = data.len();
     if len != 2 {
         return Ok(None);
@@ -118,7 +122,7 @@ pub fn parse_auth_select_reply_packet(data: &[u8]) -> Result<Option<AuthSelectRe
     Ok(Some(result))
 }
 
-pub fn encode_auth_select_reply(reply: &AuthSelectReply) -> Result<Vec<u8>, &'static str> {
+pub fn encode_auth_select_reply(reply: &AuthSelectReply) -> Result<Vec<u8>, ProtocolError> {
     let version_num = encode_version(reply.version())?;
     let auth_num = encode_auth_type(reply.auth_type())?;
 
@@ -151,12 +155,11 @@ pub struct DstServiceRequest {
     version: Version,
     cmd: CmdType,
     reserve: u8,
-    address_type: AddressType,
-    address: String,
-    port: u16,
+    address: TargetAddr,
 }
 
-pub fn parse_dst_service_request(data: &[u8]) -> Result<Option<(DstServiceRequest, u8)>, &str> {
+/// parse request and return it with the number of bytes consumed
+pub fn parse_dst_service_request(data: &[u8]) -> Result<Option<(DstServiceRequest, usize)>, ProtocolError> {
     let len = data.len();
     if len < 4 {
         return Ok(None);
@@ -164,143 +167,45 @@ pub fn parse_dst_service_request(data: &[u8]) -> Result<Option<(DstServiceReques
 
     let version = parse_version(data.get(0).cloned())?;
     let cmd = parse_cmd(data.get(1).cloned())?;
-    let reserve = match data.get(2).cloned() {
-        Some(num) => num,
-        None => 0
-    };
+    let reserve = data[2];
 
     let address_type = parse_address_type(data.get(3).cloned())?;
-    let (address, address_len) = match parse_dst_address(&data[4..data.len()],
-                                                         &address_type)? {
+    let (address, address_len) = match parse_target_addr(&data[4..], &address_type)
+        .map_err(|e| e.offset_by(4))? {
         Some(result) => result,
         None => return Ok(None)
     };
-    let len: usize = usize::from(address_len);
-
-    if data.len() < len + 6 {
-        return Ok(None);
-    }
 
-    let port = get_port(data.get(4 + len..6 + len).unwrap())?;
     let result = DstServiceRequest {
         version,
         cmd,
         reserve,
-        address_type,
         address,
-        port,
     };
 
-    Ok(Some((result, address_len)))
-}
-
-pub fn parse_dst_address(data: &[u8], addr_type: &AddressType) -> Result<Option<(String, u8)>, &'static str> {
-    let len = data.len();
-    match addr_type {
-        Ipv4 => {
-            if len < 4 {
-                return Ok(None);
-            }
-            let address = get_ipv4_from_bytes(data.get(0..4).unwrap())?;
-            //let port: u16 = get_port(data.get(4..6).unwrap())?;
-            Ok((Some((address, 4))))
-        }
-        Ipv6 => {
-            if len < 16 {
-                return Ok(None);
-            }
-            let address = get_ipv6_from_bytes(data.get(0..16).unwrap())?;
-            //let port = get_port(data.get(16..18).unwrap())?;
-            //let port: u16 = (data[17] as u16 | (data[18] as u16) << 8);
-            Ok(Some((address, 16)))
-        }
-        Domain => {
-            let addr_len = usize::from(data.get(0).cloned().unwrap());
-            if len < addr_len {
-                return Ok(None);
-            }
-            let byte_array = data.get(1..addr_len + 1).unwrap();
-            let address = get_domain_from_bytes(byte_array)?;
-            //let port = get_port(data.get(addr_len + 1..addr_len + 3).unwrap())?;
-            // let port: u16 = (data[addr_len + 1] as u16 | (data[addr_len + 2] as u16) << 8);
-            let len = addr_len + 1;
-            Ok(Some((address, len as u8)))
-        }
-    }
-}
-
-pub fn get_domain_from_bytes(bytes: &[u8]) -> Result<String, &'static str> {
-    parse_string_from_bytes(bytes)
+    Ok(Some((result, 4 + address_len)))
 }
 
-pub fn get_ipv4_from_bytes(bytes: &[u8]) -> Result<String, &'static str> {
-    let first = bytes[0].to_string();
-    let second = bytes[1].to_string();
-    let third = bytes[2].to_string();
-    let forth = bytes[3].to_string();
-
-    let mut result = String::new();
-    result.push_str(first.as_str());
-    result.push('.');
-    result.push_str(second.as_str());
-    result.push('.');
-    result.push_str(third.as_str());
-    result.push('.');
-    result.push_str(forth.as_str());
-
-    Ok(result)
-}
-
-pub fn get_ipv6_from_bytes(bytes: &[u8]) -> Result<String, &'static str> {
-    // todo parse bytes to ipv6
-    Ok(String::new())
-}
-
-pub fn get_port(bytes: &[u8]) -> Result<u16, &'static str> {
-    let len = bytes.len();
-    let high = bytes[0].clone();
-    let low = bytes[1].clone();
-
-    let port = (low as u16 | (high as u16) << 8);
-
-    Ok(port)
-}
-
-pub fn encode_dst_service_request(request: DstServiceRequest) -> Result<Vec<u8>, &'static str> {
+pub fn encode_dst_service_request(request: DstServiceRequest) -> Result<Vec<u8>, ProtocolError> {
     let mut data = Vec::<u8>::new();
     let version = encode_version(&request.version)?;
     let cmd_type = encode_cmd(&request.cmd)?;
-    let reserve = request.reserve;
-    let address_type = encode_address_type(&request.address_type)?;
-    // ipv4
-    let mut address = encode_address_with_type(request.address, &request.address_type)?;
 
     data.push(version);
     data.push(cmd_type);
     data.push(0);
-    data.push(address_type);
-
-    data.append(&mut address);
-
-    let port = request.port;
-    let low_bit = port.bitand(0x00FF) as u8;
-    let high_bit = (port >> 8) as u8;
-    data.push(high_bit);
-    data.push(low_bit);
+    encode_target_addr(&request.address, &mut data)?;
 
     Ok(data)
 }
 
 impl DstServiceRequest {
-    pub fn new(version: Version, cmd: CmdType, reserve: u8, address_type: AddressType
-               , address: String, port: u16) -> DstServiceRequest {
+    pub fn new(version: Version, cmd: CmdType, reserve: u8, address: TargetAddr) -> DstServiceRequest {
         DstServiceRequest {
             version,
             cmd,
             reserve,
-            address_type,
             address,
-            port,
         }
     }
 
@@ -312,16 +217,16 @@ impl DstServiceRequest {
         &self.cmd
     }
 
-    pub fn address_type(&self) -> &AddressType {
-        &self.address_type
+    pub fn address_type(&self) -> AddressType {
+        self.address.address_type()
     }
 
-    pub fn address(&self) -> String {
-        self.address.to_string()
+    pub fn address(&self) -> &TargetAddr {
+        &self.address
     }
 
     pub fn port(&self) -> u16 {
-        self.port
+        self.address.port()
     }
 }
 
@@ -330,12 +235,10 @@ pub struct DstServiceReply {
     version: Version,
     reply: ReplyType,
     reserve: u8,
-    address_type: AddressType,
-    address: String,
-    port: u16,
+    address: TargetAddr,
 }
 
-pub fn parse_dst_service_reply(data: &[u8]) -> Result<Option<DstServiceReply>, &'static str> {
+pub fn parse_dst_service_reply(data: &[u8]) -> Result<Option<DstServiceReply>, ProtocolError> {
     let len = data.len();
     if len < 4 {
         return Ok(None);
@@ -343,107 +246,368 @@ pub fn parse_dst_service_reply(data: &[u8]) -> Result<Option<DstServiceReply>, &
 
     let version = parse_version(data.get(0).cloned())?;
     let reply = parse_reply_type(data.get(1).cloned())?;
-    let reserve = match data.get(2).cloned() {
-        Some(num) => num,
-        None => 0
-    };
+    let reserve = data[2];
 
     let address_type = parse_address_type(data.get(3).cloned())?;
-    let (address, address_len) = match parse_dst_address(&data[4..data.len()],
-                                                         &address_type)? {
+    let (address, _) = match parse_target_addr(&data[4..], &address_type)
+        .map_err(|e| e.offset_by(4))? {
         Some(result) => result,
         None => return Ok(None)
     };
 
-    let len: usize = usize::from(address_len);
-    let port = get_port(data.get(4 + len..6 + len).unwrap())?;
     let result = DstServiceReply {
         version,
         reply,
         reserve,
-        address_type,
         address,
-        port,
     };
 
     Ok(Some(result))
 }
 
-pub fn encode_dst_service_reply(dst_reply: DstServiceReply) -> Result<Vec<u8>, &'static str> {
+pub fn encode_dst_service_reply(dst_reply: DstServiceReply) -> Result<Vec<u8>, ProtocolError> {
     let mut data = Vec::<u8>::new();
     let version = encode_version(&dst_reply.version)?;
     let reply = encode_reply_type(&dst_reply.reply)?;
 
-    let address_type = encode_address_type(&dst_reply.address_type)?;
-    let mut address =
-        encode_address_with_type(dst_reply.address, &dst_reply.address_type)?;
-
     data.push(version);
     data.push(reply);
     data.push(0);
-    data.push(address_type);
+    encode_target_addr(&dst_reply.address, &mut data)?;
 
-    if dst_reply.address_type == AddressType::Domain {
-        let address_len = address.len() as u8;
+    Ok(data)
+}
 
-        data.push(address_len);
+impl DstServiceReply {
+    pub fn new(version: Version, reply: ReplyType, address: TargetAddr) -> DstServiceReply {
+        DstServiceReply {
+            version,
+            reply,
+            reserve: 0,
+            address,
+        }
+    }
+
+    pub fn version(&self) -> &Version {
+        &self.version
     }
-    data.append(&mut address);
 
-    let port = dst_reply.port;
-    let low_bit = port.bitand(0x00FF) as u8;
-    let high_bit = (port >> 8) as u8;
-    data.push(high_bit);
-    data.push(low_bit);
+    pub fn reply(&self) -> &ReplyType {
+        &self.reply
+    }
+
+    pub fn address_type(&self) -> AddressType {
+        self.address.address_type()
+    }
+
+    pub fn address(&self) -> &TargetAddr {
+        &self.address
+    }
+
+    pub fn port(&self) -> u16 {
+        self.address.port()
+    }
+}
+
+/// longest user id or domain of a socks4 request, both end with a null byte
+const SOCKS4_MAX_STRING: usize = 255;
+
+/// request of a socks4 client, socks4a sends a domain after the user id
+/// and puts 0.0.0.x with x != 0 into the address field
+pub struct Socks4Request {
+    cmd: CmdType,
+    address: TargetAddr,
+    user_id: String,
+}
+
+/// parse request and return it with the number of bytes consumed
+pub fn parse_socks4_request(data: &[u8]) -> Result<Option<(Socks4Request, usize)>, ProtocolError> {
+    if data.len() < 8 {
+        return Ok(None);
+    }
+
+    match data[0] {
+        4 => {}
+        other => return Err(ProtocolError::UnsupportedVersion(other)),
+    }
+    let cmd = match data[1] {
+        1 => Connect,
+        2 => Bind,
+        other => return Err(ProtocolError::UnsupportedCommand(other)),
+    };
+    let port = get_port(&data[2..4])?;
+    let ip = get_ipv4_from_bytes(&data[4..8])?;
+
+    let (user_id, user_id_len) = match parse_null_terminated(&data[8..]).map_err(|e| e.offset_by(8))? {
+        Some(result) => result,
+        None => return Ok(None),
+    };
+    let mut len = 8 + user_id_len;
+
+    let octets = ip.octets();
+    let address = match octets[..3] == [0, 0, 0] && octets[3] != 0 {
+        true => {
+            let (domain, domain_len) = match parse_null_terminated(&data[len..]).map_err(|e| e.offset_by(len))? {
+                Some(result) => result,
+                None => return Ok(None),
+            };
+            if domain.is_empty() {
+                return Err(ProtocolError::MissingField("domain"));
+            }
+            len += domain_len;
+            TargetAddr::Domain(domain, port)
+        }
+        false => TargetAddr::Ip(SocketAddr::new(IpAddr::V4(ip), port)),
+    };
+
+    Ok(Some((Socks4Request { cmd, address, user_id }, len)))
+}
+
+pub fn encode_socks4_request(request: &Socks4Request) -> Result<Vec<u8>, ProtocolError> {
+    let cmd = match request.cmd {
+        Connect => 1,
+        Bind => 2,
+        Udp => return Err(ProtocolError::Unencodable("udp command of socks4")),
+    };
+
+    let mut data = vec![4, cmd];
+    data.extend_from_slice(&request.address.port().to_be_bytes());
+    match &request.address {
+        TargetAddr::Ip(SocketAddr::V4(address)) => data.extend_from_slice(&address.ip().octets()),
+        TargetAddr::Ip(SocketAddr::V6(_)) => return Err(ProtocolError::Unencodable("ipv6 address of socks4")),
+        TargetAddr::Domain(_, _) => data.extend_from_slice(&[0, 0, 0, 1]),
+    }
+
+    data.extend_from_slice(request.user_id.as_bytes());
+    data.push(0);
+    if let TargetAddr::Domain(domain, _) = &request.address {
+        data.extend_from_slice(domain.as_bytes());
+        data.push(0);
+    }
 
     Ok(data)
 }
 
-pub fn encode_address_with_type(address: String, address_type: &AddressType)
-                                -> Result<Vec<u8>, &'static str> {
-    match address_type {
-        Ipv4 => encode_address_for_ipv4(address),
-        Domain => encode_address_as_domain(address),
-        Ipv6 => Err("ipv6 is not support in encoding."),
+impl Socks4Request {
+    pub fn new(cmd: CmdType, address: TargetAddr, user_id: String) -> Socks4Request {
+        Socks4Request {
+            cmd,
+            address,
+            user_id,
+        }
+    }
+
+    pub fn cmd(&self) -> &CmdType {
+        &self.cmd
+    }
+
+    pub fn address(&self) -> &TargetAddr {
+        &self.address
+    }
+
+    pub fn user_id(&self) -> &str {
+        &self.user_id
     }
 }
 
-pub fn encode_address_as_domain(address: String) -> Result<Vec<u8>, &'static str> {
-    Ok(address.as_bytes().to_vec())
+/// socks4 reply only tells granted (90) or rejected (91), the address is
+/// meaningful for bind and left zero when it is not ipv4
+pub fn encode_socks4_reply(reply: &ReplyType, address: &SocketAddr) -> Vec<u8> {
+    let status = match reply {
+        Success => 90,
+        _ => 91,
+    };
+
+    let mut data = vec![0, status];
+    match address {
+        SocketAddr::V4(address) => {
+            data.extend_from_slice(&address.port().to_be_bytes());
+            data.extend_from_slice(&address.ip().octets());
+        }
+        SocketAddr::V6(_) => data.extend_from_slice(&[0; 6]),
+    }
+
+    data
 }
 
+/// string ending with a null byte and its length with the null byte,
+/// `None` while the null byte is not received
+fn parse_null_terminated(data: &[u8]) -> Result<Option<(String, usize)>, ProtocolError> {
+    match data.iter().take(SOCKS4_MAX_STRING + 1).position(|byte| *byte == 0) {
+        Some(end) => Ok(Some((parse_string_from_bytes(&data[..end])?, end + 1))),
+        None if data.len() > SOCKS4_MAX_STRING => Err(ProtocolError::Malformed(SOCKS4_MAX_STRING, "string is too long")),
+        None => Ok(None),
+    }
+}
 
-pub fn encode_address_for_ipv4(address: String) -> Result<Vec<u8>, &'static str> {
-    let list: Vec<_> = address.split(".").collect();
-    let mut result = Vec::<u8>::new();
+/// destination address of a request or reply, always together with its port
+#[derive(Debug, Clone, PartialEq)]
+pub enum TargetAddr {
+    Ip(SocketAddr),
+    Domain(String, u16),
+}
 
-    for i in 0..list.len() {
-        let num: u8 = match list.get(i).unwrap().parse() {
-            Ok(value) => Ok(value),
-            Err(e) => Err("parse address error.")
-        }?;
+impl TargetAddr {
+    pub fn address_type(&self) -> AddressType {
+        match self {
+            TargetAddr::Ip(SocketAddr::V4(_)) => Ipv4,
+            TargetAddr::Ip(SocketAddr::V6(_)) => Ipv6,
+            TargetAddr::Domain(_, _) => Domain,
+        }
+    }
 
-        result.push(num);
+    pub fn port(&self) -> u16 {
+        match self {
+            TargetAddr::Ip(addr) => addr.port(),
+            TargetAddr::Domain(_, port) => *port,
+        }
     }
+}
 
-    Ok(result)
+impl From<SocketAddr> for TargetAddr {
+    fn from(addr: SocketAddr) -> TargetAddr {
+        TargetAddr::Ip(addr)
+    }
 }
 
+/// accepts `1.2.3.4:80`, `[::1]:80` and `example.com:80`
+impl FromStr for TargetAddr {
+    type Err = ProtocolError;
 
-impl DstServiceReply {
-    pub fn new(version: Version, reply: ReplyType
-               , address_type: AddressType, address: String, port: u16) -> DstServiceReply {
-        DstServiceReply {
-            version,
-            reply,
-            reserve: 0,
-            address_type,
-            address,
-            port,
+    fn from_str(s: &str) -> Result<TargetAddr, ProtocolError> {
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(TargetAddr::Ip(addr));
+        }
+
+        let invalid = || ProtocolError::InvalidAddress(s.to_string());
+        let (host, port) = match s.rfind(':') {
+            Some(pos) => (&s[..pos], &s[pos + 1..]),
+            None => return Err(invalid()),
+        };
+
+        let port = match port.parse::<u16>() {
+            Ok(port) => port,
+            Err(_) => return Err(invalid()),
+        };
+
+        if host.is_empty() || host.len() > 255 || host.contains(':') {
+            return Err(invalid());
+        }
+
+        Ok(TargetAddr::Domain(host.to_string(), port))
+    }
+}
+
+impl fmt::Display for TargetAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TargetAddr::Ip(addr) => write!(f, "{}", addr),
+            TargetAddr::Domain(domain, port) => write!(f, "{}:{}", domain, port),
+        }
+    }
+}
+
+/// parse DST.ADDR and DST.PORT, return the address and the number of bytes consumed
+pub fn parse_target_addr(data: &[u8], addr_type: &AddressType)
+                         -> Result<Option<(TargetAddr, usize)>, ProtocolError> {
+    let len = data.len();
+    match addr_type {
+        Ipv4 => {
+            if len < 4 + 2 {
+                return Ok(None);
+            }
+            let ip = get_ipv4_from_bytes(&data[0..4])?;
+            let port = get_port(&data[4..6])?;
+            Ok(Some((TargetAddr::Ip(SocketAddr::new(IpAddr::V4(ip), port)), 6)))
+        }
+        Ipv6 => {
+            if len < 16 + 2 {
+                return Ok(None);
+            }
+            let ip = get_ipv6_from_bytes(&data[0..16])?;
+            let port = get_port(&data[16..18])?;
+            Ok(Some((TargetAddr::Ip(SocketAddr::new(IpAddr::V6(ip), port)), 18)))
+        }
+        Domain => {
+            let addr_len = match data.first() {
+                Some(size) => usize::from(*size),
+                None => return Ok(None),
+            };
+            if addr_len == 0 {
+                return Err(ProtocolError::Malformed(0, "domain is empty"));
+            }
+            if len < 1 + addr_len + 2 {
+                return Ok(None);
+            }
+            let domain = get_domain_from_bytes(&data[1..addr_len + 1])
+                .map_err(|e| e.offset_by(1))?;
+            let port = get_port(&data[addr_len + 1..addr_len + 3])?;
+            Ok(Some((TargetAddr::Domain(domain, port), addr_len + 3)))
         }
     }
 }
 
+/// encode ATYP, DST.ADDR and DST.PORT
+pub fn encode_target_addr(address: &TargetAddr, data: &mut Vec<u8>) -> Result<usize, ProtocolError> {
+    let start = data.len();
+    data.push(encode_address_type(&address.address_type())?);
+
+    match address {
+        TargetAddr::Ip(SocketAddr::V4(addr)) => data.extend_from_slice(&addr.ip().octets()),
+        TargetAddr::Ip(SocketAddr::V6(addr)) => data.extend_from_slice(&addr.ip().octets()),
+        TargetAddr::Domain(domain, _) => {
+            let bytes = domain.as_bytes();
+            if bytes.is_empty() || bytes.len() > 255 {
+                return Err(ProtocolError::Unencodable("domain longer than 255 bytes or empty"));
+            }
+            data.push(bytes.len() as u8);
+            data.extend_from_slice(bytes);
+        }
+    }
+
+    let port = address.port();
+    data.push((port >> 8) as u8);
+    data.push(port.bitand(0x00FF) as u8);
+
+    Ok(data.len() - start)
+}
+
+pub fn get_domain_from_bytes(bytes: &[u8]) -> Result<String, ProtocolError> {
+    parse_string_from_bytes(bytes)
+}
+
+pub fn get_ipv4_from_bytes(bytes: &[u8]) -> Result<Ipv4Addr, ProtocolError> {
+    if bytes.len() < 4 {
+        return Err(ProtocolError::Truncated(bytes.len()));
+    }
+
+    Ok(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]))
+}
+
+pub fn get_ipv6_from_bytes(bytes: &[u8]) -> Result<Ipv6Addr, ProtocolError> {
+    if bytes.len() < 16 {
+        return Err(ProtocolError::Truncated(bytes.len()));
+    }
+
+    let mut octets = [0u8; 16];
+    octets.copy_from_slice(&bytes[0..16]);
+
+    Ok(Ipv6Addr::from(octets))
+}
+
+pub fn get_port(bytes: &[u8]) -> Result<u16, ProtocolError> {
+    if bytes.len() < 2 {
+        return Err(ProtocolError::Truncated(bytes.len()));
+    }
+
+    let high = bytes[0];
+    let low = bytes[1];
+
+    Ok(low as u16 | (high as u16) << 8)
+}
+
+
+/// this packet is for username/password sub negotiation request from client (rfc 1929)
 pub struct UserPassAuthRequest {
     version: SubVersion,
     u_len: u8,
@@ -453,6 +617,16 @@ pub struct UserPassAuthRequest {
 }
 
 impl UserPassAuthRequest {
+    pub fn new(version: SubVersion, name: String, password: String) -> UserPassAuthRequest {
+        UserPassAuthRequest {
+            version,
+            u_len: name.len() as u8,
+            name,
+            p_len: password.len() as u8,
+            password,
+        }
+    }
+
     pub fn version(&self) -> &SubVersion {
         &self.version
     }
@@ -475,16 +649,27 @@ impl UserPassAuthRequest {
 }
 
 
-pub fn parse_user_auth_request(data: &[u8]) -> Result<UserPassAuthRequest, &'static str> {
+/// parse request and return it with the number of bytes consumed
+pub fn parse_user_auth_request(data: &[u8]) -> Result<Option<(UserPassAuthRequest, usize)>, ProtocolError> {
     let len = data.len();
     if len < 2 {
-        return Err("data not enough when parsing auth request.");
+        return Ok(None);
+    }
+
+    let u_end = 2 + usize::from(data[1]);
+    if len < u_end + 1 {
+        return Ok(None);
+    }
+
+    let p_end = u_end + 1 + usize::from(data[u_end]);
+    if len < p_end {
+        return Ok(None);
     }
 
     let version = parse_sub_version(data.get(0).cloned())?;
-    let (u_len, name) = parse_len_and_string(&data[1..])?;
-    let start = usize::from(2 + u_len);
-    let (p_len, password) = parse_len_and_string(&data[start..])?;
+    let (u_len, name) = parse_len_and_string(&data[1..u_end]).map_err(|e| e.offset_by(1))?;
+    let (p_len, password) = parse_len_and_string(&data[u_end..p_end])
+        .map_err(|e| e.offset_by(u_end))?;
 
     let result = UserPassAuthRequest {
         version,
@@ -494,43 +679,64 @@ pub fn parse_user_auth_request(data: &[u8]) -> Result<UserPassAuthRequest, &'sta
         password,
     };
 
-    Ok(result)
+    Ok(Some((result, p_end)))
 }
 
-pub fn parse_len_and_string(data: &[u8]) -> Result<(u8, String), &'static str> {
+pub fn encode_user_auth_request(request: &UserPassAuthRequest) -> Result<Vec<u8>, ProtocolError> {
+    let name = request.name.as_bytes();
+    let password = request.password.as_bytes();
+    if name.is_empty() || name.len() > 255 || password.is_empty() || password.len() > 255 {
+        return Err(ProtocolError::Unencodable("username or password longer than 255 bytes or empty"));
+    }
+
+    let mut data = Vec::<u8>::new();
+    data.push(encode_sub_version(&request.version)?);
+    data.push(name.len() as u8);
+    data.extend_from_slice(name);
+    data.push(password.len() as u8);
+    data.extend_from_slice(password);
+
+    Ok(data)
+}
+
+pub fn parse_len_and_string(data: &[u8]) -> Result<(u8, String), ProtocolError> {
     let total = data.len();
     if total == 0 {
-        return Err("data is not enough.");
+        return Err(ProtocolError::Truncated(0));
     }
 
-    let len = match data.get(0).cloned() {
-        Some(size) => Ok(size),
-        None => Err("len is none")
-    }?;
-
-    if total < usize::from(1 + len) {
-        return Err("data is not enough.");
+    let len = data[0];
+    let end = 1 + usize::from(len);
+    if total < end {
+        return Err(ProtocolError::Truncated(total));
     }
 
-    let end = usize::from(len + 1);
-    let name = parse_string_from_bytes(data.get(1..end).unwrap())?;
+    let name = parse_string_from_bytes(&data[1..end]).map_err(|e| e.offset_by(1))?;
 
     Ok((len, name))
 }
 
-pub fn parse_string_from_bytes(data: &[u8]) -> Result<String, &'static str> {
+pub fn parse_string_from_bytes(data: &[u8]) -> Result<String, ProtocolError> {
     match std::str::from_utf8(data) {
         Ok(addr) => Ok(String::from(addr)),
-        Err(e) => Err("err from bytes to utf8 string.")
+        Err(e) => Err(ProtocolError::Malformed(e.valid_up_to(), "string is not utf-8"))
     }
 }
 
+/// this packet is for username/password sub negotiation reply from server
 pub struct UserPassAuthReply {
     version: SubVersion,
     status: AuthResult,
 }
 
 impl UserPassAuthReply {
+    pub fn new(version: SubVersion, status: AuthResult) -> UserPassAuthReply {
+        UserPassAuthReply {
+            version,
+            status,
+        }
+    }
+
     pub fn version(&self) -> &SubVersion {
         &self.version
     }
@@ -540,10 +746,10 @@ impl UserPassAuthReply {
     }
 }
 
-pub fn parse_user_auth_reply(data: &[u8]) -> Result<UserPassAuthReply, &'static str> {
+pub fn parse_user_auth_reply(data: &[u8]) -> Result<UserPassAuthReply, ProtocolError> {
     let len = data.len();
     if len != 2 {
-        return Err("data not enough.");
+        return Err(ProtocolError::Truncated(len));
     }
 
     let version = parse_sub_version(data.get(0).cloned())?;
@@ -556,6 +762,67 @@ pub fn parse_user_auth_reply(data: &[u8]) -> Result<UserPassAuthReply, &'static
     Ok(result)
 }
 
+pub fn encode_user_auth_reply(reply: &UserPassAuthReply) -> Result<Vec<u8>, ProtocolError> {
+    let version = encode_sub_version(reply.version())?;
+    let status = encode_auth_result(reply.status());
+
+    Ok(vec![version, status])
+}
+
+/// header in front of every datagram relayed for udp associate
+#[derive(Debug, Clone, PartialEq)]
+pub struct UdpRequestHeader {
+    frag: u8,
+    address: TargetAddr,
+}
+
+impl UdpRequestHeader {
+    pub fn new(frag: u8, address: TargetAddr) -> UdpRequestHeader {
+        UdpRequestHeader {
+            frag,
+            address,
+        }
+    }
+
+    /// fragment number, 0 for a standalone datagram
+    pub fn frag(&self) -> u8 {
+        self.frag
+    }
+
+    pub fn address(&self) -> &TargetAddr {
+        &self.address
+    }
+}
+
+/// parse header of a datagram and return it with the offset of user data.
+/// datagrams arrive whole, so a short one is an error.
+pub fn parse_udp_request_header(data: &[u8]) -> Result<(UdpRequestHeader, usize), ProtocolError> {
+    if data.len() < 4 {
+        return Err(ProtocolError::Truncated(data.len()));
+    }
+
+    if data[0] != 0 || data[1] != 0 {
+        return Err(ProtocolError::Malformed(0, "reserved field should be zero"));
+    }
+
+    let frag = data[2];
+    let address_type = parse_address_type(data.get(3).cloned())?;
+    let (address, address_len) = match parse_target_addr(&data[4..], &address_type)
+        .map_err(|e| e.offset_by(4))? {
+        Some(result) => result,
+        None => return Err(ProtocolError::Truncated(data.len())),
+    };
+
+    Ok((UdpRequestHeader { frag, address }, 4 + address_len))
+}
+
+pub fn encode_udp_request_header(header: &UdpRequestHeader) -> Result<Vec<u8>, ProtocolError> {
+    let mut data = vec![0, 0, header.frag];
+    encode_target_addr(&header.address, &mut data)?;
+
+    Ok(data)
+}
+
 /// socks version
 #[derive(Debug, PartialEq)]
 pub enum Version {
@@ -563,41 +830,51 @@ pub enum Version {
     Others,
 }
 
-/// sub negotiation version
+/// sub negotiation version, rfc 1929 uses V1
 #[derive(Debug, PartialEq)]
 pub enum SubVersion {
     V0,
+    V1,
     Others,
 }
 
 
-pub fn parse_version(version: Option<u8>) -> Result<Version, &'static str> {
+pub fn parse_version(version: Option<u8>) -> Result<Version, ProtocolError> {
     match version {
         Some(5) => Ok(Version::Socks5),
         Some(_) => Ok(Version::Others),
-        None => Err("empty version num.")
+        None => Err(ProtocolError::MissingField("version"))
     }
 }
 
-pub fn encode_version(version: &Version) -> Result<u8, &'static str> {
+pub fn encode_version(version: &Version) -> Result<u8, ProtocolError> {
     match version {
         Version::Socks5 => Ok(5),
 // never
-        Version::Others => Err("proxy only support version 5.")
+        Version::Others => Err(ProtocolError::Unencodable("version other than 5"))
     }
 }
 
-fn parse_sub_version(version: Option<u8>) -> Result<SubVersion, &'static str> {
+fn parse_sub_version(version: Option<u8>) -> Result<SubVersion, ProtocolError> {
     match version {
         Some(0) => Ok(V0),
+        Some(1) => Ok(SubVersion::V1),
         Some(_) => Ok(SubVersion::Others),
-        None => Err("empty sub version num.")
+        None => Err(ProtocolError::MissingField("sub negotiation version"))
+    }
+}
+
+pub fn encode_sub_version(version: &SubVersion) -> Result<u8, ProtocolError> {
+    match version {
+        V0 => Ok(0),
+        SubVersion::V1 => Ok(1),
+        SubVersion::Others => Err(ProtocolError::Unencodable("sub negotiation version"))
     }
 }
 
 
 /// auth type enum
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum AuthType {
     Non,
     Gssapi,
@@ -607,23 +884,26 @@ pub enum AuthType {
     NonAccept,
 }
 
-pub fn parse_auth_type(auth: Option<u8>) -> Result<AuthType, &'static str> {
+/// unknown methods are kept as `IanaAssigned`/`Reserved` so a greeting
+/// offering them can still be answered.
+pub fn parse_auth_type(auth: Option<u8>) -> Result<AuthType, ProtocolError> {
     match auth {
         Some(0) => Ok(Non),
         Some(1) => Ok(Gssapi),
         Some(2) => Ok(NamePassword),
-        Some(3) => Ok(IanaAssigned),
-        Some(0x80) => Ok(Reserved),
+        Some(0x03..=0x7f) => Ok(IanaAssigned),
+        Some(0x80..=0xfe) => Ok(Reserved),
         Some(0xff) => Ok(NonAccept),
-        _ => return Err("auth method not supported.")
+        None => Err(ProtocolError::MissingField("auth method"))
     }
 }
 
-pub fn encode_auth_type(auth_type: &AuthType) -> Result<u8, &'static str> {
+pub fn encode_auth_type(auth_type: &AuthType) -> Result<u8, ProtocolError> {
     match auth_type {
         Non => Ok(0),
         NamePassword => Ok(2),
-        _ => Err("auth method not supported."),
+        NonAccept => Ok(0xff),
+        _ => Err(ProtocolError::Unencodable("auth method other than non, name/password and non accept")),
     }
 }
 
@@ -634,42 +914,43 @@ pub enum CmdType {
     Udp,
 }
 
-pub fn parse_cmd(cmd: Option<u8>) -> Result<CmdType, &'static str> {
+pub fn parse_cmd(cmd: Option<u8>) -> Result<CmdType, ProtocolError> {
     match cmd {
         Some(1) => Ok(Connect),
         Some(2) => Ok(Bind),
         Some(3) => Ok(Udp),
-        _ => Err("cmd type not support.")
+        Some(other) => Err(ProtocolError::UnsupportedCommand(other)),
+        None => Err(ProtocolError::MissingField("cmd"))
     }
 }
 
-pub fn encode_cmd(cmd_type: &CmdType) -> Result<u8, &'static str> {
+pub fn encode_cmd(cmd_type: &CmdType) -> Result<u8, ProtocolError> {
     match cmd_type {
         Connect => Ok(1),
         Bind => Ok(2),
         Udp => Ok(3),
-        _ => Err("cmd type not support.")
     }
 }
 
 /// address type enum
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum AddressType {
     Ipv4,
     Domain,
     Ipv6,
 }
 
-fn parse_address_type(addr_type: Option<u8>) -> Result<AddressType, &'static str> {
+fn parse_address_type(addr_type: Option<u8>) -> Result<AddressType, ProtocolError> {
     match addr_type {
         Some(1) => Ok(Ipv4),
         Some(3) => Ok(Domain),
         Some(4) => Ok(Ipv6),
-        _ => Err("address type not support.")
+        Some(other) => Err(ProtocolError::UnsupportedAddressType(other)),
+        None => Err(ProtocolError::MissingField("address type"))
     }
 }
 
-pub fn encode_address_type(address_type: &AddressType) -> Result<u8, &'static str> {
+pub fn encode_address_type(address_type: &AddressType) -> Result<u8, ProtocolError> {
     match address_type {
         Ipv4 => Ok(1),
         Domain => Ok(3),
@@ -684,16 +965,23 @@ pub enum AuthResult {
 }
 
 
-fn parse_auth_result(result: Option<u8>) -> Result<AuthResult, &'static str> {
+fn parse_auth_result(result: Option<u8>) -> Result<AuthResult, ProtocolError> {
     match result {
         Some(0) => Ok(AuthResult::Success),
         Some(_) => Ok(AuthResult::Failure),
-        _ => Err("auth reply is empty.")
+        None => Err(ProtocolError::MissingField("auth status"))
+    }
+}
+
+fn encode_auth_result(result: &AuthResult) -> u8 {
+    match result {
+        AuthResult::Success => 0,
+        AuthResult::Failure => 1,
     }
 }
 
 /// reply type enum
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum ReplyType {
     Success,
     ServerFailure,
@@ -707,7 +995,7 @@ pub enum ReplyType {
     Others,
 }
 
-pub fn encode_reply_type(reply_type: &ReplyType) -> Result<u8, &'static str> {
+pub fn encode_reply_type(reply_type: &ReplyType) -> Result<u8, ProtocolError> {
     match reply_type {
         Success => Ok(0),
         ServerFailure => Ok(1),
@@ -722,7 +1010,7 @@ pub fn encode_reply_type(reply_type: &ReplyType) -> Result<u8, &'static str> {
     }
 }
 
-fn parse_reply_type(reply_type: Option<u8>) -> Result<ReplyType, &'static str> {
+fn parse_reply_type(reply_type: Option<u8>) -> Result<ReplyType, ProtocolError> {
     match reply_type {
         Some(0) => Ok(Success),
         Some(1) => Ok(ServerFailure),
@@ -734,7 +1022,8 @@ fn parse_reply_type(reply_type: Option<u8>) -> Result<ReplyType, &'static str> {
         Some(7) => Ok(CmdNotSupport),
         Some(8) => Ok(AddressTypeNotSupport),
         Some(9) => Ok(ReplyType::Others),
-        _ => Err("reply type not support.")
+        Some(other) => Err(ProtocolError::UnsupportedReplyType(other)),
+        None => Err(ProtocolError::MissingField("reply"))
     }
 }
 
@@ -753,9 +1042,23 @@ pub enum ClientStage {
 /// server stage transfer enum
 #[derive(Debug, PartialEq)]
 pub enum ServerStage {
+    /// first bytes of client are awaited to tell which protocol it speaks
+    Detecting,
     Init,
+    AuthSubNegotiation,
+    /// name/password of client is being verified
+    Authenticating,
     AuthSelectFinish,
+    /// domain of request is being resolved
+    Resolving,
+    /// connect to destination is in progress, reply is sent once it finishes
+    Connecting,
+    /// first bind reply is sent, waiting for peer to connect
+    BindListening,
+    /// udp associate is set up, tcp connection only keeps it alive
+    UdpAssociated,
     RequestFinish,
     ReceiveContent,
     ContentFinish,
+    Closing,
 }
\ No newline at end of file
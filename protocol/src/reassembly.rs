@@ -0,0 +1,121 @@
+use std::time::{Duration, Instant};
+use crate::packet::{TargetAddr, UdpRequestHeader};
+
+/// rfc 1928 asks for a reassembly timer of no less than 5 seconds
+pub const DEFAULT_REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// largest datagram which can be rebuilt from fragments
+pub const MAX_REASSEMBLED_SIZE: usize = 65535;
+
+/// high bit of FRAG marks the last fragment of a sequence
+const END_OF_SEQUENCE: u8 = 0x80;
+
+struct FragmentQueue {
+    address: TargetAddr,
+    position: u8,
+    data: Vec<u8>,
+    deadline: Instant,
+}
+
+/// reassembly queue of one udp association.
+///
+/// fragments must arrive in order starting from position 1, a fragment
+/// with a position lower than expected abandons the current sequence, as
+/// does a standalone datagram or an expired timer.
+pub struct Reassembler {
+    timeout: Duration,
+    queue: Option<FragmentQueue>,
+    dropped: usize,
+}
+
+impl Reassembler {
+    pub fn new(timeout: Duration) -> Reassembler {
+        Reassembler {
+            timeout,
+            queue: None,
+            dropped: 0,
+        }
+    }
+
+    /// add a datagram, returns destination and user data once they are complete
+    pub fn push(&mut self, header: &UdpRequestHeader, data: &[u8], now: Instant)
+                -> Option<(TargetAddr, Vec<u8>)> {
+        self.expire(now);
+
+        let frag = header.frag();
+        if frag == 0 {
+            self.abandon();
+            return Some((header.address().clone(), data.to_vec()));
+        }
+
+        let position = frag & !END_OF_SEQUENCE;
+        let continued = match &self.queue {
+            Some(queue) => queue.address == *header.address() && position == queue.position + 1,
+            None => false,
+        };
+
+        if !continued {
+            self.abandon();
+            if position != 1 {
+                self.dropped += 1;
+                return None;
+            }
+        }
+
+        let queue = self.queue.get_or_insert_with(|| FragmentQueue {
+            address: header.address().clone(),
+            position: 0,
+            data: Vec::new(),
+            deadline: now,
+        });
+
+        if queue.data.len() + data.len() > MAX_REASSEMBLED_SIZE {
+            self.abandon();
+            self.dropped += 1;
+            return None;
+        }
+
+        queue.position = position;
+        queue.data.extend_from_slice(data);
+        queue.deadline = now + self.timeout;
+
+        if frag & END_OF_SEQUENCE == 0 {
+            return None;
+        }
+
+        self.queue.take().map(|queue| (queue.address, queue.data))
+    }
+
+    /// drop the incomplete sequence when its timer is expired, returns true if one was dropped
+    pub fn expire(&mut self, now: Instant) -> bool {
+        match &self.queue {
+            Some(queue) if queue.deadline <= now => {
+                self.abandon();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// a sequence is being reassembled
+    pub fn is_pending(&self) -> bool {
+        self.queue.is_some()
+    }
+
+    /// number of fragments thrown away so far, including abandoned sequences
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    fn abandon(&mut self) {
+        if let Some(queue) = self.queue.take() {
+            self.dropped += usize::from(queue.position);
+        }
+    }
+}
+
+impl Default for Reassembler {
+    fn default() -> Reassembler {
+        Reassembler::new(DEFAULT_REASSEMBLY_TIMEOUT)
+    }
+}
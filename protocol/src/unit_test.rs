@@ -1,6 +1,10 @@
 mod unit_test {
     use crate::packet::*;
     use crate::packet::AddressType::{Ipv4, Domain};
+    use crate::error::ProtocolError;
+    use std::net::Ipv4Addr;
+    use std::time::{Duration, Instant};
+    use crate::reassembly::*;
 
     #[test]
     fn parse_version_socks5_success() {
@@ -34,7 +38,7 @@ mod unit_test {
 
         match version {
             Ok(v) => assert_eq!(v, Version::Others),
-            Err(e) => assert_eq!("empty version num.", e)
+            Err(e) => assert_eq!(ProtocolError::MissingField("version"), e)
         }
     }
 
@@ -44,8 +48,8 @@ mod unit_test {
         let address = get_ipv4_from_bytes(&bytes);
 
         match address {
-            Ok(addr) => assert_eq!("49.50.55.46", addr),
-            Err(e) => assert_eq!("err from bytes to utf8 string.", e)
+            Ok(addr) => assert_eq!(Ipv4Addr::new(49, 50, 55, 46), addr),
+            Err(e) => assert_eq!(ProtocolError::Malformed(0, "string is not utf-8"), e)
         }
     }
 
@@ -80,12 +84,12 @@ mod unit_test {
     #[test]
     fn get_dst_ipv4_address_success() {
         let bytes = [49, 50, 55, 46, 1, 2];
-        let result = parse_dst_address(&bytes, &Ipv4);
+        let result = parse_target_addr(&bytes, &Ipv4);
 
         match result {
             Ok(Some(((address, address_len)))) => {
-                assert_eq!("49.50.55.46", address);
-                assert_eq!(4, address_len);
+                assert_eq!("49.50.55.46:258".parse::<TargetAddr>().unwrap(), address);
+                assert_eq!(6, address_len);
             }
             _ => unreachable!()
         }
@@ -97,12 +101,12 @@ mod unit_test {
         let mut bytes = [13, 119, 119,
             119, 46, 98, 97, 105, 100, 117, 46, 99, 111, 109, 1, 1];
 
-        let result = parse_dst_address(&bytes, &Domain);
+        let result = parse_target_addr(&bytes, &Domain);
 
         match result {
             Ok(Some((address, address_len))) => {
-                assert_eq!("www.baidu.com", address);
-                assert_eq!(14, address_len);
+                assert_eq!(TargetAddr::Domain(domain.to_string(), 257), address);
+                assert_eq!(16, address_len);
             }
             _ => unreachable!()
         }
@@ -136,7 +140,7 @@ mod unit_test {
                 assert_eq!("mio-and-tokio", name);
             }
 
-            Err(msg) => assert_eq!("data is not enough.", msg)
+            Err(msg) => assert_eq!(ProtocolError::Truncated(0), msg)
         }
     }
 
@@ -148,7 +152,8 @@ mod unit_test {
         let result = parse_user_auth_request(&bytes);
 
         match result {
-            Ok(request) => {
+            Ok(Some((request, request_len))) => {
+                assert_eq!(22, request_len);
                 assert_eq!(SubVersion::V0, *request.version());
                 assert_eq!(13 as u8, request.u_len());
                 assert_eq!("mio-and-tokio", request.name());
@@ -192,6 +197,13 @@ mod unit_test {
         }
     }
 
+    #[test]
+    fn encode_auth_select_reply_with_non_accept() {
+        let reply = AuthSelectReply::new(Version::Socks5, AuthType::NonAccept);
+
+        assert_eq!(Ok(vec![5, 0xff]), encode_auth_select_reply(&reply));
+    }
+
     #[test]
     fn encode_auth_select_reply_success_failed() {
         let reply =
@@ -205,15 +217,14 @@ mod unit_test {
                 assert_eq!(5, bytes[0]);
                 assert_eq!(0, bytes[1]);
             }
-            Err(err) => assert_eq!("proxy only support version 5.", err)
+            Err(err) => assert_eq!(ProtocolError::Unencodable("version other than 5"), err)
         }
     }
 
     #[test]
     fn encode_dst_service_reply_success() {
-        let reply = DstServiceReply::new(Version::Socks5
-                                         , ReplyType::Success, AddressType::Ipv4
-                                         , "127.0.0.1".to_string(), 258);
+        let reply = DstServiceReply::new(Version::Socks5, ReplyType::Success
+                                         , "127.0.0.1:258".parse().unwrap());
 
         let data = encode_dst_service_reply(reply);
 
@@ -263,8 +274,7 @@ mod unit_test {
     #[test]
     fn encode_dst_service_request_success() {
         let request = DstServiceRequest::new(
-            Version::Socks5, CmdType::Connect, 0
-            , AddressType::Ipv4, "127.0.0.1".to_string(), 258);
+            Version::Socks5, CmdType::Connect, 0, "127.0.0.1:258".parse().unwrap());
 
         let data = encode_dst_service_request(request);
 
@@ -292,7 +302,7 @@ mod unit_test {
     fn encode_dst_request_with_domain_success() {
         let request = DstServiceRequest::new(
             Version::Socks5, CmdType::Connect, 0
-            , AddressType::Domain, "127.0.0.1".to_string(), 80);
+            , TargetAddr::Domain("127.0.0.1".to_string(), 80));
 
         let data = encode_dst_service_request(request);
 
@@ -304,22 +314,429 @@ mod unit_test {
                 assert_eq!(1, bytes[1]);
                 assert_eq!(0, bytes[2]);
                 assert_eq!(3, bytes[3]);
-                assert_eq!(49, bytes[4]);
-                assert_eq!(50, bytes[5]);
-                assert_eq!(55, bytes[6]);
-                assert_eq!(46, bytes[7]);
-
-                assert_eq!(48, bytes[8]);
-                assert_eq!(46, bytes[9]);
-                assert_eq!(48, bytes[10]);
-                assert_eq!(46, bytes[11]);
-                assert_eq!(49, bytes[12]);
-
-                assert_eq!(0, bytes[13]);
-                assert_eq!(80, bytes[14]);
+                assert_eq!(9, bytes[4]);
+                assert_eq!(49, bytes[5]);
+                assert_eq!(50, bytes[6]);
+                assert_eq!(55, bytes[7]);
+                assert_eq!(46, bytes[8]);
+
+                assert_eq!(48, bytes[9]);
+                assert_eq!(46, bytes[10]);
+                assert_eq!(48, bytes[11]);
+                assert_eq!(46, bytes[12]);
+                assert_eq!(49, bytes[13]);
+
+                assert_eq!(0, bytes[14]);
+                assert_eq!(80, bytes[15]);
+            }
+
+            _ => unreachable!()
+        }
+    }
+
+    #[test]
+    fn get_dst_ipv6_address_success() {
+        let bytes = [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 2];
+        let result = parse_target_addr(&bytes, &AddressType::Ipv6);
+
+        match result {
+            Ok(Some((address, address_len))) => {
+                assert_eq!("[2001:db8::1]:258".parse::<TargetAddr>().unwrap(), address);
+                assert_eq!(18, address_len);
+            }
+            _ => unreachable!()
+        }
+    }
+
+    #[test]
+    fn parse_dst_ipv6_request_success() {
+        let bytes = [5, 1, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 80];
+        let result = parse_dst_service_request(&bytes);
+
+        match result {
+            Ok(Some((request, request_len))) => {
+                assert_eq!(AddressType::Ipv6, request.address_type());
+                assert_eq!("[::1]:80", request.address().to_string());
+                assert_eq!(80, request.port());
+                assert_eq!(22, request_len);
+            }
+            _ => unreachable!()
+        }
+    }
+
+    #[test]
+    fn encode_dst_service_reply_with_ipv6_success() {
+        let reply = DstServiceReply::new(Version::Socks5, ReplyType::Success
+                                         , "[fe80::1:2]:1080".parse().unwrap());
+
+        let data = encode_dst_service_reply(reply);
+
+        match data {
+            Ok(buffer) => {
+                let bytes = buffer.as_slice();
+                assert_eq!(22, bytes.len());
+                assert_eq!([5, 0, 0, 4], bytes[0..4]);
+                assert_eq!([0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 2], bytes[4..20]);
+                assert_eq!([4, 56], bytes[20..22]);
+            }
+
+            Err(err) => unreachable!()
+        }
+    }
+
+    #[test]
+    fn ipv6_reply_round_trip_success() {
+        let reply = DstServiceReply::new(Version::Socks5, ReplyType::Success
+                                         , "[2001:db8:85a3::8a2e:370:7334]:443".parse().unwrap());
+
+        let data = encode_dst_service_reply(reply).unwrap();
+        let result = parse_dst_service_reply(data.as_slice());
+
+        match result {
+            Ok(Some(parsed)) => {
+                assert_eq!(ReplyType::Success, *parsed.reply());
+                assert_eq!(AddressType::Ipv6, parsed.address_type());
+                assert_eq!("[2001:db8:85a3::8a2e:370:7334]:443", parsed.address().to_string());
+                assert_eq!(443, parsed.port());
+            }
+            _ => unreachable!()
+        }
+    }
+
+    #[test]
+    fn ipv6_request_round_trip_success() {
+        let request = DstServiceRequest::new(
+            Version::Socks5, CmdType::Connect, 0, "[::ffff:127.0.0.1]:8080".parse().unwrap());
+
+        let data = encode_dst_service_request(request).unwrap();
+        let result = parse_dst_service_request(data.as_slice());
+
+        match result {
+            Ok(Some((parsed, _))) => {
+                assert_eq!(AddressType::Ipv6, parsed.address_type());
+                assert_eq!("[::ffff:127.0.0.1]:8080", parsed.address().to_string());
+                assert_eq!(8080, parsed.port());
+            }
+            _ => unreachable!()
+        }
+    }
+
+    #[test]
+    fn socks4_request_round_trip_success() {
+        let ip = Socks4Request::new(CmdType::Connect, "10.0.0.1:1080".parse().unwrap(), "me".to_string());
+        let data = encode_socks4_request(&ip).unwrap();
+        assert_eq!(vec![4 as u8, 1, 4, 56, 10, 0, 0, 1, 109, 101, 0], data);
+
+        // socks4a sends the domain after user id
+        let domain = Socks4Request::new(CmdType::Connect, "example.com:80".parse().unwrap(), String::new());
+        let data = encode_socks4_request(&domain).unwrap();
+
+        assert_eq!(None, parse_socks4_request(&data[..data.len() - 1]).unwrap().map(|(_, len)| len));
+        match parse_socks4_request(&data) {
+            Ok(Some((parsed, len))) => {
+                assert_eq!(data.len(), len);
+                assert_eq!(&TargetAddr::Domain("example.com".to_string(), 80), parsed.address());
+                assert_eq!("", parsed.user_id());
+            }
+            _ => unreachable!()
+        }
+    }
+
+    #[test]
+    fn parse_socks4_request_failed() {
+        assert_eq!(Err(ProtocolError::UnsupportedVersion(5)),
+                   parse_socks4_request(&[5, 1, 0, 80, 10, 0, 0, 1, 0]).map(|_| ()));
+        assert_eq!(Err(ProtocolError::UnsupportedCommand(3)),
+                   parse_socks4_request(&[4, 3, 0, 80, 10, 0, 0, 1, 0]).map(|_| ()));
+
+        let mut long = vec![4 as u8, 1, 0, 80, 10, 0, 0, 1];
+        long.extend_from_slice(&[b'a'; 300]);
+        assert!(parse_socks4_request(&long).is_err());
+    }
+
+    #[test]
+    fn encode_socks4_reply_success() {
+        let bound: std::net::SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        assert_eq!(vec![0 as u8, 90, 31, 144, 127, 0, 0, 1], encode_socks4_reply(&ReplyType::Success, &bound));
+
+        let v6: std::net::SocketAddr = "[::1]:80".parse().unwrap();
+        assert_eq!(vec![0 as u8, 91, 0, 0, 0, 0, 0, 0], encode_socks4_reply(&ReplyType::HostUnreachable, &v6));
+    }
+
+    #[test]
+    fn parse_target_addr_from_str_success() {
+        let ipv4 = "10.0.0.1:1080".parse::<TargetAddr>().unwrap();
+        let ipv6 = "[::1]:443".parse::<TargetAddr>().unwrap();
+        let domain = "example.com:80".parse::<TargetAddr>().unwrap();
+
+        assert_eq!(AddressType::Ipv4, ipv4.address_type());
+        assert_eq!(AddressType::Ipv6, ipv6.address_type());
+        assert_eq!(TargetAddr::Domain("example.com".to_string(), 80), domain);
+    }
+
+    #[test]
+    fn parse_target_addr_from_str_failed() {
+        assert!("example.com".parse::<TargetAddr>().is_err());
+        assert!("example.com:http".parse::<TargetAddr>().is_err());
+        assert!(":80".parse::<TargetAddr>().is_err());
+        assert!("::1:80".parse::<TargetAddr>().is_err());
+    }
+
+    #[test]
+    fn parse_empty_domain_failed() {
+        let bytes = [5, 1, 0, 3, 0, 0, 80];
+        let result = parse_dst_service_request(&bytes);
+
+        match result {
+            Err(msg) => assert_eq!(ProtocolError::Malformed(4, "domain is empty"), msg),
+            _ => unreachable!()
+        }
+    }
+
+    #[test]
+    fn parse_domain_data_not_enough() {
+        let bytes = [5, 1, 0, 3];
+        assert!(parse_dst_service_request(&bytes).unwrap().is_none());
+
+        let bytes = [5, 1, 0, 3, 3, 97, 98, 99, 0];
+        assert!(parse_dst_service_request(&bytes).unwrap().is_none());
+    }
+
+    #[test]
+    fn domain_request_round_trip_success() {
+        let request = DstServiceRequest::new(
+            Version::Socks5, CmdType::Connect, 0, "www.example.com:443".parse().unwrap());
+
+        let data = encode_dst_service_request(request).unwrap();
+        let result = parse_dst_service_request(data.as_slice());
+
+        match result {
+            Ok(Some((parsed, request_len))) => {
+                assert_eq!(TargetAddr::Domain("www.example.com".to_string(), 443), *parsed.address());
+                assert_eq!(data.len(), request_len);
+            }
+            _ => unreachable!()
+        }
+    }
+
+    #[test]
+    fn parse_unsupported_cmd_failed() {
+        let bytes = [5, 9, 0, 1, 127, 0, 0, 1, 0, 80];
+        let result = parse_dst_service_request(&bytes);
+
+        match result {
+            Err(e) => {
+                assert_eq!(ProtocolError::UnsupportedCommand(9), e);
+                assert_eq!(ReplyType::CmdNotSupport, e.reply_type());
+            }
+            _ => unreachable!()
+        }
+    }
+
+    #[test]
+    fn parse_unsupported_address_type_failed() {
+        let bytes = [5, 1, 0, 2, 127, 0, 0, 1, 0, 80];
+        let result = parse_dst_service_request(&bytes);
+
+        match result {
+            Err(e) => {
+                assert_eq!(ProtocolError::UnsupportedAddressType(2), e);
+                assert_eq!(ReplyType::AddressTypeNotSupport, e.reply_type());
             }
+            _ => unreachable!()
+        }
+    }
+
+    #[test]
+    fn parse_domain_not_utf8_failed() {
+        let bytes = [5, 1, 0, 3, 3, 97, 0xff, 98, 0, 80];
+        let result = parse_dst_service_request(&bytes);
+
+        match result {
+            Err(e) => {
+                assert_eq!(ProtocolError::Malformed(6, "string is not utf-8"), e);
+                assert_eq!(ReplyType::ServerFailure, e.reply_type());
+            }
+            _ => unreachable!()
+        }
+    }
+
+    #[test]
+    fn parse_auth_select_request_with_unknown_method_success() {
+        let bytes = [5, 3, 0x09, 0x85, 0];
+        let result = parse_auth_select_request_packet(&bytes);
 
+        match result {
+            Ok(Some(request)) => {
+                assert_eq!(vec![AuthType::IanaAssigned, AuthType::Reserved, AuthType::Non]
+                           , *request.methods());
+            }
             _ => unreachable!()
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn parse_user_auth_request_data_not_enough() {
+        let bytes = [1, 4, 117, 115, 101, 114, 3, 112, 119];
+
+        for end in 0..bytes.len() {
+            match parse_user_auth_request(&bytes[..end]) {
+                Ok(None) => {}
+                _ => unreachable!()
+            }
+        }
+    }
+
+    #[test]
+    fn user_auth_request_round_trip_success() {
+        let request = UserPassAuthRequest::new(SubVersion::V1, "user".to_string()
+                                               , "secret".to_string());
+
+        let data = encode_user_auth_request(&request).unwrap();
+        assert_eq!(vec![1, 4, 117, 115, 101, 114, 6, 115, 101, 99, 114, 101, 116], data);
+
+        match parse_user_auth_request(&data) {
+            Ok(Some((parsed, request_len))) => {
+                assert_eq!(SubVersion::V1, *parsed.version());
+                assert_eq!("user", parsed.name());
+                assert_eq!("secret", parsed.password());
+                assert_eq!(data.len(), request_len);
+            }
+            _ => unreachable!()
+        }
+    }
+
+    #[test]
+    fn encode_user_auth_reply_success() {
+        let success = UserPassAuthReply::new(SubVersion::V1, AuthResult::Success);
+        let failure = UserPassAuthReply::new(SubVersion::V1, AuthResult::Failure);
+
+        assert_eq!(vec![1, 0], encode_user_auth_reply(&success).unwrap());
+        assert_eq!(vec![1, 1], encode_user_auth_reply(&failure).unwrap());
+    }
+
+    #[test]
+    fn udp_request_header_round_trip_success() {
+        let header = UdpRequestHeader::new(0, TargetAddr::Domain("example.com".to_string(), 53));
+        let mut data = encode_udp_request_header(&header).unwrap();
+        assert_eq!(vec![0, 0, 0, 3, 11], data[..5].to_vec());
+
+        let header_len = data.len();
+        data.extend_from_slice(&[1, 2, 3]);
+        match parse_udp_request_header(&data) {
+            Ok((parsed, offset)) => {
+                assert_eq!(header, parsed);
+                assert_eq!(header_len, offset);
+                assert_eq!(&[1, 2, 3], &data[offset..]);
+            }
+            _ => unreachable!()
+        }
+    }
+
+    #[test]
+    fn parse_udp_request_header_with_ipv6_success() {
+        let data = [0, 0, 1, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 53, 9];
+
+        let (header, offset) = parse_udp_request_header(&data).unwrap();
+        assert_eq!(1, header.frag());
+        assert_eq!(&TargetAddr::Ip("[::1]:53".parse().unwrap()), header.address());
+        assert_eq!(22, offset);
+    }
+
+    #[test]
+    fn parse_udp_request_header_failed() {
+        assert_eq!(Err(ProtocolError::Truncated(3)), parse_udp_request_header(&[0, 0, 0]));
+        assert_eq!(Err(ProtocolError::Malformed(0, "reserved field should be zero"))
+                   , parse_udp_request_header(&[0, 1, 0, 1, 127, 0, 0, 1, 0, 53]));
+        assert_eq!(Err(ProtocolError::Truncated(7))
+                   , parse_udp_request_header(&[0, 0, 0, 1, 127, 0, 0]));
+        assert_eq!(Err(ProtocolError::UnsupportedAddressType(9))
+                   , parse_udp_request_header(&[0, 0, 0, 9, 127, 0, 0, 1]));
+    }
+
+    fn fragment(frag: u8) -> UdpRequestHeader {
+        UdpRequestHeader::new(frag, TargetAddr::Domain("example.com".to_string(), 53))
+    }
+
+    #[test]
+    fn reassemble_fragments_success() {
+        let mut reassembler = Reassembler::default();
+        let now = Instant::now();
+
+        assert_eq!(None, reassembler.push(&fragment(1), b"he", now));
+        assert_eq!(None, reassembler.push(&fragment(2), b"ll", now));
+        assert!(reassembler.is_pending());
+
+        let (address, data) = reassembler.push(&fragment(0x83), b"o", now).unwrap();
+        assert_eq!(TargetAddr::Domain("example.com".to_string(), 53), address);
+        assert_eq!(b"hello".to_vec(), data);
+        assert!(!reassembler.is_pending());
+        assert_eq!(0, reassembler.dropped());
+    }
+
+    #[test]
+    fn reassemble_standalone_datagram() {
+        let mut reassembler = Reassembler::default();
+        let now = Instant::now();
+
+        assert_eq!(None, reassembler.push(&fragment(1), b"he", now));
+        let (_, data) = reassembler.push(&fragment(0), b"dns", now).unwrap();
+
+        assert_eq!(b"dns".to_vec(), data);
+        assert!(!reassembler.is_pending());
+        assert_eq!(1, reassembler.dropped());
+    }
+
+    #[test]
+    fn reassemble_drops_out_of_order_fragments() {
+        let mut reassembler = Reassembler::default();
+        let now = Instant::now();
+
+        // lower position restarts the sequence
+        assert_eq!(None, reassembler.push(&fragment(1), b"a", now));
+        assert_eq!(None, reassembler.push(&fragment(2), b"b", now));
+        assert_eq!(None, reassembler.push(&fragment(1), b"x", now));
+        assert_eq!(2, reassembler.dropped());
+
+        // a gap makes the sequence incomplete
+        assert_eq!(None, reassembler.push(&fragment(0x83), b"z", now));
+        assert!(!reassembler.is_pending());
+        assert_eq!(4, reassembler.dropped());
+
+        assert_eq!(None, reassembler.push(&fragment(0x80), b"z", now));
+        assert_eq!(5, reassembler.dropped());
+    }
+
+    #[test]
+    fn reassemble_timeout() {
+        let mut reassembler = Reassembler::new(Duration::from_secs(5));
+        let now = Instant::now();
+
+        assert_eq!(None, reassembler.push(&fragment(1), b"he", now));
+        assert!(!reassembler.expire(now + Duration::from_secs(4)));
+        assert_eq!(None, reassembler.push(&fragment(2), b"ll", now + Duration::from_secs(4)));
+
+        // timer restarts with every fragment
+        assert!(!reassembler.expire(now + Duration::from_secs(8)));
+        assert!(reassembler.expire(now + Duration::from_secs(9)));
+        assert!(!reassembler.is_pending());
+        assert_eq!(2, reassembler.dropped());
+
+        let later = now + Duration::from_secs(20);
+        assert_eq!(None, reassembler.push(&fragment(1), b"he", later));
+        assert_eq!(None, reassembler.push(&fragment(0x82), b"y", later + Duration::from_secs(5)));
+        assert_eq!(4, reassembler.dropped());
+    }
+
+    #[test]
+    fn reassemble_size_limit() {
+        let mut reassembler = Reassembler::default();
+        let now = Instant::now();
+        let data = vec![0 as u8; MAX_REASSEMBLED_SIZE];
+
+        assert_eq!(None, reassembler.push(&fragment(1), &data, now));
+        assert_eq!(None, reassembler.push(&fragment(0x82), b"!", now));
+        assert!(!reassembler.is_pending());
+        assert_eq!(2, reassembler.dropped());
+    }
+}
@@ -1,272 +1,52 @@
 extern crate network;
-extern crate mio;
 
-use network::server::ChildHandler;
-use network::server::ServerHandler;
-use mio::{Poll, Ready, PollOpt, Events, Token};
-use std::time::Duration;
-use std::collections::HashMap;
-use std::process::Child;
-use std::io::Read;
-use mio::net::TcpStream;
-use std::net::Shutdown;
-use network::tokens::Tokens;
-use std::fs::read_to_string;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use network::server::Server;
+use network::auth::{Authenticator, FileAuthenticator};
+use network::policy::AuthPolicy;
 
 fn main() {
     let args: Vec<String> = std::env::args().collect();
 
-    if args.len() != 3 {
-        panic!("address and port should be specified!");
+    if args.len() < 3 || args.len() > 5 {
+        panic!("address and port should be specified, users file and policy file are optional!");
     }
 
     let address = parse_address(args.get(1).unwrap());
     let port = parse_port(args.get(2).unwrap());
+    let address = Ipv4Addr::new(address[0], address[1], address[2], address[3]);
 
-    let mut server = ServerHandler::new(address, port);
+    let mut builder = Server::builder().listen(SocketAddr::new(IpAddr::V4(address), port));
 
-    let token = match server.init() {
-        Ok(token) => token,
-        Err(err) => {
-            panic!("bind port err.")
-        }
-    };
-    let mut poll = match Poll::new() {
-        Ok(poll) => poll,
-        Err(err) => {
-            panic!("create poll err.")
-        }
+    // name/password auth is enabled when a users file is given, `-` means no users
+    match args.get(3).map(|s| s.as_str()) {
+        Some("-") | None => {}
+        Some(path) => match FileAuthenticator::load(path) {
+            Ok(authenticator) => {
+                let users: Arc<dyn Authenticator> = Arc::new(authenticator);
+                builder = builder.users(users);
+            }
+            Err(msg) => panic!("load users err: {}", msg),
+        },
     };
 
-    let res = poll.register(server.listener().unwrap(), token
-                  , Ready::readable() | Ready::writable(), PollOpt::edge());
-    if res.is_err(){
-        panic!("bind to target address failed.");
-    }
-
-    println!("bind to target address success!");
-
-    let mut events = Events::with_capacity(128);
-
-    let mut children_map = HashMap::<Token, ChildHandler>::new();
-
-    let mut sockets_map = HashMap::<Token, TcpStream>::new();
-
-    // child_socket => proxy_socket
-    let mut proxy_map = HashMap::<Token, Token>::new();
-
-    let mut buffer = [0 as u8; 1024 * 256];
-
-    let mut terminate_tokens = Vec::<Token>::new();
-
-    let mut token_generator = Tokens::new();
-
-
-    loop {
-        while !terminate_tokens.is_empty() {
-            let token = terminate_tokens.pop().unwrap();
-            match proxy_map.get(&token) {
-                // non-proxy
-                None => {
-                    let handler = match children_map.remove(&token) {
-                        None => continue,
-                        Some(result) => result,
-                    };
-
-                    let socket = match sockets_map.remove(&token) {
-                        None => continue,
-                        Some(result) => result,
-                    };
-
-                    poll.deregister(&socket);
-                    socket.shutdown(Shutdown::Both);
-
-                    let proxy_token = match handler.get_dst_token() {
-                        None => continue,
-                        Some(result) => result,
-                    };
-
-                    let proxy_socket = match sockets_map.remove(&proxy_token) {
-                        None => continue,
-                        Some(result) => result,
-                    };
-
-                    poll.deregister(&proxy_socket);
-                    proxy_socket.shutdown(Shutdown::Both);
-                }
-
-                // proxy
-                Some(_) => {}
-            }
+    if let Some(path) = args.get(4) {
+        match AuthPolicy::load(path) {
+            Ok(policy) => builder = builder.policy(policy),
+            Err(msg) => panic!("load policy err: {}", msg),
         }
+    }
 
-        poll.poll(&mut events, Some(Duration::from_millis(100)));
-
-        for event in events.iter() {
-            match event.token() {
-                Token(0) => {
-                    loop {
-                        let result = server.accept();
-                        match result {
-                            Ok((socket, _)) => {
-                                let token = token_generator.next();
-                                poll.register(&socket, token
-                                              , Ready::readable() | Ready::writable()
-                                              , PollOpt::edge());
-                                // 先move到map中，然后进行borrow --- 抛错
-                                // 可以先borrow,再move
-                                let child = ChildHandler::new(&token);
-                                children_map.insert(token, child);
-                                sockets_map.insert(token, socket);
-                            }
-                            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
-
-                            Err(_) => break
-                        }
-                    }
-                }
-                token if event.readiness().is_readable() => {
-                    let socket = sockets_map.get_mut(&token).unwrap();
-
-                    // proxy socket read
-                    let is_proxy = match proxy_map.get(&token) {
-                        None => false,
-                        Some(server) => true,
-                    };
-
-                    let mut handler = match proxy_map.get(&token) {
-                        None => children_map.get_mut(&token).unwrap(),
-                        Some(child_token) => {
-                            //println!("in panic point:{:?}", token.0);
-                            children_map.get_mut(&child_token).unwrap()
-                        }
-                    };
-
-                    let before_dst_request = handler.before_dst_request();
-                    let empty_dst_token = handler.is_dst_token_empty();
-                    let init_proxy_env = before_dst_request && empty_dst_token;
-
-                    let mut close = false;
-
-                    loop {
-                        let read = socket.read(&mut buffer);
-                        match read {
-                            Ok(0) => {
-                                //
-                                close = true;
-                                terminate_tokens.push(token);
-                                break;
-                            }
-                            Ok(size) => {
-                                if size == 0 {
-                                    continue;
-                                }
-                                for i in 0..size {
-                                    handler.receive_u8_data(buffer[i], is_proxy);
-                                    buffer[i] = 0;
-                                }
-                            }
-                            Err(e)  if e.kind() == std::io::ErrorKind::WouldBlock => {
-                                break;
-                            }
-                            Err(_) => {
-                                break;
-                            }
-                        }
-                    }
-
-                    if close {
-                        continue;
-                    }
-                    match handler.handle() {
-                        // TODO  add proxy socket logic
-                        Ok(size) => {
-
-                            // write to client socket
-                            if size != 0 {
-                                match handler.forward_to_proxy() {
-                                    false => {
-                                        let socket = sockets_map.get_mut(&token).unwrap();
-                                        handler.write_to_socket(socket, false);
-
-                                        handler.try_enable_forward();
-                                    }
-                                    true => {
-                                        match is_proxy {
-                                            true => {
-                                                handler.move_to_client();
-                                                let child_token = proxy_map.get(&token).unwrap();
-                                                let socket = sockets_map.get_mut(&child_token).unwrap();
-                                                handler.write_to_socket(socket, false);
-                                            }
-
-                                            false => {
-                                                handler.move_to_proxy();
-                                                let dst_token = handler.get_dst_token().unwrap();
-                                                let socket = sockets_map.get_mut(&dst_token).unwrap();
-                                                handler.write_to_socket(socket, true);
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                        Err(msg) => {
-                            // terminate
-                            println!("read err msg:{:?}", msg);
-                            terminate_tokens.push(token);
-                            socket.shutdown(Shutdown::Both);
-                        }
-                    };
-
-                    if init_proxy_env && !handler.proxy_inited() {
-                        let proxy_socket = handler.get_proxy_socket().unwrap();
-                        let proxy_token = &token_generator.next();
-                        let server_token = handler.get_token();
-                        sockets_map.insert(proxy_token.clone(), proxy_socket);
-                        proxy_map.insert(proxy_token.clone(), server_token.clone());
-
-                        // first register write event
-                        poll.register(sockets_map.get(proxy_token).unwrap()
-                                      , *proxy_token
-                                      , Ready::readable() | Ready::writable()
-                                      , PollOpt::edge());
-
-                        handler.set_proxy_inited(true);
-                        handler.set_dst_token(proxy_token.clone());
-                    }
-                }
-                token if event.readiness().is_writable() => {
-                    let socket = sockets_map.get_mut(&token).unwrap();
-
-                    let is_proxy = match proxy_map.get(&token) {
-                        None => false,
-                        Some(server) => true,
-                    };
-
-                    let mut child_handler = match proxy_map.get(&token) {
-                        None => children_map.get_mut(&token).unwrap(),
-                        Some(child_token) => children_map.get_mut(&child_token).unwrap(),
-                    };
+    let server = match builder.build() {
+        Ok(server) => server,
+        Err(msg) => panic!("start server err: {}", msg),
+    };
 
-                    match child_handler.write_to_socket(socket, is_proxy) {
-                        Ok(size) => {
-                            poll.reregister(sockets_map.get(&token).unwrap(), token
-                                            , Ready::readable()
-                                            , PollOpt::edge());
-                        }
-                        Err(msg) => {
-                            // terminate
-                            println!("write err msg:{:?}", msg);
-                            terminate_tokens.push(token);
-                            socket.shutdown(Shutdown::Both);
-                        }
-                    };
-                }
+    println!("bind to target address success!");
 
-                _ => ()
-            }
-        }
+    if let Err(msg) = server.run() {
+        panic!("server err: {}", msg);
     }
 }
 